@@ -0,0 +1,518 @@
+use alloc::{sync::Arc, vec::Vec};
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, MappedFrame, TranslateResult, UnmapError},
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+            PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+use crate::{
+    frame_allocator_bitmap::FrameAllocatorBitmap,
+    frame_refcount, kernel_level_4_frame, physical_memory_offset,
+    region::RegionKind,
+    try_with_mapper_and_allocator,
+    vma::{PageFaultError, Vma},
+    with_mapper_and_allocator, without_interrupts,
+};
+
+/// Number of entries in a page table
+const ENTRY_COUNT: usize = 512;
+
+lazy_static! {
+    /// The address space loaded in CR3, unless it is the kernel's own table
+    static ref ACTIVE: Mutex<Option<Arc<Mutex<AddressSpace>>>> = Mutex::new(None);
+}
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// The page lies in a level 4 entry shared with the kernel, or in the
+    /// upper half
+    KernelAddress(Page),
+    /// A new area collides with an existing one
+    VmaOverlap(Vma),
+    FrameAllocationFailed,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        AddressSpaceError::Map(err)
+    }
+}
+
+impl From<UnmapError> for AddressSpaceError {
+    fn from(err: UnmapError) -> Self {
+        AddressSpaceError::Unmap(err)
+    }
+}
+
+/// An isolated set of page tables.
+///
+/// Every level 4 entry that is present in the kernel page table when the
+/// address space is created is shared with it, which keeps the kernel (and
+/// everything the bootloader mapped) reachable after switching. User pages can
+/// only be mapped in the remaining lower half entries, which are owned by the
+/// address space and torn down when it is dropped.
+///
+/// User memory is either mapped eagerly with `map_user_page` or described by
+/// areas (`Vma`) that the page fault handler backs on first touch.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    /// Level 4 entries copied from the kernel page table
+    kernel_entries: [bool; ENTRY_COUNT],
+    vmas: Vec<Vma>,
+}
+
+impl AddressSpace {
+    /// Creates an address space sharing the kernel mappings of the global
+    /// mapper
+    pub fn new() -> Result<Self, AddressSpaceError> {
+        with_mapper_and_allocator(|mapper, frame_allocator| {
+            let physical_memory_offset = physical_memory_offset();
+            let kernel_table = mapper.level_4_table();
+
+            // Make sure the level 4 entries of every kernel region window
+            // exist, so kernel mappings made later show up in this address
+            // space too
+            for kind in RegionKind::ALL {
+                let (window_start, _) = kind.window();
+                let entry = &mut kernel_table[window_start.p4_index()];
+
+                if entry.is_unused() {
+                    let frame = allocate_table(frame_allocator, physical_memory_offset)?;
+                    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+                }
+            }
+
+            let level_4_frame = allocate_table(frame_allocator, physical_memory_offset)?;
+            let level_4_table = unsafe { table_mut(level_4_frame, physical_memory_offset) };
+
+            let mut kernel_entries = [false; ENTRY_COUNT];
+            for (index, entry) in kernel_table.iter().enumerate() {
+                if !entry.is_unused() {
+                    level_4_table[index] = entry.clone();
+                    kernel_entries[index] = true;
+                }
+            }
+
+            Ok(Self {
+                level_4_frame,
+                physical_memory_offset,
+                kernel_entries,
+                vmas: Vec::new(),
+            })
+        })
+    }
+
+    /// The frame of the level 4 table, as loaded into CR3
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Returns whether `page` may be mapped as a user page
+    pub fn is_user_page(&self, page: Page) -> bool {
+        let index = usize::from(page.p4_index());
+
+        index < ENTRY_COUNT / 2 && !self.kernel_entries[index]
+    }
+
+    /// Maps `page` to a freshly allocated, zeroed frame that is accessible
+    /// from user mode
+    pub fn map_user_page(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, AddressSpaceError> {
+        if !self.is_user_page(page) {
+            return Err(AddressSpaceError::KernelAddress(page));
+        }
+
+        let physical_memory_offset = self.physical_memory_offset;
+        let active = self.is_active();
+        let mut mapper = self.mapper();
+
+        with_mapper_and_allocator(|_, frame_allocator| {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+            unsafe { zero_frame(frame, physical_memory_offset) };
+
+            let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            let result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
+
+            match result {
+                // Only flush if this address space is the active one, the TLB
+                // holds nothing for it otherwise
+                Ok(flush) if active => flush.flush(),
+                Ok(flush) => flush.ignore(),
+                Err(err) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(err.into());
+                }
+            }
+
+            Ok(frame)
+        })
+    }
+
+    /// Unmaps a user page and frees the frame behind it
+    pub fn unmap_user_page(&mut self, page: Page) -> Result<(), AddressSpaceError> {
+        if !self.is_user_page(page) {
+            return Err(AddressSpaceError::KernelAddress(page));
+        }
+
+        let active = self.is_active();
+        let (frame, flush) = self.mapper().unmap(page)?;
+
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+
+        if frame_refcount::release(frame) {
+            with_mapper_and_allocator(|_, frame_allocator| unsafe {
+                frame_allocator.deallocate_frame(frame)
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Adds an area of lazily backed memory. Its pages are mapped to zeroed
+    /// frames when they are first accessed.
+    pub fn add_vma(&mut self, vma: Vma) -> Result<(), AddressSpaceError> {
+        for page in [
+            Page::containing_address(vma.start()),
+            Page::containing_address(vma.end() - 1u64),
+        ] {
+            if !self.is_user_page(page) {
+                return Err(AddressSpaceError::KernelAddress(page));
+            }
+        }
+
+        if let Some(existing) = self.vmas.iter().find(|existing| existing.overlaps(&vma)) {
+            return Err(AddressSpaceError::VmaOverlap(*existing));
+        }
+
+        self.vmas.push(vma);
+        Ok(())
+    }
+
+    pub fn vmas(&self) -> &[Vma] {
+        &self.vmas
+    }
+
+    /// Creates a copy of this address space that shares all pages mapped in
+    /// its areas copy-on-write.
+    ///
+    /// Writable pages are made read-only in both address spaces, the first
+    /// write to one of them copies the frame (see `handle_page_fault`).
+    pub fn fork(&mut self) -> Result<AddressSpace, AddressSpaceError> {
+        let mut child = AddressSpace::new()?;
+        let vmas = self.vmas.clone();
+        let active = self.is_active();
+
+        {
+            let mut parent_mapper = self.mapper();
+            let mut child_mapper = child.mapper();
+
+            with_mapper_and_allocator(|_, frame_allocator| {
+                for vma in &vmas {
+                    let flags = vma.flags() - PageTableFlags::WRITABLE;
+
+                    for page in vma.pages() {
+                        let frame = match parent_mapper.translate_page(page) {
+                            Ok(frame) => frame,
+                            Err(_) => continue,
+                        };
+
+                        if vma.is_writable() {
+                            let flush = unsafe { parent_mapper.update_flags(page, flags) }
+                                .expect("mapped page has no flags to update");
+
+                            if active {
+                                flush.flush();
+                            } else {
+                                flush.ignore();
+                            }
+                        }
+
+                        unsafe { child_mapper.map_to(page, frame, flags, frame_allocator)? }
+                            .ignore();
+                        frame_refcount::share(frame);
+                    }
+                }
+
+                Ok::<(), AddressSpaceError>(())
+            })?;
+        }
+
+        child.vmas = vmas;
+        Ok(child)
+    }
+
+    /// Resolves a page fault at `addr` inside one of the areas.
+    ///
+    /// Untouched pages get a zeroed frame, writes to copy-on-write pages get
+    /// their own copy of the frame (or just the write permission back if no
+    /// other address space uses it anymore).
+    pub fn handle_page_fault(
+        &mut self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> Result<(), PageFaultError> {
+        let vma = *self
+            .vmas
+            .iter()
+            .find(|vma| vma.contains(addr))
+            .ok_or(PageFaultError::NoVma)?;
+
+        let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+        let fetch = error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+        let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+        if (write && !vma.is_writable())
+            || (fetch && vma.flags().contains(PageTableFlags::NO_EXECUTE))
+            || error_code.intersects(
+                PageFaultErrorCode::MALFORMED_TABLE | PageFaultErrorCode::PROTECTION_KEY,
+            )
+        {
+            return Err(PageFaultError::AccessViolation);
+        }
+
+        let page = Page::containing_address(addr);
+        let physical_memory_offset = self.physical_memory_offset;
+        let mut mapper = self.mapper();
+
+        try_with_mapper_and_allocator(|_, frame_allocator| {
+            match mapper.translate(page.start_address()) {
+                // First touch, back the page with a zeroed frame
+                TranslateResult::NotMapped => {
+                    let frame = frame_allocator
+                        .allocate_frame()
+                        .ok_or(PageFaultError::FrameAllocationFailed)?;
+                    unsafe { zero_frame(frame, physical_memory_offset) };
+
+                    match unsafe { mapper.map_to(page, frame, vma.flags(), frame_allocator) } {
+                        Ok(flush) => flush.flush(),
+                        Err(err) => {
+                            unsafe { frame_allocator.deallocate_frame(frame) };
+                            return Err(err.into());
+                        }
+                    }
+                }
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(frame),
+                    flags,
+                    ..
+                } => {
+                    if user && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                        return Err(PageFaultError::AccessViolation);
+                    }
+
+                    // Write to a copy-on-write page
+                    if write && !flags.contains(PageTableFlags::WRITABLE) {
+                        let owners =
+                            frame_refcount::try_count(frame).ok_or(PageFaultError::Busy)?;
+                        if owners == 1 {
+                            unsafe { mapper.update_flags(page, vma.flags()) }
+                                .expect("mapped page has no flags to update")
+                                .flush();
+                        } else {
+                            let copy = frame_allocator
+                                .allocate_frame()
+                                .ok_or(PageFaultError::FrameAllocationFailed)?;
+                            unsafe { copy_frame(frame, copy, physical_memory_offset) };
+
+                            // Only give up the original once the copy is
+                            // done, its other owners may write to it after
+                            let last = match frame_refcount::try_release(frame) {
+                                Some(last) => last,
+                                None => {
+                                    unsafe { frame_allocator.deallocate_frame(copy) };
+                                    return Err(PageFaultError::Busy);
+                                }
+                            };
+
+                            mapper
+                                .unmap(page)
+                                .expect("mapped page could not be unmapped")
+                                .1
+                                .ignore();
+                            unsafe { mapper.map_to(page, copy, vma.flags(), frame_allocator)? }
+                                .flush();
+
+                            // The other owners released it in the meantime
+                            if last {
+                                unsafe { frame_allocator.deallocate_frame(frame) };
+                            }
+                        }
+                    } else if fetch && flags.contains(PageTableFlags::NO_EXECUTE) {
+                        return Err(PageFaultError::AccessViolation);
+                    }
+                    // Otherwise the entry allows the access, and the fault
+                    // came from a stale TLB entry
+                }
+                _ => return Err(PageFaultError::AccessViolation),
+            }
+
+            Ok(())
+        })
+        .unwrap_or(Err(PageFaultError::Busy))
+    }
+
+    /// Loads this address space into CR3
+    ///
+    /// # Safety
+    /// The caller must make sure the address space outlives its time in CR3,
+    /// and that nothing relies on the user mappings of the previous one.
+    pub unsafe fn switch(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Returns a mapper over the page tables of this address space
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe {
+            let level_4_table = table_mut(self.level_4_frame, self.physical_memory_offset);
+            OffsetPageTable::new(level_4_table, self.physical_memory_offset)
+        }
+    }
+
+    /// Frees every user page, page table and finally the level 4 table
+    unsafe fn free_tables(&mut self, frame_allocator: &mut FrameAllocatorBitmap) {
+        let offset = self.physical_memory_offset;
+        let level_4_table = table_mut(self.level_4_frame, offset);
+
+        for (index, entry) in level_4_table.iter_mut().enumerate() {
+            if self.kernel_entries[index] || entry.is_unused() {
+                continue;
+            }
+
+            free_table(entry.frame().unwrap(), 3, offset, frame_allocator);
+            entry.set_unused();
+        }
+
+        frame_allocator.deallocate_frame(self.level_4_frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        with_mapper_and_allocator(|_, frame_allocator| unsafe {
+            self.free_tables(frame_allocator)
+        });
+    }
+}
+
+/// Frees a page table at `level` together with everything it maps. Huge pages
+/// are never created by `AddressSpace`, so they are left alone.
+unsafe fn free_table(
+    frame: PhysFrame,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let table = table_mut(frame, physical_memory_offset);
+
+    for entry in table.iter() {
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            continue;
+        }
+
+        let child = entry.frame().unwrap();
+        if level > 1 {
+            free_table(child, level - 1, physical_memory_offset, frame_deallocator);
+        } else if frame_refcount::release(child) {
+            frame_deallocator.deallocate_frame(child);
+        }
+    }
+
+    frame_deallocator.deallocate_frame(frame);
+}
+
+fn allocate_table(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> Result<PhysFrame, AddressSpaceError> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(AddressSpaceError::FrameAllocationFailed)?;
+
+    unsafe { table_mut(frame, physical_memory_offset).zero() };
+
+    Ok(frame)
+}
+
+/// Switches to `space`, keeping it alive for as long as it is active
+pub fn activate(space: &Arc<Mutex<AddressSpace>>) {
+    without_interrupts(|| {
+        let mut active = ACTIVE.lock();
+
+        unsafe { space.lock().switch() };
+        *active = Some(space.clone());
+    });
+}
+
+/// Switches back to the kernel's own page table
+pub fn deactivate() {
+    without_interrupts(|| {
+        let mut active = ACTIVE.lock();
+
+        let (_, flags) = Cr3::read();
+        unsafe { Cr3::write(kernel_level_4_frame(), flags) };
+        active.take();
+    });
+}
+
+/// Resolves a page fault in the active address space, see
+/// `AddressSpace::handle_page_fault`.
+///
+/// Called from the page fault handler, so it fails instead of waiting on any
+/// lock.
+pub fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), PageFaultError> {
+    let space = ACTIVE
+        .try_lock()
+        .ok_or(PageFaultError::Busy)?
+        .clone()
+        .ok_or(PageFaultError::NoAddressSpace)?;
+
+    let mut space = space.try_lock().ok_or(PageFaultError::Busy)?;
+    space.handle_page_fault(addr, error_code)
+}
+
+unsafe fn copy_frame(from: PhysFrame, to: PhysFrame, physical_memory_offset: VirtAddr) {
+    let from: *const u8 = (physical_memory_offset + from.start_address().as_u64()).as_ptr();
+    let to: *mut u8 = (physical_memory_offset + to.start_address().as_u64()).as_mut_ptr();
+    to.copy_from_nonoverlapping(from, Page::<Size4KiB>::SIZE as usize);
+}
+
+unsafe fn zero_frame(frame: PhysFrame, physical_memory_offset: VirtAddr) {
+    let ptr: *mut u8 = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+    ptr.write_bytes(0, Page::<Size4KiB>::SIZE as usize);
+}
+
+unsafe fn table_mut<'a>(frame: PhysFrame, physical_memory_offset: VirtAddr) -> &'a mut PageTable {
+    let virt = physical_memory_offset + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
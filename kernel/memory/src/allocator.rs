@@ -1,44 +1,284 @@
-use linked_list_allocator::LockedHeap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::NonNull,
+};
+
+use linked_list_allocator::Heap;
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::{MapToError, MapperAllSizes, Translate},
+        FrameAllocator, FrameDeallocator, PageSize, Size2MiB, Size4KiB,
     },
     VirtAddr,
 };
 
+use crate::{
+    paging::map_anonymous_range,
+    protection::Permissions,
+    reclaim::reclaim,
+    region::{allocate_region, RegionKind},
+    slab::{size_class, slab_layout, SlabCache, SIZE_CLASSES},
+    try_with_mapper_and_allocator, without_interrupts,
+};
+
 // Set the global allocator rust will use for the Kernel
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+//
+// With the `debug-alloc` feature it is wrapped by `debug_alloc` instead, and
+// host builds, like the tests of this crate and the kernel, keep the allocator
+// of the standard library
+#[cfg_attr(
+    all(target_os = "none", not(feature = "debug-alloc")),
+    global_allocator
+)]
+pub(crate) static ALLOCATOR: KernelAllocator = KernelAllocator::new();
 
 // Define a heap for the kernel
-// Currently it is a fixed size of 100 KiB
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 9000 * 1024;
+// It starts out with 8 MiB mapped and grows on demand, up to the size of its
+// virtual region
+pub const HEAP_INITIAL_SIZE: usize = 8 * 1024 * 1024;
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024 * 1024;
+
+/// The heap grows in multiples of this, keeping its top 2 MiB aligned
+pub const HEAP_GROW_STEP: usize = Size2MiB::SIZE as usize;
 
 /// Initializes the kernel heap
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
-
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
-    }
+///
+/// The heap gets its own 2 MiB aligned region, so it is backed by 2 MiB pages
+/// wherever the frame allocator can provide them.
+pub fn init_heap<A>(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    let region = allocate_region(RegionKind::Heap, HEAP_MAX_SIZE as u64, Size2MiB::SIZE)
+        .expect("failed to reserve virtual memory for the heap");
+
+    let flags = Permissions::ReadWrite.flags();
+    map_anonymous_range(
+        mapper,
+        frame_allocator,
+        region.start(),
+        HEAP_INITIAL_SIZE as u64,
+        flags,
+    )?;
+
+    without_interrupts(|| unsafe {
+        ALLOCATOR.heap.lock().init(
+            region.start().as_u64() as usize,
+            HEAP_INITIAL_SIZE,
+            region.end().as_u64() as usize,
+        );
+    });
 
     Ok(())
 }
+
+/// Usage counters of the kernel heap, see [`heap_stats`]
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes currently mapped for the heap
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    /// Largest allocation the heap can serve without growing
+    pub largest_free_block: usize,
+    /// Bytes handed to the slab caches, counted in `used` as well
+    pub slab_bytes: usize,
+}
+
+/// Returns the current usage of the kernel heap
+pub fn heap_stats() -> HeapStats {
+    without_interrupts(|| {
+        let slab_bytes = ALLOCATOR
+            .slabs
+            .iter()
+            .map(|slab| slab.lock().reserved_bytes())
+            .sum();
+
+        let mut heap = ALLOCATOR.heap.lock();
+
+        HeapStats {
+            size: heap.heap.size(),
+            used: heap.heap.used(),
+            free: heap.heap.free(),
+            largest_free_block: heap.largest_free_block(),
+            slab_bytes,
+        }
+    })
+}
+
+/// The kernel's global allocator.
+///
+/// Small allocations are served by per size class slab caches, everything else
+/// by a linked list heap that maps more memory when it runs out.
+///
+/// Interrupt handlers allocate and free too, e.g. when a boxed handler is
+/// dropped, so the locks are only ever held with interrupts disabled.
+pub struct KernelAllocator {
+    heap: Mutex<GrowableHeap>,
+    slabs: [Mutex<SlabCache>; SIZE_CLASSES.len()],
+}
+
+impl KernelAllocator {
+    pub const fn new() -> Self {
+        Self {
+            heap: Mutex::new(GrowableHeap::empty()),
+            slabs: [
+                Mutex::new(SlabCache::new(SIZE_CLASSES[0])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[1])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[2])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[3])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[4])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[5])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[6])),
+                Mutex::new(SlabCache::new(SIZE_CLASSES[7])),
+            ],
+        }
+    }
+
+    /// Allocates without running the reclaimers
+    unsafe fn try_alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| match size_class(layout) {
+            Some(class) => self.slabs[class]
+                .lock()
+                .allocate(|| self.heap.lock().allocate(slab_layout())),
+            None => self
+                .heap
+                .lock()
+                .allocate(layout)
+                .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr()),
+        })
+    }
+}
+
+impl Default for KernelAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.try_alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+
+        // Give the registered reclaimers a chance to free memory before the
+        // allocation fails for good
+        if reclaim() > 0 {
+            self.try_alloc(layout)
+        } else {
+            ptr
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = NonNull::new_unchecked(ptr);
+
+        without_interrupts(|| match size_class(layout) {
+            Some(class) => self.slabs[class].lock().deallocate(ptr),
+            None => self.heap.lock().heap.deallocate(ptr, layout),
+        })
+    }
+}
+
+/// Linked list heap that maps more of its virtual region when it is full
+struct GrowableHeap {
+    heap: Heap,
+    /// End of the virtual region the heap may grow into
+    limit: usize,
+}
+
+impl GrowableHeap {
+    const fn empty() -> Self {
+        Self {
+            heap: Heap::empty(),
+            limit: 0,
+        }
+    }
+
+    /// # Safety
+    /// `[bottom, bottom + size)` must be mapped and unused, and
+    /// `[bottom + size, limit)` must be reserved for the heap.
+    unsafe fn init(&mut self, bottom: usize, size: usize, limit: usize) {
+        self.heap.init(bottom, size);
+        self.limit = limit;
+    }
+
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        if let Ok(ptr) = self.heap.allocate_first_fit(layout) {
+            return Some(ptr);
+        }
+
+        // Leave room for the padding the linked list may need to align the
+        // allocation inside the new memory
+        self.grow(layout.size() + layout.align())
+            .then(|| self.heap.allocate_first_fit(layout).ok())
+            .flatten()
+    }
+
+    /// Finds the largest block the linked list can hand out without growing.
+    ///
+    /// The list does not expose its holes, so this binary searches with trial
+    /// allocations instead.
+    fn largest_free_block(&mut self) -> usize {
+        const ALIGN: usize = core::mem::size_of::<usize>();
+
+        let mut low = 0;
+        let mut high = self.heap.free() / ALIGN;
+
+        while low < high {
+            let words = (low + high + 1) / 2;
+            let layout = Layout::from_size_align(words * ALIGN, ALIGN).unwrap();
+
+            match self.heap.allocate_first_fit(layout) {
+                Ok(ptr) => {
+                    unsafe { self.heap.deallocate(ptr, layout) };
+                    low = words;
+                }
+                Err(()) => high = words - 1,
+            }
+        }
+
+        low * ALIGN
+    }
+
+    /// Maps at least `min_size` more bytes at the top of the heap. Returns
+    /// whether any memory was added.
+    fn grow(&mut self, min_size: usize) -> bool {
+        let size = (min_size + HEAP_GROW_STEP - 1) & !(HEAP_GROW_STEP - 1);
+        let top = self.heap.top();
+
+        if self.limit == 0 || top + size > self.limit {
+            return false;
+        }
+
+        // The heap can be reached while the mapper is held, e.g. from inside
+        // `with_mapper_and_allocator`, so never wait on it here
+        let mapped = try_with_mapper_and_allocator(|mapper, frame_allocator| {
+            let flags = Permissions::ReadWrite.flags();
+            map_anonymous_range(
+                mapper,
+                frame_allocator,
+                VirtAddr::new(top as u64),
+                size as u64,
+                flags,
+            )
+            .is_ok()
+        })
+        .unwrap_or(false);
+
+        if mapped {
+            unsafe { self.heap.extend(size) };
+        }
+
+        mapped
+    }
+}
@@ -0,0 +1,418 @@
+//! Allocator wrapper for hunting heap corruption, enabled by the `debug-alloc`
+//! feature.
+//!
+//! Every allocation gets a red zone on both sides that is checked when it is
+//! freed, fresh and freed memory are poisoned, and all live allocations are
+//! tracked so double frees are caught and leaks can be dumped. Callers are
+//! found by walking frame pointers, so build with
+//! `-C force-frame-pointers=yes` to get useful addresses.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    fmt,
+};
+
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+use crate::{
+    allocator::{KernelAllocator, ALLOCATOR},
+    physical_memory_offset, translate_address,
+};
+
+#[cfg_attr(target_os = "none", global_allocator)]
+static DEBUG_ALLOCATOR: DebugAllocator<KernelAllocator> = DebugAllocator::new(&ALLOCATOR);
+
+/// Bytes of red zone on each side of an allocation
+pub const RED_ZONE_SIZE: usize = 16;
+/// Maximum number of live allocations that can be tracked
+pub const MAX_TRACKED: usize = 8192;
+/// Number of return addresses kept per allocation
+pub const CALLER_DEPTH: usize = 6;
+
+const RED_ZONE: u8 = 0xfd;
+/// Fill of memory that was just allocated
+const ALLOC_POISON: u8 = 0xcd;
+/// Fill of memory that was freed
+const FREE_POISON: u8 = 0xdd;
+
+/// Frames further apart than this end the frame pointer walk
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+static LIVE: Mutex<LiveTable> = Mutex::new(LiveTable::new());
+
+/// A live allocation, as handed out to the caller
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+    pub ptr: usize,
+    pub size: usize,
+    pub align: usize,
+    /// Return addresses of the innermost frames at the time of the
+    /// allocation, zero where the walk stopped
+    pub callers: [usize; CALLER_DEPTH],
+}
+
+/// Open addressing hash table of live allocations, keyed by pointer. Uses
+/// linear probing, and removal moves the rest of a cluster back instead of
+/// leaving tombstones, so lookups only ever probe as far as the cluster of
+/// their pointer.
+struct LiveTable {
+    slots: [Option<AllocationRecord>; MAX_TRACKED],
+    count: usize,
+    bytes: usize,
+    /// Set once an allocation could not be tracked, after which unknown
+    /// pointers can no longer be told apart from double frees
+    overflowed: bool,
+}
+
+impl LiveTable {
+    const fn new() -> Self {
+        Self {
+            slots: [None; MAX_TRACKED],
+            count: 0,
+            bytes: 0,
+            overflowed: false,
+        }
+    }
+
+    fn home(ptr: usize) -> usize {
+        // Allocations are at least 16 byte aligned, so drop the low bits
+        (ptr >> 4).wrapping_mul(0x9e37_79b9_7f4a_7c15) % MAX_TRACKED
+    }
+
+    fn probe(ptr: usize) -> impl Iterator<Item = usize> {
+        let start = Self::home(ptr);
+
+        (0..MAX_TRACKED).map(move |offset| (start + offset) % MAX_TRACKED)
+    }
+
+    /// Tracks `record`, or returns the live record with the same pointer
+    fn insert(&mut self, record: AllocationRecord) -> Result<(), AllocationRecord> {
+        for index in Self::probe(record.ptr) {
+            match self.slots[index] {
+                Some(live) if live.ptr == record.ptr => return Err(live),
+                Some(_) => {}
+                None => {
+                    self.slots[index] = Some(record);
+                    self.count += 1;
+                    self.bytes += record.size;
+                    return Ok(());
+                }
+            }
+        }
+
+        self.overflowed = true;
+        Ok(())
+    }
+
+    fn remove(&mut self, ptr: usize) -> Option<AllocationRecord> {
+        let index = Self::probe(ptr)
+            .take_while(|&index| self.slots[index].is_some())
+            .find(|&index| matches!(self.slots[index], Some(live) if live.ptr == ptr))?;
+
+        let record = self.slots[index].take()?;
+        self.count -= 1;
+        self.bytes -= record.size;
+
+        // Close the hole, moving back every later record of the cluster that
+        // would have been placed there
+        let mut hole = index;
+        let mut next = index;
+        loop {
+            next = (next + 1) % MAX_TRACKED;
+            let moved = match self.slots[next] {
+                Some(moved) => moved,
+                None => break,
+            };
+
+            let home = Self::home(moved.ptr);
+            let distance = |from: usize| (next + MAX_TRACKED - from) % MAX_TRACKED;
+            if distance(home) >= distance(hole) {
+                self.slots[hole] = self.slots[next].take();
+                hole = next;
+            }
+        }
+
+        Some(record)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &AllocationRecord> {
+        self.slots.iter().flatten()
+    }
+}
+
+/// Wraps `inner` with red zones, poisoning and allocation tracking
+pub struct DebugAllocator<A: 'static> {
+    inner: &'static A,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        Self { inner }
+    }
+}
+
+/// Layout of the block taken from the inner allocator, and the offset of the
+/// caller's memory inside it
+fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+    // Keeps the caller's memory aligned, and leaves at least a full red zone
+    // in front of it
+    let offset = layout.align().max(RED_ZONE_SIZE);
+    let size = offset
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+
+    Some((Layout::from_size_align(size, layout.align()).ok()?, offset))
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let callers = callers();
+
+        let (outer, offset) = match outer_layout(layout) {
+            Some(outer) => outer,
+            None => return core::ptr::null_mut(),
+        };
+
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = base.add(offset);
+        base.write_bytes(RED_ZONE, offset);
+        ptr.write_bytes(ALLOC_POISON, layout.size());
+        ptr.add(layout.size()).write_bytes(RED_ZONE, RED_ZONE_SIZE);
+
+        let tracked = LIVE.lock().insert(AllocationRecord {
+            ptr: ptr as usize,
+            size: layout.size(),
+            align: layout.align(),
+            callers,
+        });
+        if let Err(live) = tracked {
+            panic!(
+                "heap corruption: {:p} handed out again while still live, allocated from {:#x?}",
+                ptr, live.callers
+            );
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, offset) = outer_layout(layout).expect("invalid layout freed");
+        let base = ptr.sub(offset);
+
+        let (record, overflowed) = {
+            let mut live = LIVE.lock();
+            (live.remove(ptr as usize), live.overflowed)
+        };
+
+        let record = match record {
+            Some(record) => record,
+            None if overflowed => AllocationRecord {
+                ptr: ptr as usize,
+                size: layout.size(),
+                align: layout.align(),
+                callers: [0; CALLER_DEPTH],
+            },
+            None if is_filled(ptr, layout.size().min(RED_ZONE_SIZE), FREE_POISON) => {
+                panic!("double free of {:p} ({} bytes)", ptr, layout.size())
+            }
+            None => panic!(
+                "free of {:p} ({} bytes), which was never allocated",
+                ptr,
+                layout.size()
+            ),
+        };
+
+        assert!(
+            record.size == layout.size() && record.align == layout.align(),
+            "allocation {:p} of {} bytes (align {}) freed as {} bytes (align {}), allocated from {:#x?}",
+            ptr,
+            record.size,
+            record.align,
+            layout.size(),
+            layout.align(),
+            record.callers
+        );
+
+        if !is_filled(base, offset, RED_ZONE) {
+            panic!(
+                "heap corruption: red zone before {:p} ({} bytes) overwritten, allocated from {:#x?}",
+                ptr, record.size, record.callers
+            );
+        }
+        if !is_filled(ptr.add(layout.size()), RED_ZONE_SIZE, RED_ZONE) {
+            panic!(
+                "heap corruption: red zone after {:p} ({} bytes) overwritten, allocated from {:#x?}",
+                ptr, record.size, record.callers
+            );
+        }
+
+        base.write_bytes(FREE_POISON, outer.size());
+        self.inner.dealloc(base, outer);
+    }
+}
+
+unsafe fn is_filled(ptr: *const u8, len: usize, value: u8) -> bool {
+    core::slice::from_raw_parts(ptr, len)
+        .iter()
+        .all(|&byte| byte == value)
+}
+
+/// Collects return addresses by following the frame pointer chain, stopping
+/// at the first frame that does not look like one
+#[inline(always)]
+fn callers() -> [usize; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+
+    let mut frame: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+
+    for caller in callers.iter_mut() {
+        if frame == 0 || frame % 8 != 0 || !is_mapped(frame) || !is_mapped(frame + 8) {
+            break;
+        }
+
+        // The saved frame pointer, followed by the return address
+        let (next, return_address) = unsafe {
+            let frame = frame as *const usize;
+            (*frame, *frame.add(1))
+        };
+        *caller = return_address;
+
+        if next <= frame || next - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = next;
+    }
+
+    callers
+}
+
+fn is_mapped(addr: usize) -> bool {
+    VirtAddr::try_new(addr as u64)
+        .ok()
+        .and_then(|addr| translate_address(addr, physical_memory_offset()))
+        .is_some()
+}
+
+/// Writes every live allocation with its size and callers to `out`.
+///
+/// The allocation table stays locked while writing, so `out` must not
+/// allocate.
+pub fn dump_live_allocations(out: &mut dyn fmt::Write) -> fmt::Result {
+    let live = LIVE.lock();
+
+    writeln!(
+        out,
+        "Live allocations: {} ({} bytes)",
+        live.count, live.bytes
+    )?;
+    if live.overflowed {
+        writeln!(out, "  table overflowed, some allocations are missing")?;
+    }
+
+    for record in live.iter() {
+        write!(
+            out,
+            "  {:#x} {} bytes, align {}, from",
+            record.ptr, record.size, record.align
+        )?;
+        for caller in record.callers.iter().take_while(|&&caller| caller != 0) {
+            write!(out, " {:#x}", caller)?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+
+    use super::*;
+
+    fn record(ptr: usize, size: usize) -> AllocationRecord {
+        AllocationRecord {
+            ptr,
+            size,
+            align: 16,
+            callers: [0; CALLER_DEPTH],
+        }
+    }
+
+    #[test]
+    fn outer_layout_keeps_alignment_and_red_zones() {
+        let (outer, offset) = outer_layout(Layout::from_size_align(10, 8).unwrap()).unwrap();
+        assert_eq!(offset, RED_ZONE_SIZE);
+        assert_eq!(outer.size(), RED_ZONE_SIZE + 10 + RED_ZONE_SIZE);
+        assert_eq!(outer.align(), 8);
+
+        let (outer, offset) = outer_layout(Layout::from_size_align(100, 64).unwrap()).unwrap();
+        assert_eq!(offset, 64);
+        assert_eq!(outer.size(), 64 + 100 + RED_ZONE_SIZE);
+        assert_eq!(outer.align(), 64);
+
+        let huge = Layout::from_size_align(isize::MAX as usize - 8, 1).unwrap();
+        assert!(outer_layout(huge).is_none());
+    }
+
+    #[test]
+    fn live_table_tracks_allocations() {
+        let mut table = Box::new(LiveTable::new());
+
+        table.insert(record(0x1000, 10)).unwrap();
+        table.insert(record(0x2000, 20)).unwrap();
+        assert_eq!((table.count, table.bytes), (2, 30));
+
+        assert_eq!(table.insert(record(0x1000, 5)).unwrap_err().size, 10);
+        assert_eq!((table.count, table.bytes), (2, 30));
+
+        assert_eq!(table.remove(0x1000).unwrap().size, 10);
+        assert!(table.remove(0x1000).is_none());
+        assert!(table.remove(0x3000).is_none());
+        assert_eq!((table.count, table.bytes), (1, 20));
+        assert_eq!(table.iter().count(), 1);
+    }
+
+    #[test]
+    fn live_table_finds_records_after_removals_in_a_cluster() {
+        let mut table = Box::new(LiveTable::new());
+        let ptrs = (1..=MAX_TRACKED * 3 / 4).map(|index| index * 16);
+
+        for ptr in ptrs.clone() {
+            table.insert(record(ptr, 1)).unwrap();
+        }
+        for ptr in ptrs.clone().step_by(2) {
+            assert!(table.remove(ptr).is_some());
+        }
+
+        for (index, ptr) in ptrs.enumerate() {
+            assert_eq!(table.remove(ptr).is_some(), index % 2 == 1);
+        }
+        assert_eq!(table.count, 0);
+        assert!(table.slots.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn live_table_overflows_when_full() {
+        let mut table = Box::new(LiveTable::new());
+
+        for index in 0..=MAX_TRACKED {
+            table.insert(record((index + 1) * 16, 1)).unwrap();
+        }
+        assert_eq!(table.count, MAX_TRACKED);
+        assert!(table.overflowed);
+
+        // Lookups of untracked pointers end once there is a free slot again
+        assert!(table.remove(16).is_some());
+        assert!(table.remove((MAX_TRACKED + 1) * 16).is_none());
+        table.insert(record(16, 1)).unwrap();
+        assert_eq!(table.count, MAX_TRACKED);
+    }
+}
@@ -0,0 +1,93 @@
+use x86_64::{
+    structures::paging::{frame::PhysFrameRange, PageSize, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{physical_memory_offset, with_mapper_and_allocator, zone::Zone};
+
+#[derive(Debug)]
+pub enum DmaError {
+    /// The size was zero or the alignment not a power of two
+    InvalidLayout,
+    /// No free run of frames in the zone is large enough
+    OutOfMemory(Zone),
+}
+
+/// Physically contiguous, zeroed memory a device can access directly.
+///
+/// The buffer is reached through the physical memory mapping, so the CPU sees
+/// it as normal cacheable memory, which x86 keeps coherent with DMA. Dropping
+/// it frees the frames, so it must outlive any transfer using it. Dropping
+/// takes the global frame allocator, so it must not happen inside
+/// `with_mapper_and_allocator`.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    frames: PhysFrameRange,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates at least `len` bytes whose physical address lies in `zone`
+    /// (or a lower zone) and is aligned to `align` bytes. Alignments below a
+    /// page are rounded up to it.
+    pub fn new(len: usize, zone: Zone, align: u64) -> Result<Self, DmaError> {
+        if len == 0 || !align.is_power_of_two() {
+            return Err(DmaError::InvalidLayout);
+        }
+
+        let count = (len as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        let align = (align / Size4KiB::SIZE).max(1);
+
+        let frames = with_mapper_and_allocator(|_, frame_allocator| {
+            frame_allocator.allocate_contiguous_in(zone, count, align)
+        })
+        .ok_or(DmaError::OutOfMemory(zone))?;
+
+        let buffer = Self { frames, len };
+        unsafe {
+            buffer
+                .as_ptr::<u8>()
+                .write_bytes(0, (count * Size4KiB::SIZE) as usize)
+        };
+
+        Ok(buffer)
+    }
+
+    /// Address of the buffer as the device sees it
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    /// Address of the buffer as the CPU sees it
+    pub fn virt_addr(&self) -> VirtAddr {
+        physical_memory_offset() + self.phys_addr().as_u64()
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt_addr().as_mut_ptr()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        with_mapper_and_allocator(|_, frame_allocator| unsafe {
+            frame_allocator.deallocate_contiguous(self.frames)
+        });
+    }
+}
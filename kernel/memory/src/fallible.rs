@@ -0,0 +1,66 @@
+//! Allocation helpers that report running out of memory instead of calling
+//! the allocation error handler, for driver code that can back off.
+
+use core::{alloc::Layout, fmt, ptr::NonNull};
+
+use alloc::{boxed::Box, vec::Vec};
+
+/// The heap could not serve an allocation of `layout`, even after reclaiming
+#[derive(Debug, Clone, Copy)]
+pub struct AllocError {
+    pub layout: Layout,
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "out of memory allocating {} bytes aligned to {}",
+            self.layout.size(),
+            self.layout.align()
+        )
+    }
+}
+
+/// Moves `value` to the heap, or hands it back if there is no memory for it
+pub fn try_box<T>(value: T) -> Result<Box<T>, (AllocError, T)> {
+    let layout = Layout::new::<T>();
+
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+
+    match NonNull::new(unsafe { alloc::alloc::alloc(layout) }.cast::<T>()) {
+        Some(ptr) => unsafe {
+            ptr.as_ptr().write(value);
+            Ok(Box::from_raw(ptr.as_ptr()))
+        },
+        None => Err((AllocError { layout }, value)),
+    }
+}
+
+/// Creates a `Vec` with room for at least `capacity` elements
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let mut vec = Vec::new();
+    try_reserve(&mut vec, capacity)?;
+
+    Ok(vec)
+}
+
+/// Makes room for at least `additional` more elements in `vec`
+pub fn try_reserve<T>(vec: &mut Vec<T>, additional: usize) -> Result<(), AllocError> {
+    vec.try_reserve(additional).map_err(|_| AllocError {
+        layout: Layout::array::<T>(vec.len().saturating_add(additional))
+            .unwrap_or_else(|_| Layout::new::<T>()),
+    })
+}
+
+/// Appends `value` to `vec`, or hands it back if the `Vec` cannot grow
+pub fn try_push<T>(vec: &mut Vec<T>, value: T) -> Result<(), (AllocError, T)> {
+    if let Err(err) = try_reserve(vec, 1) {
+        return Err((err, value));
+    }
+
+    vec.push(value);
+    Ok(())
+}
@@ -0,0 +1,419 @@
+use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB,
+        Size4KiB,
+    },
+    PhysAddr,
+};
+
+use crate::{physical_memory::PhysicalMemory, zone::Zone};
+
+const BITS_PER_WORD: u64 = u64::BITS as u64;
+
+/// Physical memory manager keeping one bit per 4 KiB frame.
+///
+/// A set bit means the frame is in use (or not RAM at all). The bitmap itself
+/// lives in the first usable region large enough to hold it and is accessed
+/// through the bootloader's physical memory mapping.
+pub struct FrameAllocatorBitmap {
+    /// One bit per frame, starting at physical address zero
+    bitmap: &'static mut [u64],
+    /// Number of frames covered by the bitmap
+    frame_count: u64,
+    /// Number of frames currently available for allocation
+    free_frames: u64,
+    /// Free frames per zone, indexed by `Zone::index`
+    zone_free_frames: [u64; Zone::ALL.len()],
+    /// Frame number the next search in each zone starts from
+    next: [u64; Zone::ALL.len()],
+}
+
+impl FrameAllocatorBitmap {
+    /// Creates a bitmap allocator from the memory map of the bootloader
+    ///
+    /// # Safety
+    /// The caller must guarantee that the passed memory map is valid, that all
+    /// frames marked as `USABLE` are indeed unused, and that `memory` gives
+    /// access to the complete physical memory.
+    pub unsafe fn init(memory_regions: &[MemoryRegion], memory: &impl PhysicalMemory) -> Self {
+        let usable_regions = || {
+            memory_regions
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+        };
+
+        // Only track memory up to the end of the last usable region, nothing
+        // above it can ever be handed out
+        let memory_end = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        let frame_count = memory_end / Size4KiB::SIZE;
+        let bitmap_words = ((frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD) as usize;
+        let bitmap_bytes = (bitmap_words * core::mem::size_of::<u64>()) as u64;
+
+        let bitmap_start = usable_regions()
+            .map(|r| (align_up(r.start, Size4KiB::SIZE), r.end))
+            .find(|&(start, end)| start + bitmap_bytes <= end)
+            .map(|(start, _)| start)
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_ptr: *mut u64 = memory.virt_addr(PhysAddr::new(bitmap_start)).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, bitmap_words);
+
+        // Everything starts out as used, then the usable regions are released
+        bitmap.fill(!0);
+
+        let mut allocator = Self {
+            bitmap,
+            frame_count,
+            free_frames: 0,
+            zone_free_frames: [0; Zone::ALL.len()],
+            next: Zone::ALL.map(|zone| zone.frames().start),
+        };
+
+        for region in usable_regions() {
+            let start = align_up(region.start, Size4KiB::SIZE) / Size4KiB::SIZE;
+            let end = region.end / Size4KiB::SIZE;
+
+            for frame in start..end {
+                allocator.mark_free(frame);
+            }
+        }
+
+        // Take back the frames holding the bitmap, and never hand out the frame
+        // at physical address zero
+        let bitmap_frames = align_up(bitmap_bytes, Size4KiB::SIZE) / Size4KiB::SIZE;
+        let bitmap_first = bitmap_start / Size4KiB::SIZE;
+        for frame in bitmap_first..bitmap_first + bitmap_frames {
+            allocator.mark_used(frame);
+        }
+        if allocator.frame_count > 0 && !allocator.is_used(0) {
+            allocator.mark_used(0);
+        }
+
+        allocator
+    }
+
+    /// Number of frames currently free
+    pub fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    /// Number of frames tracked by the bitmap, up to the end of the highest
+    /// usable region
+    pub fn total_frames(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Number of frames currently free in `zone`
+    pub fn zone_free_frames(&self, zone: Zone) -> u64 {
+        self.zone_free_frames[zone.index()]
+    }
+
+    /// Allocates `count` physically contiguous frames, with the first frame
+    /// aligned to `align` frames. `align` must be a power of two.
+    ///
+    /// The frames come from anywhere in memory, preferring the `Normal` zone.
+    pub fn allocate_contiguous(&mut self, count: u64, align: u64) -> Option<PhysFrameRange> {
+        self.allocate_contiguous_in(Zone::Normal, count, align)
+    }
+
+    /// Like `allocate_contiguous`, but only hands out frames that satisfy the
+    /// addressing limit of `zone`
+    pub fn allocate_contiguous_in(
+        &mut self,
+        zone: Zone,
+        count: u64,
+        align: u64,
+    ) -> Option<PhysFrameRange> {
+        assert!(
+            align.is_power_of_two(),
+            "frame alignment must be a power of two"
+        );
+
+        zone.fallbacks()
+            .iter()
+            .find_map(|&zone| self.allocate_in_zone(zone, count, align))
+    }
+
+    /// Like `allocate_contiguous`, but for limits below the lowest zone: the
+    /// frames end at or below the physical address `end`
+    pub fn allocate_contiguous_below(
+        &mut self,
+        end: PhysAddr,
+        count: u64,
+        align: u64,
+    ) -> Option<PhysFrameRange> {
+        assert!(
+            align.is_power_of_two(),
+            "frame alignment must be a power of two"
+        );
+
+        let end = (end.as_u64() / Size4KiB::SIZE).min(self.frame_count);
+        if count == 0 {
+            return None;
+        }
+        let start = self.find_free_run(0, end, count, align)?;
+
+        for frame in start..start + count {
+            self.mark_used(frame);
+        }
+
+        Some(PhysFrame::range(
+            frame_from_number(start),
+            frame_from_number(start + count),
+        ))
+    }
+
+    fn allocate_in_zone(&mut self, zone: Zone, count: u64, align: u64) -> Option<PhysFrameRange> {
+        if count == 0 || count > self.zone_free_frames(zone) {
+            return None;
+        }
+
+        let frames = zone.frames();
+        let end = frames.end.min(self.frame_count);
+        let next = self.next[zone.index()];
+
+        let start = self
+            .find_free_run(next, end, count, align)
+            .or_else(|| self.find_free_run(frames.start, end, count, align))?;
+
+        for frame in start..start + count {
+            self.mark_used(frame);
+        }
+        self.next[zone.index()] = start + count;
+
+        Some(PhysFrame::range(
+            frame_from_number(start),
+            frame_from_number(start + count),
+        ))
+    }
+
+    /// Returns a range of frames previously handed out by this allocator.
+    ///
+    /// # Safety
+    /// The caller must ensure that none of the frames are still in use.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    /// Finds the first run of `count` free frames at or after `from` that
+    /// ends before `end`
+    fn find_free_run(&self, from: u64, end: u64, count: u64, align: u64) -> Option<u64> {
+        let mut start = from;
+
+        loop {
+            start = align_up(self.next_free(start)?, align);
+
+            if start + count > end {
+                return None;
+            }
+
+            // Restart the search right after the last used frame in the run
+            match (start..start + count).rev().find(|&f| self.is_used(f)) {
+                Some(used) => start = used + 1,
+                None => return Some(start),
+            }
+        }
+    }
+
+    /// Returns the first free frame at or after `from`, skipping full words
+    fn next_free(&self, from: u64) -> Option<u64> {
+        let mut word_index = (from / BITS_PER_WORD) as usize;
+        // Treat the frames below `from` in the first word as used
+        let mut word = *self.bitmap.get(word_index)? | ((1 << (from % BITS_PER_WORD)) - 1);
+
+        while word == !0 {
+            word_index += 1;
+            word = *self.bitmap.get(word_index)?;
+        }
+
+        let frame = word_index as u64 * BITS_PER_WORD + u64::from(word.trailing_ones());
+        (frame < self.frame_count).then_some(frame)
+    }
+
+    fn is_used(&self, frame: u64) -> bool {
+        let word = self.bitmap[(frame / BITS_PER_WORD) as usize];
+        word & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn mark_used(&mut self, frame: u64) {
+        self.bitmap[(frame / BITS_PER_WORD) as usize] |= 1 << (frame % BITS_PER_WORD);
+        self.free_frames -= 1;
+        self.zone_free_frames[Zone::of_frame(frame).index()] -= 1;
+    }
+
+    fn mark_free(&mut self, frame: u64) {
+        self.bitmap[(frame / BITS_PER_WORD) as usize] &= !(1 << (frame % BITS_PER_WORD));
+        self.free_frames += 1;
+        self.zone_free_frames[Zone::of_frame(frame).index()] += 1;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for FrameAllocatorBitmap {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_contiguous(1, 1).map(|range| range.start)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for FrameAllocatorBitmap {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let number = frame.start_address().as_u64() / Size4KiB::SIZE;
+
+        assert!(
+            number < self.frame_count && self.is_used(number),
+            "deallocating frame {:?} which is not allocated",
+            frame
+        );
+
+        self.mark_free(number);
+    }
+}
+
+/// Huge frames are handed out as naturally aligned runs of 4 KiB frames
+unsafe impl FrameAllocator<Size2MiB> for FrameAllocatorBitmap {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frames = Size2MiB::SIZE / Size4KiB::SIZE;
+        let range = self.allocate_contiguous(frames, frames)?;
+
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for FrameAllocatorBitmap {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        let end = PhysFrame::containing_address(frame.start_address() + Size2MiB::SIZE);
+
+        self.deallocate_contiguous(PhysFrame::range(start, end));
+    }
+}
+
+fn frame_from_number(number: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(number * Size4KiB::SIZE))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockSystem;
+
+    const MEMORY_SIZE: u64 = 4 * 1024 * 1024;
+
+    #[test]
+    fn allocates_until_exhausted() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let allocator = &mut system.frame_allocator;
+        let free = allocator.free_frames();
+
+        let mut allocated = 0;
+        while let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(allocator) {
+            assert_ne!(frame.start_address().as_u64(), 0, "handed out frame zero");
+            allocated += 1;
+        }
+
+        assert_eq!(allocated, free);
+        assert_eq!(allocator.free_frames(), 0);
+        assert!(FrameAllocator::<Size2MiB>::allocate_frame(allocator).is_none());
+        assert!(allocator.allocate_contiguous(1, 1).is_none());
+    }
+
+    #[test]
+    fn freed_frames_are_reused() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let allocator = &mut system.frame_allocator;
+
+        let mut last = None;
+        while let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(allocator) {
+            last = Some(frame);
+        }
+
+        let frame = last.unwrap();
+        unsafe { allocator.deallocate_frame(frame) };
+
+        assert_eq!(allocator.free_frames(), 1);
+        assert_eq!(
+            FrameAllocator::<Size4KiB>::allocate_frame(allocator),
+            Some(frame)
+        );
+    }
+
+    #[test]
+    fn contiguous_allocations_are_aligned() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let allocator = &mut system.frame_allocator;
+
+        // Throw the next search position off alignment
+        FrameAllocator::<Size4KiB>::allocate_frame(allocator).unwrap();
+
+        let range = allocator.allocate_contiguous(3, 16).unwrap();
+        assert!(range.start.start_address().is_aligned(16 * Size4KiB::SIZE));
+        assert_eq!(range.end - range.start, 3);
+
+        let huge = FrameAllocator::<Size2MiB>::allocate_frame(allocator).unwrap();
+        assert!(huge.start_address().is_aligned(Size2MiB::SIZE));
+
+        let free = allocator.free_frames();
+        unsafe { allocator.deallocate_contiguous(range) };
+        assert_eq!(allocator.free_frames(), free + 3);
+    }
+
+    #[test]
+    fn zones_limit_physical_addresses() {
+        let mut system = MockSystem::new(20 * 1024 * 1024);
+        let allocator = &mut system.frame_allocator;
+        let dma16_end = Zone::Dma16.range().end;
+
+        // General allocations stay out of the lowest zone while they can
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(allocator).unwrap();
+        assert!(frame.start_address().as_u64() >= dma16_end);
+
+        let range = allocator.allocate_contiguous_in(Zone::Dma16, 4, 4).unwrap();
+        assert!(range.end.start_address().as_u64() <= dma16_end);
+        assert!(range.start.start_address().is_aligned(4 * Size4KiB::SIZE));
+
+        let dma16_free = allocator.zone_free_frames(Zone::Dma16);
+        while allocator
+            .allocate_contiguous_in(Zone::Dma16, 1, 1)
+            .is_some()
+        {}
+        assert_eq!(allocator.zone_free_frames(Zone::Dma16), 0);
+        assert!(dma16_free > 0);
+
+        // DMA32 still has the memory above 16 MiB, and nothing below it is
+        // left for DMA16
+        assert!(allocator
+            .allocate_contiguous_in(Zone::Dma32, 1, 1)
+            .is_some());
+        assert!(allocator
+            .allocate_contiguous_in(Zone::Dma16, 1, 1)
+            .is_none());
+    }
+
+    #[test]
+    fn allocates_below_an_address() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let allocator = &mut system.frame_allocator;
+        let end = PhysAddr::new(1024 * 1024);
+
+        let mut allocated = 0;
+        while let Some(range) = allocator.allocate_contiguous_below(end, 1, 1) {
+            assert!(range.end.start_address() <= end);
+            assert_ne!(
+                range.start.start_address().as_u64(),
+                0,
+                "handed out frame zero"
+            );
+            allocated += 1;
+        }
+
+        assert!(allocated > 0);
+        assert!(allocator.free_frames() > 0);
+        assert!(allocator.allocate_contiguous_below(end, 1, 1).is_none());
+    }
+}
@@ -0,0 +1,122 @@
+use alloc::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::without_interrupts;
+
+lazy_static! {
+    /// Number of owners of every frame shared between address spaces. Frames
+    /// with a single owner are not tracked, or left at a count of one by
+    /// `try_release`.
+    static ref SHARED_FRAMES: Mutex<BTreeMap<PhysFrame, u32>> = Mutex::new(BTreeMap::new());
+}
+
+/// Adds an owner to `frame`
+pub fn share(frame: PhysFrame) {
+    without_interrupts(|| {
+        *SHARED_FRAMES.lock().entry(frame).or_insert(1) += 1;
+    });
+}
+
+/// Drops one owner of `frame`. Returns whether that was the last one, in
+/// which case the caller has to free the frame.
+pub fn release(frame: PhysFrame) -> bool {
+    without_interrupts(|| {
+        let mut shared = SHARED_FRAMES.lock();
+
+        match shared.get_mut(&frame) {
+            None => true,
+            Some(count) => {
+                *count -= 1;
+                let owners = *count;
+                if owners <= 1 {
+                    shared.remove(&frame);
+                }
+                owners == 0
+            }
+        }
+    })
+}
+
+/// Like `release`, but for the page fault handler. Returns `None` instead of
+/// waiting for the lock, and never frees memory of the table, which the
+/// handler may have interrupted the allocator for.
+pub fn try_release(frame: PhysFrame) -> Option<bool> {
+    without_interrupts(|| {
+        let mut shared = SHARED_FRAMES.try_lock()?;
+
+        Some(match shared.get_mut(&frame) {
+            None => true,
+            Some(count) if *count == 1 => true,
+            Some(count) => {
+                *count -= 1;
+                false
+            }
+        })
+    })
+}
+
+/// Returns the number of owners of `frame`
+pub fn count(frame: PhysFrame) -> u32 {
+    without_interrupts(|| SHARED_FRAMES.lock().get(&frame).copied().unwrap_or(1))
+}
+
+/// Like `count`, but returns `None` instead of waiting for the lock
+pub fn try_count(frame: PhysFrame) -> Option<u32> {
+    without_interrupts(|| Some(SHARED_FRAMES.try_lock()?.get(&frame).copied().unwrap_or(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::PhysAddr;
+
+    use super::*;
+
+    fn frame(index: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index * 4096))
+    }
+
+    // The table is global, so every test uses frames of its own
+
+    #[test]
+    fn release_reports_the_last_owner() {
+        let frame = frame(0x1000);
+        assert!(release(frame));
+
+        share(frame);
+        share(frame);
+        assert_eq!(count(frame), 3);
+        assert!(!release(frame));
+        assert!(!release(frame));
+        assert_eq!(count(frame), 1);
+        assert!(release(frame));
+    }
+
+    #[test]
+    fn try_release_leaves_the_last_entry() {
+        let frame = frame(0x2000);
+        share(frame);
+        assert_eq!(try_count(frame), Some(2));
+
+        assert_eq!(try_release(frame), Some(false));
+        assert!(SHARED_FRAMES.lock().contains_key(&frame));
+        assert_eq!(try_count(frame), Some(1));
+        assert_eq!(try_release(frame), Some(true));
+
+        // A frame left at one owner counts as untracked once reused
+        share(frame);
+        assert_eq!(count(frame), 2);
+        assert!(!release(frame));
+        assert!(!SHARED_FRAMES.lock().contains_key(&frame));
+    }
+
+    #[test]
+    fn try_variants_fail_while_locked() {
+        let frame = frame(0x3000);
+        let _guard = SHARED_FRAMES.lock();
+        assert_eq!(try_count(frame), None);
+        assert_eq!(try_release(frame), None);
+    }
+}
@@ -1,19 +1,46 @@
-#![no_std]
+// Host tests run on std, everything else is freestanding
+#![cfg_attr(not(test), no_std)]
 
-use core::intrinsics::transmute;
+extern crate alloc;
 
-use bootloader::BootInfo;
-use frame_allocator_bootinfo::FrameAllocatorBootInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::{boot_info::MemoryRegion, BootInfo};
+use frame_allocator_bitmap::FrameAllocatorBitmap;
+use paging::ActiveRoot;
+use physical_memory::OffsetPhysicalMemory;
 use spin::Mutex;
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{page_table::FrameError, OffsetPageTable, PageTable},
+    structures::paging::{OffsetPageTable, PhysFrame},
     PhysAddr, VirtAddr,
 };
+pub mod address_space;
 pub mod allocator;
-pub mod frame_allocator_bootinfo;
+#[cfg(feature = "debug-alloc")]
+pub mod debug_alloc;
+pub mod dma;
+pub mod fallible;
+pub mod frame_allocator_bitmap;
+pub mod frame_refcount;
+pub mod mmio;
+#[cfg(test)]
+mod mock;
 pub mod paging;
+pub mod physical_memory;
+pub mod protection;
+pub mod reclaim;
+pub mod region;
+pub mod slab;
 pub mod stack;
+pub mod stats;
+pub mod vma;
+pub mod zone;
+
+pub use dma::DmaBuffer;
+pub use mmio::{map_mmio, CacheMode, MmioMapping};
+pub use stats::stats;
+pub use zone::Zone;
 
 use lazy_static::lazy_static;
 
@@ -22,14 +49,22 @@ use lazy_static::lazy_static;
 // Used in the rest of the kernel to make allocations
 lazy_static! {
     pub static ref MAPPER: Mutex<Option<SendWrapper<OffsetPageTable<'static>>>> = Mutex::new(None);
-    pub static ref FRAME_ALLOCATOR: Mutex<Option<SendWrapper<FrameAllocatorBootInfo>>> =
+    pub static ref FRAME_ALLOCATOR: Mutex<Option<SendWrapper<FrameAllocatorBitmap>>> =
         Mutex::new(None);
+    /// The memory map the bootloader handed to the kernel
+    pub static ref MEMORY_REGIONS: Mutex<Option<&'static [MemoryRegion]>> = Mutex::new(None);
 }
 
+/// Virtual address the bootloader mapped the complete physical memory at
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Physical address of the level 4 table the kernel booted with
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
 pub struct SendWrapper<T>(T);
 
 unsafe impl Send for SendWrapper<OffsetPageTable<'static>> {}
-unsafe impl Send for SendWrapper<FrameAllocatorBootInfo> {}
+unsafe impl Send for SendWrapper<FrameAllocatorBitmap> {}
 
 impl<T> core::ops::Deref for SendWrapper<T> {
     type Target = T;
@@ -46,13 +81,23 @@ impl<T> core::ops::DerefMut for SendWrapper<T> {
 }
 
 pub fn init_allocator(boot_info: &BootInfo) {
-    let yolo: &'static BootInfo = unsafe { transmute(boot_info) };
-
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
 
-    let mapper: OffsetPageTable<'static> = unsafe { paging::init(phys_mem_offset) };
+    PHYSICAL_MEMORY_OFFSET.store(phys_mem_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LEVEL_4_TABLE.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+
+    protection::enable_nxe();
+    mmio::init_pat();
+
+    let mut mapper: OffsetPageTable<'static> = unsafe { paging::init(phys_mem_offset) };
+    region::check_windows_unused(mapper.level_4_table());
 
-    let frame_allocator = unsafe { FrameAllocatorBootInfo::init(yolo) };
+    let frame_allocator = unsafe {
+        FrameAllocatorBitmap::init(
+            &boot_info.memory_regions,
+            &OffsetPhysicalMemory::new(phys_mem_offset),
+        )
+    };
 
     // Set the global kernel mapper
     let mut mapper_static = MAPPER.lock();
@@ -61,43 +106,59 @@ pub fn init_allocator(boot_info: &BootInfo) {
     // Set the global frame allocator
     let mut frame_allocator_static = FRAME_ALLOCATOR.lock();
     *frame_allocator_static = Some(SendWrapper(frame_allocator));
+
+    // The boot info lives for as long as the kernel and nothing writes to the
+    // memory map after boot
+    let memory_regions: &'static [MemoryRegion] =
+        unsafe { &*(&*boot_info.memory_regions as *const [MemoryRegion]) };
+    *MEMORY_REGIONS.lock() = Some(memory_regions);
+
+    protection::enforce_kernel_permissions().expect("failed to protect the kernel mappings");
+}
+
+/// Returns where the physical memory is mapped in the kernel address space
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Returns the level 4 table of the kernel, as opposed to that of an
+/// `AddressSpace`
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
 }
 
+/// Translates `addr` through the active page table
 pub fn translate_address(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let table_indexes = [
-        addr.p4_index(),
-        addr.p3_index(),
-        addr.p2_index(),
-        addr.p1_index(),
-    ];
-    let mut frame = level_4_table_frame;
-
-    for &index in &table_indexes {
-        // convert the frame into a page table reference
-        let virt = physical_memory_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe { &*table_ptr };
-
-        // read the page table entry and update `frame`
-        let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
-        };
-    }
+    paging::translate(
+        addr,
+        &ActiveRoot,
+        &OffsetPhysicalMemory::new(physical_memory_offset),
+    )
+}
 
-    // calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+/// Runs `f` with interrupts disabled.
+///
+/// Host tests run in user mode, where touching the interrupt flag faults, so
+/// there it just runs `f`.
+pub(crate) fn without_interrupts<F, T>(f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    #[cfg(not(test))]
+    {
+        x86_64::instructions::interrupts::without_interrupts(f)
+    }
+    #[cfg(test)]
+    {
+        f()
+    }
 }
 
 pub fn with_mapper_and_allocator<F, T>(f: F) -> T
 where
-    F: FnOnce(&mut x86_64::structures::paging::OffsetPageTable, &mut FrameAllocatorBootInfo) -> T,
+    F: FnOnce(&mut x86_64::structures::paging::OffsetPageTable, &mut FrameAllocatorBitmap) -> T,
 {
-    x86_64::instructions::interrupts::without_interrupts(|| {
+    without_interrupts(|| {
         let mut mapper_lock = MAPPER.lock();
         let mapper = mapper_lock.as_mut().unwrap();
         let mut frame_allocator_lock = FRAME_ALLOCATOR.lock();
@@ -106,3 +167,22 @@ where
         f(mapper, frame_allocator)
     })
 }
+
+/// Like `with_mapper_and_allocator`, but returns `None` instead of waiting if
+/// either of them is locked or not set up yet.
+///
+/// Used on paths that can be reached while the mapper is already held, like
+/// the heap growing or the page fault handler.
+pub fn try_with_mapper_and_allocator<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&mut x86_64::structures::paging::OffsetPageTable, &mut FrameAllocatorBitmap) -> T,
+{
+    without_interrupts(|| {
+        let mut mapper_lock = MAPPER.try_lock()?;
+        let mapper = mapper_lock.as_mut()?;
+        let mut frame_allocator_lock = FRAME_ALLOCATOR.try_lock()?;
+        let frame_allocator = frame_allocator_lock.as_mut()?;
+
+        Some(f(mapper, frame_allocator))
+    })
+}
@@ -0,0 +1,359 @@
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::tlb,
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::{
+            FlagUpdateError, MapToError, MappedFrame, Translate, TranslateResult, UnmapError,
+        },
+        Mapper, Page, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    paging::{flush, gigabyte_pages_supported, map_physical_range, split_huge_page, unmap_range},
+    physical_memory::OffsetPhysicalMemory,
+    physical_memory_offset,
+    region::{allocate_region, release_region, RegionError, RegionKind},
+    translate_address, with_mapper_and_allocator,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+/// PAT memory types, as encoded in the `IA32_PAT` MSR
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_BACK: u64 = 0x07;
+const PAT_UNCACHED_MINUS: u64 = 0x06;
+
+/// The PAT layout the kernel runs with. It matches the power on default except
+/// for entry 1 (`WRITE_THROUGH` in a page table entry), which becomes write
+/// combining. Entries are only ever selected through `WRITE_THROUGH` and
+/// `NO_CACHE`, so the PAT bit, which moves around with the page size, stays
+/// clear.
+const PAT_LAYOUT: [u64; 8] = [
+    PAT_WRITE_BACK,
+    PAT_WRITE_COMBINING,
+    PAT_UNCACHED_MINUS,
+    PAT_UNCACHEABLE,
+    PAT_WRITE_BACK,
+    PAT_WRITE_THROUGH,
+    PAT_UNCACHED_MINUS,
+    PAT_UNCACHEABLE,
+];
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Programs the page attribute table so write combining mappings are possible.
+///
+/// Has to run on every CPU before it touches a write combining mapping.
+pub fn init_pat() {
+    // CPUID.01h:EDX.PAT
+    if unsafe { __cpuid(1) }.edx & (1 << 16) == 0 {
+        return;
+    }
+
+    let value = PAT_LAYOUT
+        .iter()
+        .enumerate()
+        .fold(0, |value, (index, &kind)| value | kind << (index * 8));
+
+    unsafe {
+        // Nothing may be cached under the old memory types once they change
+        asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(value);
+    }
+    tlb::flush_all();
+
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// How the CPU caches accesses to a mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Every access goes to the device, in order. The default for device
+    /// registers.
+    Uncached,
+    /// Writes are buffered and combined into bursts, reads are uncached. For
+    /// framebuffers and other memory the CPU mostly streams into.
+    WriteCombining,
+    /// Normal cacheable memory, for firmware tables and anything else that is
+    /// actually RAM.
+    WriteBack,
+}
+
+impl CacheMode {
+    /// Page table flags selecting this mode's PAT entry
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => {
+                PageTableFlags::WRITE_THROUGH
+            }
+            // Without the PAT, entry 1 is write through, so fall back to the
+            // safe choice
+            CacheMode::WriteCombining | CacheMode::Uncached => {
+                PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    /// The length was zero or the range wraps around
+    InvalidRange,
+    Region(RegionError),
+    Map(MapToError<Size4KiB>),
+    /// Changing the cache mode of an existing mapping failed
+    UpdateFlags(FlagUpdateError),
+}
+
+/// Physical memory mapped into the kernel's MMIO window.
+///
+/// The mapping is never executable. Dropping it unmaps the range and releases
+/// the virtual region, the physical memory itself is left alone. Dropping takes
+/// the global mapper, so it must not happen inside `with_mapper_and_allocator`.
+#[derive(Debug)]
+pub struct MmioMapping {
+    /// Page aligned start of the mapping
+    start: VirtAddr,
+    phys: PhysAddr,
+    len: u64,
+}
+
+/// Maps `len` bytes of physical memory starting at `phys` with the given cache
+/// mode.
+///
+/// The virtual region is aligned like the physical address, so large ranges
+/// like framebuffers end up on huge pages. For any mode other than write back,
+/// the physical memory mapping of the range is switched to the same mode, so
+/// the two never alias with different memory types. It keeps that mode after
+/// the mapping is dropped, device memory has no business being cached anyway.
+/// Mapping the same range twice with different modes is not supported.
+pub fn map_mmio(phys: PhysAddr, len: u64, mode: CacheMode) -> Result<MmioMapping, MmioError> {
+    if len == 0 || phys.as_u64().checked_add(len).is_none() {
+        return Err(MmioError::InvalidRange);
+    }
+
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let size = mapped_size(phys, len);
+
+    let align = if gigabyte_pages_supported() && fits::<Size1GiB>(phys_start, size) {
+        Size1GiB::SIZE
+    } else if fits::<Size2MiB>(phys_start, size) {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    };
+
+    let region = allocate_region(RegionKind::Mmio, size, align).map_err(MmioError::Region)?;
+
+    if mode != CacheMode::WriteBack {
+        if let Err(err) = unsafe { set_physical_map_cache_mode(phys_start, size, mode) } {
+            release_region(region.start()).expect("MMIO region was not allocated");
+            return Err(err);
+        }
+    }
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | mode.flags();
+
+    let mapped = with_mapper_and_allocator(|mapper, frame_allocator| unsafe {
+        map_physical_range(
+            mapper,
+            frame_allocator,
+            region.start(),
+            phys_start,
+            size,
+            flags,
+        )
+    });
+
+    if let Err(err) = mapped {
+        // Whatever part did get mapped is covered by the region, undo it
+        // before handing the region back
+        with_mapper_and_allocator(|mapper, _| unsafe {
+            let _ = unmap_range(mapper, region.start(), size);
+        });
+        release_region(region.start()).expect("MMIO region was not allocated");
+
+        return Err(MmioError::Map(err));
+    }
+
+    Ok(MmioMapping {
+        start: region.start(),
+        phys,
+        len,
+    })
+}
+
+/// Switches the existing mappings of `[start, start + size)` to `mode` in
+/// place, keeping the frames and all other flags. Huge pages that reach past
+/// the range are split first, so nothing outside of it changes.
+///
+/// Unlike mapping the memory a second time with `map_mmio`, this never leaves
+/// two mappings of one frame with different memory types, which the CPU does
+/// not support.
+///
+/// # Safety
+/// Nothing may rely on the old memory type, and there must be no other mapping
+/// of the frames with a different one.
+pub unsafe fn set_cache_mode(start: VirtAddr, size: u64, mode: CacheMode) -> Result<(), MmioError> {
+    let cache_flags = PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE;
+    let end = start + size;
+
+    with_mapper_and_allocator(|mapper, frame_allocator| {
+        let memory = OffsetPhysicalMemory::new(mapper.phys_offset());
+        let mut virt = start;
+
+        while virt < end {
+            let (frame, flags) = match mapper.translate(virt) {
+                TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+                _ => return Err(MmioError::UpdateFlags(FlagUpdateError::PageNotMapped)),
+            };
+            let page_start = virt.align_down(frame.size());
+
+            let huge = !matches!(frame, MappedFrame::Size4KiB(_));
+            if huge && (page_start < start || page_start + frame.size() > end) {
+                split_huge_page(mapper.level_4_table(), &memory, frame_allocator, virt)
+                    .map_err(MmioError::Map)?;
+                continue;
+            }
+
+            let flags = (flags - cache_flags) | mode.flags();
+            match frame {
+                MappedFrame::Size4KiB(_) => flush(
+                    mapper
+                        .update_flags(Page::<Size4KiB>::containing_address(page_start), flags)
+                        .map_err(MmioError::UpdateFlags)?,
+                ),
+                MappedFrame::Size2MiB(_) => flush(
+                    mapper
+                        .update_flags(Page::<Size2MiB>::containing_address(page_start), flags)
+                        .map_err(MmioError::UpdateFlags)?,
+                ),
+                MappedFrame::Size1GiB(_) => flush(
+                    mapper
+                        .update_flags(Page::<Size1GiB>::containing_address(page_start), flags)
+                        .map_err(MmioError::UpdateFlags)?,
+                ),
+            }
+
+            virt = page_start + frame.size();
+        }
+
+        Ok(())
+    })?;
+
+    // Lines cached under the old memory type must not be written back later
+    asm!("wbinvd", options(nostack, preserves_flags));
+
+    Ok(())
+}
+
+/// Switches the physical memory mapping of `[phys, phys + size)` to `mode`, if
+/// it covers the range.
+///
+/// # Safety
+/// Same as `set_cache_mode`.
+unsafe fn set_physical_map_cache_mode(
+    phys: PhysAddr,
+    size: u64,
+    mode: CacheMode,
+) -> Result<(), MmioError> {
+    let offset = physical_memory_offset();
+    let alias = offset + phys.as_u64();
+
+    let covered = translate_address(alias, offset).is_some()
+        && translate_address(alias + (size - 1), offset).is_some();
+    if !covered {
+        return Ok(());
+    }
+
+    set_cache_mode(alias, size, mode)
+}
+
+impl MmioMapping {
+    /// Virtual address of the first mapped byte, which corresponds to `phys`
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.start + page_offset(self.phys)
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes actually mapped, including the partial pages at both
+    /// ends
+    pub fn mapped_len(&self) -> u64 {
+        mapped_size(self.phys, self.len)
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.virt_addr().as_mut_ptr()
+    }
+
+    /// Gives up ownership of the mapping without unmapping it, returning the
+    /// virtual address of the first byte.
+    pub fn into_raw(self) -> VirtAddr {
+        let virt = self.virt_addr();
+        core::mem::forget(self);
+        virt
+    }
+
+    /// Takes back ownership of a mapping given up with `into_raw`
+    ///
+    /// # Safety
+    /// `virt`, `phys` and `len` must be those of a mapping passed to
+    /// `into_raw`, and ownership must only be taken back once.
+    pub unsafe fn from_raw(virt: VirtAddr, phys: PhysAddr, len: u64) -> Self {
+        Self {
+            start: virt - page_offset(phys),
+            phys,
+            len,
+        }
+    }
+
+    unsafe fn unmap(&self) -> Result<(), UnmapError> {
+        with_mapper_and_allocator(|mapper, _| unmap_range(mapper, self.start, self.mapped_len()))
+    }
+}
+
+impl Drop for MmioMapping {
+    fn drop(&mut self) {
+        unsafe { self.unmap() }.expect("MMIO mapping was not mapped");
+
+        release_region(self.start).expect("MMIO region was not allocated");
+    }
+}
+
+fn page_offset(phys: PhysAddr) -> u64 {
+    phys - phys.align_down(Size4KiB::SIZE)
+}
+
+/// Size of the page aligned range covering `len` bytes at `phys`
+fn mapped_size(phys: PhysAddr, len: u64) -> u64 {
+    (page_offset(phys) + len + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1)
+}
+
+/// Whether a page aligned range can start with a page of size `S`
+fn fits<S: PageSize>(phys_start: PhysAddr, size: u64) -> bool {
+    phys_start.is_aligned(S::SIZE) && size >= S::SIZE
+}
@@ -0,0 +1,96 @@
+//! In-memory stand-in for physical memory, so the paging code can be tested
+//! on the host.
+
+use alloc::{boxed::Box, vec, vec::Vec};
+
+use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::{
+    structures::paging::{FrameAllocator, MappedPageTable, PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    frame_allocator_bitmap::FrameAllocatorBitmap,
+    paging,
+    physical_memory::{PhysicalMemory, PhysicalMemoryMapping},
+};
+
+#[derive(Clone)]
+#[repr(C, align(4096))]
+struct Frame([u8; Size4KiB::SIZE as usize]);
+
+/// "Physical memory" backed by a host buffer, starting at physical address
+/// zero
+pub struct MockPhysicalMemory {
+    frames: Vec<Frame>,
+}
+
+impl MockPhysicalMemory {
+    pub fn new(size: u64) -> Self {
+        let frame = Frame([0; Size4KiB::SIZE as usize]);
+
+        Self {
+            frames: vec![frame; (size / Size4KiB::SIZE) as usize],
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.frames.len() as u64 * Size4KiB::SIZE
+    }
+}
+
+impl PhysicalMemory for MockPhysicalMemory {
+    fn virt_addr(&self, phys: PhysAddr) -> VirtAddr {
+        assert!(
+            phys.as_u64() < self.size(),
+            "access to {:?} outside of the mock memory",
+            phys
+        );
+
+        VirtAddr::from_ptr(self.frames.as_ptr()) + phys.as_u64()
+    }
+}
+
+pub type MockMapper = MappedPageTable<'static, PhysicalMemoryMapping<&'static MockPhysicalMemory>>;
+
+/// Mock memory with a frame allocator and an empty page table in it
+pub struct MockSystem {
+    pub memory: &'static MockPhysicalMemory,
+    pub level_4_frame: PhysFrame,
+    pub frame_allocator: FrameAllocatorBitmap,
+}
+
+impl MockSystem {
+    /// Creates a system whose whole memory is usable
+    pub fn new(size: u64) -> Self {
+        // Leaked, as the frame allocator keeps its bitmap in there
+        let memory: &'static MockPhysicalMemory =
+            Box::leak(Box::new(MockPhysicalMemory::new(size)));
+
+        let regions = [MemoryRegion {
+            start: 0,
+            end: memory.size(),
+            kind: MemoryRegionKind::Usable,
+        }];
+        let mut frame_allocator = unsafe { FrameAllocatorBitmap::init(&regions, memory) };
+
+        let level_4_frame = frame_allocator.allocate_frame().unwrap();
+
+        Self {
+            memory,
+            level_4_frame,
+            frame_allocator,
+        }
+    }
+
+    /// A mapper for the page table of this system, next to its allocator
+    pub fn parts(&mut self) -> (MockMapper, &mut FrameAllocatorBitmap) {
+        let mapper = unsafe { paging::mapper(&self.level_4_frame, self.memory) };
+
+        (mapper, &mut self.frame_allocator)
+    }
+
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        paging::translate(addr, &self.level_4_frame, &self.memory)
+    }
+}
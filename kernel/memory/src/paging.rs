@@ -1,6 +1,39 @@
+use core::arch::x86_64::__cpuid;
+
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::OffsetPageTable;
-use x86_64::{structures::paging::PageTable, VirtAddr};
+use x86_64::structures::paging::{
+    mapper::{
+        MapToError, MappedFrame, MapperAllSizes, MapperFlush, Translate, TranslateResult,
+        UnmapError,
+    },
+    page_table::{FrameError, PageTableEntry},
+    FrameAllocator, FrameDeallocator, MappedPageTable, Mapper, OffsetPageTable, Page, PageSize,
+    PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{structures::paging::PageTable, PhysAddr, VirtAddr};
+
+use crate::physical_memory::{OffsetPhysicalMemory, PhysicalMemory, PhysicalMemoryMapping};
+
+/// Where a page table hierarchy starts
+pub trait PageTableRoot {
+    fn level_4_frame(&self) -> PhysFrame;
+}
+
+/// The level 4 table currently loaded in CR3
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveRoot;
+
+impl PageTableRoot for ActiveRoot {
+    fn level_4_frame(&self) -> PhysFrame {
+        Cr3::read().0
+    }
+}
+
+impl PageTableRoot for PhysFrame {
+    fn level_4_frame(&self) -> PhysFrame {
+        *self
+    }
+}
 
 /// Initialize a new `OffsetPageTable`
 ///
@@ -25,13 +58,595 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 /// once to avoid aliasing `&mut` references (which is undefined
 /// behavior).
 pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    // Read directly from the paging control register
-    let (level_4_table_frame, _) = Cr3::read();
+    level_4_table(
+        &ActiveRoot,
+        &OffsetPhysicalMemory::new(physical_memory_offset),
+    )
+}
 
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+/// Returns a mutable reference to the level 4 table of `root`.
+///
+/// # Safety
+///
+/// Same as `active_level_4_table`, `memory` must give access to the table and
+/// no other `&mut` reference to it may exist.
+pub unsafe fn level_4_table<'a>(
+    root: &impl PageTableRoot,
+    memory: &impl PhysicalMemory,
+) -> &'a mut PageTable {
+    let phys = root.level_4_frame().start_address();
+    let page_table_ptr: *mut PageTable = memory.virt_addr(phys).as_mut_ptr();
 
     // SAFETY: The caller was warned about aliasing `&mut` references
     &mut *page_table_ptr
 }
+
+/// Creates a mapper for the page tables below `root`, reaching them through
+/// `memory`
+///
+/// # Safety
+///
+/// Same as `level_4_table`.
+pub unsafe fn mapper<'a, M: PhysicalMemory>(
+    root: &impl PageTableRoot,
+    memory: M,
+) -> MappedPageTable<'a, PhysicalMemoryMapping<M>> {
+    let level_4_table = level_4_table(root, &memory);
+
+    MappedPageTable::new(level_4_table, PhysicalMemoryMapping(memory))
+}
+
+/// Translates `addr` through the page tables below `root`, including huge
+/// pages
+pub fn translate(
+    addr: VirtAddr,
+    root: &impl PageTableRoot,
+    memory: &impl PhysicalMemory,
+) -> Option<PhysAddr> {
+    let table_indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut frame = root.level_4_frame();
+
+    for (level, &index) in table_indexes.iter().enumerate() {
+        // convert the frame into a page table reference
+        let table_ptr: *const PageTable = memory.virt_addr(frame.start_address()).as_ptr();
+        let table = unsafe { &*table_ptr };
+
+        // read the page table entry and update `frame`
+        let entry = &table[index];
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => {
+                // A huge entry in the P3 table maps 1 GiB and one in the P2
+                // table maps 2 MiB, the rest of the address is the offset
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => return None,
+                };
+
+                let base = entry.addr().align_down(page_size);
+                return Some(base + (addr.as_u64() & (page_size - 1)));
+            }
+        };
+    }
+
+    // calculate the physical address by adding the page offset
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+}
+
+/// Flushes a changed page from the TLB.
+///
+/// Host tests run in user mode, where `invlpg` faults and there is no TLB to
+/// speak of, so they skip it.
+pub(crate) fn flush<S: PageSize>(flush: MapperFlush<S>) {
+    #[cfg(not(test))]
+    flush.flush();
+    #[cfg(test)]
+    flush.ignore();
+}
+
+/// Maps `size` bytes of physical memory starting at `phys_start` to
+/// `virt_start`.
+///
+/// Each step uses the largest page size that both addresses are aligned to and
+/// that still fits in the remaining length, so large regions like the
+/// framebuffer end up on 2 MiB or 1 GiB pages.
+///
+/// # Safety
+///
+/// The caller must guarantee that the physical range is not owned by anything
+/// else that would be violated by an additional mapping, and that the virtual
+/// range is unused.
+pub unsafe fn map_physical_range(
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt_start: VirtAddr,
+    phys_start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut offset = 0;
+
+    while offset < size {
+        let virt = virt_start + offset;
+        let phys = phys_start + offset;
+        let remaining = size - offset;
+
+        offset += if gigabyte_pages_supported() && fits::<Size1GiB>(virt, phys, remaining) {
+            map_page::<Size1GiB>(mapper, frame_allocator, virt, phys, flags)?
+        } else if fits::<Size2MiB>(virt, phys, remaining) {
+            map_page::<Size2MiB>(mapper, frame_allocator, virt, phys, flags)?
+        } else {
+            map_page::<Size4KiB>(mapper, frame_allocator, virt, phys, flags)?
+        };
+    }
+
+    Ok(())
+}
+
+/// Maps `size` bytes of freshly allocated memory at `virt_start`.
+///
+/// Whenever the virtual address is 2 MiB aligned and enough of the range is
+/// left, a 2 MiB frame is requested and mapped as a huge page. If no such
+/// frame is available it falls back to 4 KiB frames.
+///
+/// On failure the part of the range mapped so far is unmapped and its frames
+/// are freed, so the range can be mapped again later.
+pub fn map_anonymous_range<A>(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut A,
+    virt_start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    let mut offset = 0;
+
+    while offset < size {
+        let virt = virt_start + offset;
+
+        match map_anonymous_page(mapper, frame_allocator, virt, size - offset, flags) {
+            Ok(mapped) => offset += mapped,
+            Err(err) => {
+                unsafe { free_anonymous_range(mapper, frame_allocator, virt_start, offset) };
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps a fresh frame at `virt`, a 2 MiB one if one is available and fits in
+/// `remaining`, returning the number of bytes mapped. The frame is freed again
+/// if mapping it fails.
+fn map_anonymous_page<A>(
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut A,
+    virt: VirtAddr,
+    remaining: u64,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    if virt.is_aligned(Size2MiB::SIZE) && remaining >= Size2MiB::SIZE {
+        if let Some(frame) = FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator) {
+            let phys = frame.start_address();
+            let mapped =
+                unsafe { map_page::<Size2MiB>(mapper, frame_allocator, virt, phys, flags) };
+            if mapped.is_err() {
+                unsafe { FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame) };
+            }
+            return mapped;
+        }
+    }
+
+    let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let phys = frame.start_address();
+    let mapped = unsafe { map_page::<Size4KiB>(mapper, frame_allocator, virt, phys, flags) };
+    if mapped.is_err() {
+        unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame) };
+    }
+
+    mapped
+}
+
+/// Unmaps `size` bytes mapped by `map_anonymous_range` and frees their frames.
+///
+/// # Safety
+///
+/// The caller must guarantee that nothing uses the range anymore.
+unsafe fn free_anonymous_range<A>(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut A,
+    virt_start: VirtAddr,
+    size: u64,
+) where
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>,
+{
+    let mut offset = 0;
+
+    while offset < size {
+        let virt = virt_start + offset;
+
+        offset += match mapper.translate(virt) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            } => {
+                let (frame, flushed) =
+                    Mapper::<Size4KiB>::unmap(mapper, Page::containing_address(virt))
+                        .expect("anonymous page was not mapped");
+                flush(flushed);
+                FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame);
+                Size4KiB::SIZE
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                let (frame, flushed) =
+                    Mapper::<Size2MiB>::unmap(mapper, Page::containing_address(virt))
+                        .expect("anonymous page was not mapped");
+                flush(flushed);
+                FrameDeallocator::<Size2MiB>::deallocate_frame(frame_allocator, frame);
+                Size2MiB::SIZE
+            }
+            _ => panic!("{:?} is not mapped by map_anonymous_range", virt),
+        };
+    }
+}
+
+/// Unmaps `size` bytes starting at `virt_start` without freeing the frames,
+/// whatever page sizes the range was mapped with.
+///
+/// # Safety
+///
+/// The caller must guarantee that nothing uses the range anymore, and that
+/// the pages at its ends do not extend outside of it.
+pub unsafe fn unmap_range(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    virt_start: VirtAddr,
+    size: u64,
+) -> Result<(), UnmapError> {
+    let mut offset = 0;
+
+    while offset < size {
+        let virt = virt_start + offset;
+
+        let frame = match mapper.translate(virt) {
+            TranslateResult::Mapped { frame, .. } => frame,
+            TranslateResult::NotMapped => return Err(UnmapError::PageNotMapped),
+            TranslateResult::InvalidFrameAddress(addr) => {
+                return Err(UnmapError::InvalidFrameAddress(addr))
+            }
+        };
+
+        match frame {
+            MappedFrame::Size4KiB(_) => unmap_page::<Size4KiB>(mapper, virt)?,
+            MappedFrame::Size2MiB(_) => unmap_page::<Size2MiB>(mapper, virt)?,
+            MappedFrame::Size1GiB(_) => unmap_page::<Size1GiB>(mapper, virt)?,
+        }
+
+        offset += frame.size();
+    }
+
+    Ok(())
+}
+
+/// Splits the 1 GiB or 2 MiB page mapping `virt` into pages of the next
+/// smaller size, mapping the same frames with the same flags. The PAT bit of
+/// the huge page has to be clear, as it moves with the page size.
+///
+/// # Safety
+///
+/// `memory` must give access to the page tables below `level_4_table`, and
+/// `virt` must be mapped by a huge page.
+pub unsafe fn split_huge_page(
+    level_4_table: &mut PageTable,
+    memory: &impl PhysicalMemory,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt: VirtAddr,
+) -> Result<(), MapToError<Size4KiB>> {
+    let p3 = table_at(memory, &level_4_table[virt.p4_index()]);
+    let p3_entry = &mut p3[virt.p3_index()];
+    let (entry, child_size) = if p3_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        (p3_entry, Size2MiB::SIZE)
+    } else {
+        let p2 = table_at(memory, p3_entry);
+        let p2_entry = &mut p2[virt.p2_index()];
+        assert!(
+            p2_entry.flags().contains(PageTableFlags::HUGE_PAGE),
+            "{:?} is not mapped by a huge page",
+            virt
+        );
+        (p2_entry, Size4KiB::SIZE)
+    };
+
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let table: &mut PageTable = &mut *memory.virt_addr(frame.start_address()).as_mut_ptr();
+
+    let mut flags = entry.flags();
+    if child_size == Size4KiB::SIZE {
+        flags.remove(PageTableFlags::HUGE_PAGE);
+    }
+    for (index, child) in table.iter_mut().enumerate() {
+        child.set_addr(entry.addr() + index as u64 * child_size, flags);
+    }
+
+    // Access rights are combined along the walk, the caching of the memory is
+    // up to the pages
+    let table_flags = entry.flags()
+        & (PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE);
+    entry.set_frame(frame, table_flags);
+
+    // Invalidates the TLB entry of the whole huge page
+    flush(MapperFlush::<Size4KiB>::new(Page::containing_address(virt)));
+
+    Ok(())
+}
+
+/// The table a present, non huge entry points at
+///
+/// # Safety
+///
+/// `memory` must give access to the table, and no other reference to it may
+/// exist.
+unsafe fn table_at<'a>(memory: &impl PhysicalMemory, entry: &PageTableEntry) -> &'a mut PageTable {
+    assert!(
+        entry.flags().contains(PageTableFlags::PRESENT),
+        "page table entry is not present"
+    );
+
+    &mut *memory.virt_addr(entry.addr()).as_mut_ptr()
+}
+
+/// Returns whether the CPU can map 1 GiB pages (`CPUID.80000001h:EDX.Page1GB`)
+pub fn gigabyte_pages_supported() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+
+    max_extended_leaf >= 0x8000_0001 && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 26) != 0
+}
+
+fn fits<S: PageSize>(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> bool {
+    virt.is_aligned(S::SIZE) && phys.is_aligned(S::SIZE) && remaining >= S::SIZE
+}
+
+/// Maps a single page of size `S`, returning the number of bytes mapped
+unsafe fn map_page<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let page = Page::<S>::from_start_address(virt).expect("virtual address not aligned");
+    let frame = PhysFrame::<S>::from_start_address(phys).expect("physical address not aligned");
+
+    let mapped = mapper
+        .map_to(page, frame, flags, frame_allocator)
+        .map_err(|err| match err {
+            MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => {
+                MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+            }
+        })?;
+    flush(mapped);
+
+    Ok(S::SIZE)
+}
+
+fn unmap_page<S: PageSize>(mapper: &mut impl Mapper<S>, virt: VirtAddr) -> Result<(), UnmapError> {
+    let page = Page::<S>::from_start_address(virt).expect("page extends outside the range");
+
+    flush(mapper.unmap(page)?.1);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use x86_64::structures::paging::{mapper::TranslateResult, FrameAllocator, Translate};
+
+    use super::*;
+    use crate::mock::MockSystem;
+
+    const MEMORY_SIZE: u64 = 16 * 1024 * 1024;
+    const FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+    fn mapped_size(mapper: &impl Translate, addr: VirtAddr) -> u64 {
+        match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, .. } => frame.size(),
+            _ => panic!("{:?} is not mapped", addr),
+        }
+    }
+
+    #[test]
+    fn map_and_translate() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x1234_5000));
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator).unwrap();
+        unsafe { flush(mapper.map_to(page, frame, FLAGS, frame_allocator).unwrap()) };
+
+        assert_eq!(
+            system.translate(page.start_address() + 0x123u64),
+            Some(frame.start_address() + 0x123u64)
+        );
+        assert_eq!(
+            system.translate(page.start_address() + Size4KiB::SIZE),
+            None
+        );
+    }
+
+    #[test]
+    fn map_physical_range_uses_huge_pages() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        // Far above the mock memory, only the page tables are ever touched
+        let virt = VirtAddr::new(Size1GiB::SIZE);
+        let phys = PhysAddr::new(4 * Size1GiB::SIZE);
+        let size = Size1GiB::SIZE + Size2MiB::SIZE + Size4KiB::SIZE;
+        unsafe { map_physical_range(&mut mapper, frame_allocator, virt, phys, size, FLAGS) }
+            .unwrap();
+
+        let expected_first = if gigabyte_pages_supported() {
+            Size1GiB::SIZE
+        } else {
+            Size2MiB::SIZE
+        };
+        assert_eq!(mapped_size(&mapper, virt), expected_first);
+        assert_eq!(mapped_size(&mapper, virt + Size1GiB::SIZE), Size2MiB::SIZE);
+        assert_eq!(
+            mapped_size(&mapper, virt + Size1GiB::SIZE + Size2MiB::SIZE),
+            Size4KiB::SIZE
+        );
+
+        for offset in [0, 0x1234_5678, Size1GiB::SIZE + 0x1_2345, size - 1] {
+            assert_eq!(system.translate(virt + offset), Some(phys + offset));
+        }
+        assert_eq!(system.translate(virt + size), None);
+    }
+
+    #[test]
+    fn map_anonymous_range_prefers_2mib_frames() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        let virt = VirtAddr::new(Size1GiB::SIZE);
+        let size = Size2MiB::SIZE + 3 * Size4KiB::SIZE;
+        map_anonymous_range(&mut mapper, frame_allocator, virt, size, FLAGS).unwrap();
+
+        assert_eq!(mapped_size(&mapper, virt), Size2MiB::SIZE);
+        assert_eq!(mapped_size(&mapper, virt + Size2MiB::SIZE), Size4KiB::SIZE);
+        assert_eq!(system.translate(virt + size), None);
+    }
+
+    #[test]
+    fn map_anonymous_range_undoes_a_partial_mapping() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        // Creates the page tables for the range, which stay around
+        let virt = VirtAddr::new(Size1GiB::SIZE);
+        map_anonymous_range(&mut mapper, frame_allocator, virt, Size4KiB::SIZE, FLAGS).unwrap();
+        unsafe { free_anonymous_range(&mut mapper, frame_allocator, virt, Size4KiB::SIZE) };
+        let free = frame_allocator.free_frames();
+
+        // Leaves 4 KiB frames only, and fewer of them than the range needs
+        let mut taken = Vec::new();
+        while frame_allocator.free_frames() > 16 {
+            taken.push(FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator).unwrap());
+        }
+
+        let size = 32 * Size4KiB::SIZE;
+        assert!(matches!(
+            map_anonymous_range(&mut mapper, frame_allocator, virt, size, FLAGS),
+            Err(MapToError::FrameAllocationFailed)
+        ));
+        assert_eq!(frame_allocator.free_frames(), 16);
+        assert_eq!(system.translate(virt), None);
+
+        // The same range can be mapped once there is memory again
+        let (mut mapper, frame_allocator) = system.parts();
+        for frame in taken {
+            unsafe { FrameDeallocator::<Size4KiB>::deallocate_frame(frame_allocator, frame) };
+        }
+        assert_eq!(frame_allocator.free_frames(), free);
+        map_anonymous_range(&mut mapper, frame_allocator, virt, size, FLAGS).unwrap();
+        assert_eq!(mapped_size(&mapper, virt + size - 1u64), Size4KiB::SIZE);
+    }
+
+    #[test]
+    fn split_huge_page_keeps_frames_and_flags() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let memory = system.memory;
+        let (mut mapper, frame_allocator) = system.parts();
+
+        let virt = VirtAddr::new(Size1GiB::SIZE);
+        let phys = PhysAddr::new(4 * Size1GiB::SIZE);
+        let flags = FLAGS | PageTableFlags::NO_CACHE | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            map_physical_range(
+                &mut mapper,
+                frame_allocator,
+                virt,
+                phys,
+                Size2MiB::SIZE,
+                flags,
+            )
+            .unwrap();
+            split_huge_page(mapper.level_4_table(), &memory, frame_allocator, virt).unwrap();
+        }
+
+        for offset in [0, 0x1234, Size2MiB::SIZE - 1] {
+            assert_eq!(mapped_size(&mapper, virt + offset), Size4KiB::SIZE);
+            assert_eq!(system.translate(virt + offset), Some(phys + offset));
+            match mapper.translate(virt + offset) {
+                TranslateResult::Mapped { flags: mapped, .. } => assert_eq!(mapped, flags),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn mapping_twice_fails() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        let virt = VirtAddr::new(0x4000_0000);
+        let phys = PhysAddr::new(0x20_0000);
+        unsafe { map_physical_range(&mut mapper, frame_allocator, virt, phys, 0x1000, FLAGS) }
+            .unwrap();
+
+        let err =
+            unsafe { map_physical_range(&mut mapper, frame_allocator, virt, phys, 0x1000, FLAGS) };
+        assert!(matches!(err, Err(MapToError::PageAlreadyMapped(_))));
+    }
+
+    #[test]
+    fn unmap_range_removes_all_page_sizes() {
+        let mut system = MockSystem::new(MEMORY_SIZE);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        let virt = VirtAddr::new(Size1GiB::SIZE);
+        let phys = PhysAddr::new(4 * Size1GiB::SIZE);
+        let size = Size1GiB::SIZE + Size2MiB::SIZE + Size4KiB::SIZE;
+        unsafe {
+            map_physical_range(&mut mapper, frame_allocator, virt, phys, size, FLAGS).unwrap();
+            unmap_range(&mut mapper, virt, size).unwrap();
+        }
+
+        for offset in [0, Size1GiB::SIZE, Size1GiB::SIZE + Size2MiB::SIZE] {
+            assert_eq!(system.translate(virt + offset), None);
+        }
+        assert!(matches!(
+            unsafe { unmap_range(&mut mapper, virt, Size4KiB::SIZE) },
+            Err(UnmapError::PageNotMapped)
+        ));
+    }
+}
@@ -0,0 +1,47 @@
+use x86_64::{
+    structures::paging::{mapper::PageTableFrameMapping, PageTable, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// Gives access to physical memory, like page tables or the frame bitmap.
+///
+/// The kernel reaches physical memory through the bootloader's offset mapping,
+/// host tests through a plain buffer.
+pub trait PhysicalMemory {
+    /// Returns the address `phys` can be accessed at
+    fn virt_addr(&self, phys: PhysAddr) -> VirtAddr;
+}
+
+/// Physical memory mapped linearly at an offset, like the bootloader does
+#[derive(Debug, Clone, Copy)]
+pub struct OffsetPhysicalMemory {
+    offset: VirtAddr,
+}
+
+impl OffsetPhysicalMemory {
+    pub fn new(offset: VirtAddr) -> Self {
+        Self { offset }
+    }
+}
+
+impl PhysicalMemory for OffsetPhysicalMemory {
+    fn virt_addr(&self, phys: PhysAddr) -> VirtAddr {
+        self.offset + phys.as_u64()
+    }
+}
+
+impl<M: PhysicalMemory> PhysicalMemory for &M {
+    fn virt_addr(&self, phys: PhysAddr) -> VirtAddr {
+        (**self).virt_addr(phys)
+    }
+}
+
+/// Lets a `MappedPageTable` find its tables through a `PhysicalMemory`
+#[derive(Debug, Clone, Copy)]
+pub struct PhysicalMemoryMapping<M>(pub M);
+
+unsafe impl<M: PhysicalMemory> PageTableFrameMapping for PhysicalMemoryMapping<M> {
+    fn frame_to_pointer(&self, frame: PhysFrame) -> *mut PageTable {
+        self.0.virt_addr(frame.start_address()).as_mut_ptr()
+    }
+}
@@ -0,0 +1,295 @@
+use alloc::vec::Vec;
+
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{FlagUpdateError, MappedFrame, MapperAllSizes, Translate, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+        Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::{
+    kernel_level_4_frame, paging::flush, physical_memory_offset, with_mapper_and_allocator,
+    MEMORY_REGIONS,
+};
+
+/// ELF program header types and flags
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    /// Start of the kernel ELF header, provided by the linker. The header is
+    /// part of the first loaded segment, so it is mapped like the rest of the
+    /// kernel.
+    static __ehdr_start: u8;
+}
+
+/// Access rights of a kernel mapping.
+///
+/// Nothing is ever both writable and executable; data is mapped `ReadWrite`,
+/// which includes `NO_EXECUTE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permissions {
+    Read,
+    ReadWrite,
+    ReadExecute,
+}
+
+impl Permissions {
+    /// Flags of a present kernel page with these permissions
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            Permissions::Read => PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE,
+            Permissions::ReadWrite => {
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+            }
+            Permissions::ReadExecute => PageTableFlags::PRESENT,
+        }
+    }
+}
+
+/// Makes the CPU honour `NO_EXECUTE`, and read only pages in ring 0
+pub fn enable_nxe() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// Changes the permissions of the mapped range `[start, start + size)`,
+/// keeping the frames and all other flags.
+///
+/// # Safety
+///
+/// Nothing may rely on the access rights being taken away, like code running
+/// from a page that becomes non executable.
+pub unsafe fn protect_range(
+    mapper: &mut (impl MapperAllSizes + Translate),
+    start: VirtAddr,
+    size: u64,
+    permissions: Permissions,
+) -> Result<(), FlagUpdateError> {
+    let mut offset = 0;
+
+    while offset < size {
+        let virt = start + offset;
+
+        let (frame, flags) = match mapper.translate(virt) {
+            TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+            _ => return Err(FlagUpdateError::PageNotMapped),
+        };
+
+        let flags =
+            (flags - (PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)) | permissions.flags();
+
+        match frame {
+            MappedFrame::Size4KiB(_) => update_flags::<Size4KiB>(mapper, virt, flags)?,
+            MappedFrame::Size2MiB(_) => update_flags::<Size2MiB>(mapper, virt, flags)?,
+            MappedFrame::Size1GiB(_) => update_flags::<Size1GiB>(mapper, virt, flags)?,
+        }
+
+        offset += frame.size();
+    }
+
+    Ok(())
+}
+
+/// Fails with `ParentEntryHugePage` if `virt` is inside a huge page, as the
+/// range would only cover part of it
+unsafe fn update_flags<S: PageSize>(
+    mapper: &mut impl Mapper<S>,
+    virt: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    let page =
+        Page::<S>::from_start_address(virt).map_err(|_| FlagUpdateError::ParentEntryHugePage)?;
+
+    flush(mapper.update_flags(page, flags)?);
+
+    Ok(())
+}
+
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    file_size: u64,
+    mem_size: u64,
+    align: u64,
+}
+
+/// Returns the program headers of the running kernel and the difference
+/// between their addresses and where the kernel actually got loaded
+fn kernel_program_headers() -> (&'static [ProgramHeader], u64) {
+    let header = unsafe { &__ehdr_start as *const u8 };
+
+    unsafe {
+        assert_eq!(
+            core::slice::from_raw_parts(header, 4),
+            b"\x7fELF",
+            "kernel ELF header not found"
+        );
+
+        let phoff = header.add(0x20).cast::<u64>().read_unaligned();
+        let phnum = header.add(0x38).cast::<u16>().read_unaligned();
+
+        let headers = core::slice::from_raw_parts(
+            header.add(phoff as usize).cast::<ProgramHeader>(),
+            phnum as usize,
+        );
+
+        // The segment holding the ELF header starts at file offset 0
+        let load_bias = headers
+            .iter()
+            .find(|ph| ph.kind == PT_LOAD)
+            .map_or(0, |ph| {
+                (header as u64).wrapping_sub(ph.vaddr.wrapping_sub(ph.offset))
+            });
+
+        (headers, load_bias)
+    }
+}
+
+/// Applies the permissions of the kernel's ELF segments to its mappings: code
+/// becomes read only and executable, read only data read only, and everything
+/// else non executable. The physical memory mapping is made non executable
+/// as well.
+///
+/// Segments that are both writable and executable are left alone, see
+/// `writable_executable_ranges`.
+pub fn enforce_kernel_permissions() -> Result<(), FlagUpdateError> {
+    let (headers, load_bias) = kernel_program_headers();
+
+    with_mapper_and_allocator(|mapper, _| {
+        let loads = headers.iter().filter(|ph| ph.kind == PT_LOAD);
+        // Relocated data is read only once the bootloader has applied the
+        // relocations
+        let relro = headers.iter().filter(|ph| ph.kind == PT_GNU_RELRO);
+
+        for ph in loads.chain(relro) {
+            let permissions = match (ph.kind, ph.flags & PF_W != 0, ph.flags & PF_X != 0) {
+                (PT_GNU_RELRO, _, _) => Permissions::Read,
+                (_, true, true) => continue,
+                (_, true, false) => Permissions::ReadWrite,
+                (_, false, true) => Permissions::ReadExecute,
+                (_, false, false) => Permissions::Read,
+            };
+
+            if ph.mem_size == 0 {
+                continue;
+            }
+
+            let start = VirtAddr::new(ph.vaddr.wrapping_add(load_bias));
+            let first = Page::<Size4KiB>::containing_address(start);
+            let last = Page::<Size4KiB>::containing_address(start + (ph.mem_size - 1));
+            let size = last.start_address() - first.start_address() + Size4KiB::SIZE;
+
+            unsafe { protect_range(mapper, first.start_address(), size, permissions)? };
+        }
+
+        protect_physical_memory_map(mapper);
+
+        Ok(())
+    })
+}
+
+/// Sets `NO_EXECUTE` on the level 4 entries of the physical memory mapping.
+/// The bootloader gives it level 4 entries of its own, so nothing else is
+/// affected.
+fn protect_physical_memory_map(mapper: &mut OffsetPageTable) {
+    let memory_end = match *MEMORY_REGIONS.lock() {
+        Some(regions) => regions.iter().map(|region| region.end).max().unwrap_or(0),
+        None => return,
+    };
+    if memory_end == 0 {
+        return;
+    }
+
+    let start = physical_memory_offset();
+    let end = start + (memory_end - 1);
+
+    let table = mapper.level_4_table();
+    for index in u16::from(start.p4_index())..=u16::from(end.p4_index()) {
+        let entry = &mut table[index as usize];
+
+        if !entry.is_unused() {
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+    }
+
+    tlb::flush_all();
+}
+
+/// A run of kernel memory that is both writable and executable
+#[derive(Debug, Clone, Copy)]
+pub struct WxRange {
+    pub start: VirtAddr,
+    pub size: u64,
+}
+
+/// Walks the kernel page table and returns every range that is writable and
+/// executable at the same time. Empty once `enforce_kernel_permissions` has
+/// done its job.
+///
+/// The walk allocates, so it cannot hold the mapper and reads the tables
+/// without locking. Meant for checks at boot, while nothing else maps memory.
+pub fn writable_executable_ranges() -> Vec<WxRange> {
+    let mut ranges = Vec::new();
+
+    unsafe { find_writable_executable(kernel_level_4_frame(), 4, 0, &mut ranges) };
+
+    ranges
+}
+
+/// Collects the writable and executable pages below the table at `level`.
+/// Access rights are the intersection of all levels, so a subtree is skipped
+/// as soon as an entry takes either one away.
+unsafe fn find_writable_executable(
+    frame: PhysFrame,
+    level: u8,
+    base: u64,
+    ranges: &mut Vec<WxRange>,
+) {
+    let table: &PageTable = &*(physical_memory_offset() + frame.start_address().as_u64()).as_ptr();
+    let entry_size = 1u64 << (12 + 9 * (level as u64 - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+
+        if entry.is_unused()
+            || !flags.contains(PageTableFlags::WRITABLE)
+            || flags.contains(PageTableFlags::NO_EXECUTE)
+        {
+            continue;
+        }
+
+        let start = base + index as u64 * entry_size;
+
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let addr = VirtAddr::new_truncate(start);
+
+            match ranges.last_mut() {
+                Some(last) if last.start.as_u64().wrapping_add(last.size) == addr.as_u64() => {
+                    last.size += entry_size
+                }
+                _ => ranges.push(WxRange {
+                    start: addr,
+                    size: entry_size,
+                }),
+            }
+        } else if let Ok(child) = entry.frame() {
+            find_writable_executable(child, level - 1, start, ranges);
+        }
+    }
+}
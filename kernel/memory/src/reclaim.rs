@@ -0,0 +1,74 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::without_interrupts;
+
+/// Maximum number of reclaimers that can be registered at the same time
+pub const MAX_RECLAIMERS: usize = 16;
+
+static RECLAIMERS: Mutex<[Option<Reclaimer>; MAX_RECLAIMERS]> = Mutex::new([None; MAX_RECLAIMERS]);
+
+/// Set while the reclaimers run, so an allocation failing inside one of them
+/// does not start another round
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// A callback that frees memory when the heap runs out, like dropping a cache
+#[derive(Debug, Clone, Copy)]
+pub struct Reclaimer {
+    pub name: &'static str,
+    /// Frees what it can and returns the number of bytes freed
+    pub reclaim: fn() -> usize,
+}
+
+/// Registers `reclaim` to run whenever an allocation fails.
+///
+/// It runs in the context of the failing allocation, so it may free memory,
+/// but should not allocate or wait on locks its subsystem could be holding.
+pub fn register_reclaimer(name: &'static str, reclaim: fn() -> usize) {
+    without_interrupts(|| {
+        let mut reclaimers = RECLAIMERS.lock();
+        let slot = reclaimers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("too many reclaimers");
+
+        *slot = Some(Reclaimer { name, reclaim });
+    });
+}
+
+pub fn unregister_reclaimer(name: &'static str) {
+    without_interrupts(|| {
+        let mut reclaimers = RECLAIMERS.lock();
+
+        if let Some(slot) = reclaimers
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(reclaimer) if reclaimer.name == name))
+        {
+            *slot = None;
+        }
+    });
+}
+
+/// Runs every registered reclaimer, returning the number of bytes they freed
+/// in total.
+///
+/// Called by the allocator before it gives up on an allocation.
+pub fn reclaim() -> usize {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+
+    // The reclaimers free memory, so they must run without the registry
+    // locked
+    let reclaimers = without_interrupts(|| *RECLAIMERS.lock());
+    let freed = reclaimers
+        .iter()
+        .flatten()
+        .map(|reclaimer| (reclaimer.reclaim)())
+        .sum();
+
+    RECLAIMING.store(false, Ordering::Release);
+
+    freed
+}
@@ -0,0 +1,361 @@
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Page, PageSize, PageTable, Size4KiB},
+    VirtAddr,
+};
+
+/// Maximum number of regions that can be live at the same time
+pub const MAX_REGIONS: usize = 256;
+
+/// Size of the window each kind of region is placed in, one level 4 entry
+const WINDOW_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+lazy_static! {
+    /// Kernel-wide virtual address space allocator
+    pub static ref VIRTUAL_REGIONS: Mutex<VirtualRegionAllocator> =
+        Mutex::new(VirtualRegionAllocator::new());
+}
+
+/// What a region of kernel virtual memory is used for.
+///
+/// Every kind gets its own window in the upper half, which has to be left
+/// untouched by the bootloader, see `check_windows_unused`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Stack,
+    Heap,
+    Mmio,
+}
+
+impl RegionKind {
+    pub const ALL: [RegionKind; 3] = [RegionKind::Stack, RegionKind::Heap, RegionKind::Mmio];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RegionKind::Stack => "stack",
+            RegionKind::Heap => "heap",
+            RegionKind::Mmio => "mmio",
+        }
+    }
+
+    /// Returns the start and end of the window regions of this kind live in
+    pub fn window(self) -> (VirtAddr, VirtAddr) {
+        let index = match self {
+            RegionKind::Stack => 0,
+            RegionKind::Heap => 1,
+            RegionKind::Mmio => 2,
+        };
+
+        let start = VirtAddr::new(0x_ffff_8000_0000_0000 + index * WINDOW_SIZE);
+        (start, start + WINDOW_SIZE)
+    }
+}
+
+/// Panics if the bootloader mapped anything into the window of a region kind,
+/// as regions handed out there would clash with it
+pub fn check_windows_unused(level_4_table: &PageTable) {
+    for kind in RegionKind::ALL {
+        let (start, _) = kind.window();
+        assert!(
+            level_4_table[start.p4_index()].is_unused(),
+            "the {} window at {:?} is used by the bootloader",
+            kind.name(),
+            start
+        );
+    }
+}
+
+/// A page-aligned range of kernel virtual memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtualRegion {
+    kind: RegionKind,
+    start: VirtAddr,
+    size: u64,
+}
+
+impl VirtualRegion {
+    pub fn kind(&self) -> RegionKind {
+        self.kind
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn start_page(&self) -> Page {
+        Page::containing_address(self.start)
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The window of the requested kind has no gap large enough
+    OutOfSpace,
+    /// The fixed number of region slots is used up
+    TooManyRegions,
+    /// A fixed reservation collides with an existing region
+    Overlap(VirtualRegion),
+    /// A fixed reservation is not inside the window of its kind
+    OutsideWindow,
+    /// An address or size is not page aligned
+    Misaligned,
+    /// No region starts at the given address
+    NotFound,
+}
+
+/// Hands out and takes back ranges of kernel virtual memory.
+///
+/// Regions are kept sorted by start address in a fixed array, so the allocator
+/// works before the heap exists (the heap itself lives in one of its regions).
+pub struct VirtualRegionAllocator {
+    regions: [Option<VirtualRegion>; MAX_REGIONS],
+    len: usize,
+}
+
+impl VirtualRegionAllocator {
+    pub const fn new() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Allocates `size` bytes, rounded up to whole pages, at an address
+    /// aligned to `align` inside the window of `kind`
+    pub fn allocate(
+        &mut self,
+        kind: RegionKind,
+        size: u64,
+        align: u64,
+    ) -> Result<VirtualRegion, RegionError> {
+        assert!(
+            align.is_power_of_two(),
+            "region alignment must be a power of two"
+        );
+
+        let size = align_up(size.max(1), Size4KiB::SIZE);
+        let align = align.max(Size4KiB::SIZE);
+        let (window_start, window_end) = kind.window();
+
+        // First fit: walk the regions in address order and stop at the first
+        // gap that can hold the request
+        let mut candidate = window_start.align_up(align);
+        for region in self.iter() {
+            if region.end() <= candidate {
+                continue;
+            }
+
+            if region.start() >= window_end || candidate + size <= region.start() {
+                break;
+            }
+
+            candidate = region.end().align_up(align);
+        }
+
+        if candidate + size > window_end {
+            return Err(RegionError::OutOfSpace);
+        }
+
+        self.insert(VirtualRegion {
+            kind,
+            start: candidate,
+            size,
+        })
+    }
+
+    /// Reserves a region at a fixed address, failing if it overlaps anything
+    /// already handed out
+    pub fn reserve(
+        &mut self,
+        kind: RegionKind,
+        start: VirtAddr,
+        size: u64,
+    ) -> Result<VirtualRegion, RegionError> {
+        if !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 || size == 0 {
+            return Err(RegionError::Misaligned);
+        }
+
+        let (window_start, window_end) = kind.window();
+        if start < window_start || start + size > window_end {
+            return Err(RegionError::OutsideWindow);
+        }
+
+        if let Some(existing) = self.iter().find(|r| r.overlaps(start, start + size)) {
+            return Err(RegionError::Overlap(existing));
+        }
+
+        self.insert(VirtualRegion { kind, start, size })
+    }
+
+    /// Releases the region starting at `start`, making its range available
+    /// again. The caller is responsible for unmapping it first.
+    pub fn release(&mut self, start: VirtAddr) -> Result<VirtualRegion, RegionError> {
+        let index = self
+            .iter()
+            .position(|r| r.start() == start)
+            .ok_or(RegionError::NotFound)?;
+
+        let region = self.regions[index].take();
+        self.regions[index..self.len].rotate_left(1);
+        self.len -= 1;
+
+        Ok(region.unwrap())
+    }
+
+    /// Returns the region containing `addr`, if any
+    pub fn find(&self, addr: VirtAddr) -> Option<VirtualRegion> {
+        self.iter().find(|r| r.contains(addr))
+    }
+
+    /// Iterates over all live regions in address order
+    pub fn iter(&self) -> impl Iterator<Item = VirtualRegion> + '_ {
+        self.regions[..self.len].iter().flatten().copied()
+    }
+
+    fn insert(&mut self, region: VirtualRegion) -> Result<VirtualRegion, RegionError> {
+        if self.len == MAX_REGIONS {
+            return Err(RegionError::TooManyRegions);
+        }
+
+        let index = self
+            .iter()
+            .position(|r| r.start() > region.start())
+            .unwrap_or(self.len);
+
+        self.regions[index..=self.len].rotate_right(1);
+        self.regions[index] = Some(region);
+        self.len += 1;
+
+        Ok(region)
+    }
+}
+
+impl Default for VirtualRegionAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Allocates a region from the global [`VIRTUAL_REGIONS`] allocator
+pub fn allocate_region(
+    kind: RegionKind,
+    size: u64,
+    align: u64,
+) -> Result<VirtualRegion, RegionError> {
+    crate::without_interrupts(|| VIRTUAL_REGIONS.lock().allocate(kind, size, align))
+}
+
+/// Releases a region of the global [`VIRTUAL_REGIONS`] allocator
+pub fn release_region(start: VirtAddr) -> Result<VirtualRegion, RegionError> {
+    crate::without_interrupts(|| VIRTUAL_REGIONS.lock().release(start))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = Size4KiB::SIZE;
+
+    #[test]
+    fn allocates_inside_the_window() {
+        let mut regions = VirtualRegionAllocator::new();
+        let (window_start, window_end) = RegionKind::Mmio.window();
+
+        let first = regions.allocate(RegionKind::Mmio, 1, 1).unwrap();
+        assert_eq!(first.start(), window_start);
+        assert_eq!(first.size(), PAGE);
+
+        let aligned = regions
+            .allocate(RegionKind::Mmio, 3 * PAGE, 16 * PAGE)
+            .unwrap();
+        assert!(aligned.start().is_aligned(16 * PAGE));
+        assert!(aligned.start() >= first.end());
+        assert!(aligned.end() <= window_end);
+
+        let other = regions.allocate(RegionKind::Stack, PAGE, PAGE).unwrap();
+        assert_eq!(other.start(), RegionKind::Stack.window().0);
+
+        assert_eq!(regions.find(aligned.start() + PAGE), Some(aligned));
+        assert_eq!(regions.find(first.end()), None);
+        assert_eq!(
+            regions.reserve(RegionKind::Mmio, first.start(), PAGE),
+            Err(RegionError::Overlap(first))
+        );
+    }
+
+    #[test]
+    fn released_regions_are_reused() {
+        let mut regions = VirtualRegionAllocator::new();
+
+        let first = regions.allocate(RegionKind::Heap, PAGE, PAGE).unwrap();
+        let second = regions.allocate(RegionKind::Heap, PAGE, PAGE).unwrap();
+
+        assert_eq!(regions.release(first.start()), Ok(first));
+        assert_eq!(regions.release(first.start()), Err(RegionError::NotFound));
+        assert_eq!(regions.iter().count(), 1);
+
+        let again = regions.allocate(RegionKind::Heap, PAGE, PAGE).unwrap();
+        assert_eq!(again.start(), first.start());
+        assert!(again.end() <= second.start());
+    }
+
+    #[test]
+    fn neighbouring_gaps_merge() {
+        let mut regions = VirtualRegionAllocator::new();
+
+        let a = regions.allocate(RegionKind::Mmio, 2 * PAGE, PAGE).unwrap();
+        let b = regions.allocate(RegionKind::Mmio, 3 * PAGE, PAGE).unwrap();
+        let c = regions.allocate(RegionKind::Mmio, PAGE, PAGE).unwrap();
+
+        regions.release(a.start()).unwrap();
+        regions.release(b.start()).unwrap();
+
+        // Only the two freed regions together fit this, in front of `c`
+        let merged = regions.allocate(RegionKind::Mmio, 5 * PAGE, PAGE).unwrap();
+        assert_eq!(merged.start(), a.start());
+        assert_eq!(merged.end(), c.start());
+    }
+
+    #[test]
+    fn runs_out_of_space_and_slots() {
+        let mut regions = VirtualRegionAllocator::new();
+
+        let all = regions
+            .allocate(RegionKind::Stack, WINDOW_SIZE, PAGE)
+            .unwrap();
+        assert_eq!(
+            regions.allocate(RegionKind::Stack, PAGE, PAGE),
+            Err(RegionError::OutOfSpace)
+        );
+        regions.release(all.start()).unwrap();
+
+        for _ in 0..MAX_REGIONS {
+            regions.allocate(RegionKind::Stack, PAGE, PAGE).unwrap();
+        }
+        assert_eq!(
+            regions.allocate(RegionKind::Heap, PAGE, PAGE),
+            Err(RegionError::TooManyRegions)
+        );
+    }
+}
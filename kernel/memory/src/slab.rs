@@ -0,0 +1,103 @@
+use core::{alloc::Layout, ptr::NonNull};
+
+/// Object sizes served by the slab caches, anything larger goes straight to
+/// the linked list heap
+pub const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Size and alignment of the chunks slabs carve their objects from
+pub const SLAB_SIZE: usize = 4096;
+
+/// Returns the index of the size class serving `layout`, if any.
+///
+/// Objects in a slab are aligned to their own size, so a class can serve any
+/// layout whose size and alignment both fit in it.
+pub fn size_class(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+
+    SIZE_CLASSES.iter().position(|&class| size <= class)
+}
+
+/// A free object, linked through its own first word
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Cache of equally sized objects for one size class.
+///
+/// Freed objects go back onto the free list of their class and are never
+/// returned to the heap, so repeated `Box`/`Vec` churn of the same sizes does
+/// not fragment the linked list.
+pub struct SlabCache {
+    object_size: usize,
+    free_list: Option<NonNull<FreeObject>>,
+    /// Number of slabs taken from the heap so far
+    slabs: usize,
+}
+
+// The free list only points into slabs owned by this cache
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    pub const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            free_list: None,
+            slabs: 0,
+        }
+    }
+
+    /// Pops an object off the free list, calling `allocate_slab` for a new
+    /// `SLAB_SIZE` chunk when the list is empty
+    pub fn allocate(&mut self, allocate_slab: impl FnOnce() -> Option<NonNull<u8>>) -> *mut u8 {
+        if self.free_list.is_none() {
+            match allocate_slab() {
+                Some(slab) => unsafe { self.add_slab(slab) },
+                None => return core::ptr::null_mut(),
+            }
+        }
+
+        match self.free_list {
+            Some(object) => {
+                self.free_list = unsafe { object.as_ref().next };
+                object.as_ptr() as *mut u8
+            }
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    /// Puts an object back onto the free list
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` on this cache and must not
+    /// be used afterwards.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let object = ptr.cast::<FreeObject>();
+        object.as_ptr().write(FreeObject {
+            next: self.free_list,
+        });
+        self.free_list = Some(object);
+    }
+
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Number of bytes taken from the heap by this cache
+    pub fn reserved_bytes(&self) -> usize {
+        self.slabs * SLAB_SIZE
+    }
+
+    unsafe fn add_slab(&mut self, slab: NonNull<u8>) {
+        for index in (0..SLAB_SIZE / self.object_size).rev() {
+            let object = NonNull::new_unchecked(slab.as_ptr().add(index * self.object_size));
+            self.deallocate(object);
+        }
+
+        self.slabs += 1;
+    }
+}
+
+/// Layout of the chunks handed to `SlabCache::allocate`
+pub fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
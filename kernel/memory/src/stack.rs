@@ -1,19 +1,46 @@
-use core::sync::atomic::{AtomicU64, Ordering};
-
+use lazy_static::lazy_static;
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, Size4KiB,
     },
     VirtAddr,
 };
 
-#[derive(Debug, Clone, Copy)]
+use crate::{
+    paging::flush,
+    protection::Permissions,
+    region::{allocate_region, release_region, RegionKind},
+    with_mapper_and_allocator, without_interrupts,
+};
+
+/// Maximum number of stacks that can be registered at the same time, enough
+/// for the interrupt and kernel stacks of every CPU
+pub const MAX_STACKS: usize = 256;
+
+lazy_static! {
+    /// Every stack allocated through `alloc_stack` that is still alive
+    static ref STACKS: Mutex<[Option<StackInfo>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+}
+
+/// A kernel stack with a guard page below it.
+///
+/// The stack owns its mapping: dropping it unmaps the pages, frees their frames
+/// and releases the virtual region, so it must not be dropped while in use.
+/// Dropping takes the global mapper, so it must not happen inside
+/// `with_mapper_and_allocator` either.
+#[derive(Debug)]
 pub struct StackBounds {
+    name: &'static str,
     start: VirtAddr,
     end: VirtAddr,
 }
 
 impl StackBounds {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
     pub fn start(&self) -> VirtAddr {
         self.start
     }
@@ -21,12 +48,87 @@ impl StackBounds {
     pub fn end(&self) -> VirtAddr {
         self.end
     }
+
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.start) - 1
+    }
+
+    /// Frees the stack through `mapper` instead of the global mapper, which
+    /// also makes it usable while the global mapper is held
+    ///
+    /// # Safety
+    /// The stack must not be in use, and `mapper` must be the page table the
+    /// stack was mapped into.
+    pub unsafe fn free(
+        self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        unregister(self.start);
+        self.unmap(mapper, frame_deallocator);
+        release_region(self.guard_page().start_address()).expect("stack region was not allocated");
+
+        core::mem::forget(self);
+    }
+
+    /// Unmaps the stack pages and hands their frames back
+    ///
+    /// # Safety
+    /// The stack must not be in use, and `mapper` must be the page table the
+    /// stack was mapped into.
+    unsafe fn unmap(
+        &self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        let pages = Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        );
+
+        for page in pages {
+            let (frame, page_flush) = mapper.unmap(page).expect("stack page was not mapped");
+            flush(page_flush);
+            frame_deallocator.deallocate_frame(frame);
+        }
+    }
+}
+
+impl Drop for StackBounds {
+    fn drop(&mut self) {
+        unregister(self.start);
+
+        with_mapper_and_allocator(|mapper, frame_allocator| unsafe {
+            self.unmap(mapper, frame_allocator)
+        });
+
+        release_region(self.guard_page().start_address()).expect("stack region was not allocated");
+    }
+}
+
+/// Describes a live stack, as kept in the stack registry
+#[derive(Debug, Clone, Copy)]
+pub struct StackInfo {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+}
+
+impl StackInfo {
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.start) - 1
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
 }
 
 pub fn alloc_stack(
+    name: &'static str,
     size_in_pages: u64,
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
 ) -> Result<StackBounds, MapToError<Size4KiB>> {
     // Reserve a guard page to prevent the stack from reading into data it
     // shouldn't
@@ -35,26 +137,180 @@ pub fn alloc_stack(
     let stack_start = guard_page + 1;
     let stack_end = stack_start + size_in_pages;
 
+    // Grows with every mapped page, so a failure frees just those
+    let mut stack = StackBounds {
+        name,
+        start: stack_start.start_address(),
+        end: stack_start.start_address(),
+    };
+
     for page in Page::range(stack_start, stack_end) {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        if let Err(err) = map_stack_page(page, mapper, frame_allocator) {
+            unsafe { stack.free(mapper, frame_allocator) };
+            return Err(err);
+        }
+        stack.end = page.start_address() + Size4KiB::SIZE;
     }
 
-    Ok(StackBounds {
-        start: stack_start.start_address(),
-        end: stack_end.start_address(),
-    })
+    register(StackInfo {
+        name,
+        start: stack.start,
+        end: stack.end,
+    });
+
+    Ok(stack)
+}
+
+fn map_stack_page(
+    page: Page,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let flags = Permissions::ReadWrite.flags();
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(page_flush) => {
+            flush(page_flush);
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+/// Returns the live stack whose guard page contains `addr`.
+///
+/// Called from the page fault handler, so it gives up instead of spinning if
+/// the registry is locked.
+pub fn guard_page_hit(addr: VirtAddr) -> Option<StackInfo> {
+    let page = Page::containing_address(addr);
+
+    STACKS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|stack| stack.guard_page() == page)
+        .copied()
+}
+
+/// Returns the live stack that `addr` lies in.
+///
+/// Like `guard_page_hit` it gives up if the registry is locked, as it is used
+/// to walk the stack after a panic or exception.
+pub fn find_stack(addr: VirtAddr) -> Option<StackInfo> {
+    STACKS
+        .try_lock()?
+        .iter()
+        .flatten()
+        .find(|stack| stack.contains(addr))
+        .copied()
+}
+
+fn register(info: StackInfo) {
+    without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        let slot = stacks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("too many live stacks");
+
+        *slot = Some(info);
+    });
+}
+
+fn unregister(start: VirtAddr) {
+    without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+
+        if let Some(slot) = stacks
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(stack) if stack.start == start))
+        {
+            *slot = None;
+        }
+    });
 }
 
 /// Reserve the specified amount of virtual memory. Returns the start
 /// page.
 fn reserve_stack_memory(size_in_pages: u64) -> Page {
-    static STACK_ALLOC_NEXT: AtomicU64 = AtomicU64::new(0x_5555_5555_0000);
-    let start_addr = VirtAddr::new(
-        STACK_ALLOC_NEXT.fetch_add(size_in_pages * Page::<Size4KiB>::SIZE, Ordering::Relaxed),
-    );
-    Page::from_start_address(start_addr).expect("`STACK_ALLOC_NEXT` not page aligned")
+    allocate_region(
+        RegionKind::Stack,
+        size_in_pages * Size4KiB::SIZE,
+        Size4KiB::SIZE,
+    )
+    .expect("out of virtual memory for stacks")
+    .start_page()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use x86_64::structures::paging::Translate;
+
+    use super::*;
+    use crate::mock::MockSystem;
+
+    #[test]
+    fn stack_has_guard_page_and_is_freed() {
+        let mut system = MockSystem::new(4 * 1024 * 1024);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        let stack = alloc_stack("test", 4, &mut mapper, frame_allocator).unwrap();
+        let guard_page = stack.guard_page().start_address();
+        assert_eq!(stack.end() - stack.start(), 4 * Size4KiB::SIZE);
+        assert!(mapper.translate_addr(stack.start()).is_some());
+        assert!(mapper.translate_addr(stack.end() - 1u64).is_some());
+        assert!(mapper.translate_addr(guard_page).is_none());
+
+        let free = frame_allocator.free_frames();
+        let hit = guard_page_hit(guard_page + 8u64).expect("guard page not registered");
+        assert_eq!(hit.start, stack.start());
+        assert_eq!(hit.name, "test");
+        assert_eq!(find_stack(stack.start()).unwrap().start, stack.start());
+        assert_eq!(find_stack(stack.end() - 8u64).unwrap().start, stack.start());
+        assert!(find_stack(stack.end()).is_none());
+        assert!(find_stack(guard_page).is_none());
+
+        let (start, end) = (stack.start(), stack.end());
+        unsafe { stack.free(&mut mapper, frame_allocator) };
+
+        assert_eq!(frame_allocator.free_frames(), free + 4);
+        assert!(guard_page_hit(guard_page).is_none());
+        assert!(find_stack(start).is_none());
+
+        assert_eq!(system.translate(guard_page), None);
+        assert_eq!(system.translate(start), None);
+        assert_eq!(system.translate(end - 1u64), None);
+    }
+
+    #[test]
+    fn partially_mapped_stack_is_freed() {
+        let mut system = MockSystem::new(4 * 1024 * 1024);
+        let (mut mapper, frame_allocator) = system.parts();
+
+        // Creates the page tables of the stack region, which stay around
+        let stack = alloc_stack("warm up", 32, &mut mapper, frame_allocator).unwrap();
+        let start = stack.start();
+        unsafe { stack.free(&mut mapper, frame_allocator) };
+
+        // Fewer frames than the stack needs
+        let mut taken = Vec::new();
+        while frame_allocator.free_frames() > 16 {
+            taken.push(FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator).unwrap());
+        }
+
+        assert!(matches!(
+            alloc_stack("partial", 32, &mut mapper, frame_allocator),
+            Err(MapToError::FrameAllocationFailed)
+        ));
+        assert_eq!(frame_allocator.free_frames(), 16);
+        assert!(find_stack(start).is_none());
+        assert_eq!(system.translate(start), None);
+    }
 }
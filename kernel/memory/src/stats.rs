@@ -0,0 +1,176 @@
+use core::fmt;
+
+use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use x86_64::{
+    structures::paging::{PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    allocator::{heap_stats, HeapStats},
+    kernel_level_4_frame, physical_memory_offset, with_mapper_and_allocator,
+    zone::Zone,
+    MEMORY_REGIONS,
+};
+
+/// Maximum number of distinct region kinds reported separately
+pub const MAX_REGION_KINDS: usize = 16;
+
+/// Frames of a single `MemoryRegionKind` in the bootloader memory map
+#[derive(Debug, Clone, Copy)]
+pub struct KindFrames {
+    pub kind: MemoryRegionKind,
+    pub frames: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Frames covered by the memory map
+    pub total: u64,
+    /// Frames in `Usable` regions
+    pub usable: u64,
+    /// Frames in every other region
+    pub reserved: u64,
+    /// Usable frames currently handed out by the frame allocator
+    pub allocated: u64,
+    /// Free frames per zone, in the order of `Zone::ALL`
+    pub zone_free: [u64; Zone::ALL.len()],
+    pub by_kind: [Option<KindFrames>; MAX_REGION_KINDS],
+}
+
+/// Snapshot of the kernel's memory usage, see [`stats`]
+pub struct MemoryStats {
+    pub frames: FrameStats,
+    pub heap: HeapStats,
+    /// Frames used by the kernel's page tables, including the level 4 table
+    pub page_table_frames: u64,
+    memory_map: Option<&'static [MemoryRegion]>,
+}
+
+impl MemoryStats {
+    /// The bootloader memory map, if the memory subsystem is initialized
+    pub fn memory_map(&self) -> Option<&'static [MemoryRegion]> {
+        self.memory_map
+    }
+}
+
+/// Collects the current frame, heap and page table counters
+pub fn stats() -> MemoryStats {
+    let memory_map = *MEMORY_REGIONS.lock();
+
+    let mut frames = FrameStats {
+        total: 0,
+        usable: 0,
+        reserved: 0,
+        allocated: 0,
+        zone_free: [0; Zone::ALL.len()],
+        by_kind: [None; MAX_REGION_KINDS],
+    };
+
+    for region in memory_map.iter().flat_map(|regions| regions.iter()) {
+        let count = (region.end - region.start) / Size4KiB::SIZE;
+
+        frames.total += count;
+        if region.kind == MemoryRegionKind::Usable {
+            frames.usable += count;
+        } else {
+            frames.reserved += count;
+        }
+
+        let slot = frames
+            .by_kind
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(k) if k.kind == region.kind) || slot.is_none());
+        match slot {
+            Some(Some(kind)) => kind.frames += count,
+            Some(slot) => {
+                *slot = Some(KindFrames {
+                    kind: region.kind,
+                    frames: count,
+                })
+            }
+            None => {}
+        }
+    }
+
+    let (free_frames, page_table_frames) = with_mapper_and_allocator(|_, frame_allocator| {
+        let page_table_frames =
+            unsafe { count_tables(kernel_level_4_frame(), 4, physical_memory_offset()) };
+
+        frames.zone_free = Zone::ALL.map(|zone| frame_allocator.zone_free_frames(zone));
+
+        (frame_allocator.free_frames(), page_table_frames)
+    });
+    frames.allocated = frames.usable.saturating_sub(free_frames);
+
+    MemoryStats {
+        frames,
+        heap: heap_stats(),
+        page_table_frames,
+        memory_map,
+    }
+}
+
+/// Counts the page table at `level` and every table below it
+unsafe fn count_tables(frame: PhysFrame, level: u8, physical_memory_offset: VirtAddr) -> u64 {
+    if level == 1 {
+        return 1;
+    }
+
+    let table: &PageTable = &*(physical_memory_offset + frame.start_address().as_u64()).as_ptr();
+
+    1 + table
+        .iter()
+        .filter(|entry| !entry.is_unused())
+        .filter(|entry| !entry.flags().contains(PageTableFlags::HUGE_PAGE))
+        .filter_map(|entry| entry.frame().ok())
+        .map(|child| count_tables(child, level - 1, physical_memory_offset))
+        .sum::<u64>()
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const KIB_PER_FRAME: u64 = Size4KiB::SIZE / 1024;
+
+        if let Some(memory_map) = self.memory_map {
+            writeln!(f, "Memory map:")?;
+            for region in memory_map.iter() {
+                writeln!(
+                    f,
+                    "  {:#014x} - {:#014x} {:?}",
+                    region.start, region.end, region.kind
+                )?;
+            }
+        }
+
+        let frames = &self.frames;
+        writeln!(
+            f,
+            "Frames: {} total, {} usable, {} reserved, {} allocated ({} KiB)",
+            frames.total,
+            frames.usable,
+            frames.reserved,
+            frames.allocated,
+            frames.allocated * KIB_PER_FRAME
+        )?;
+        for kind in frames.by_kind.iter().flatten() {
+            writeln!(f, "  {:?}: {} frames", kind.kind, kind.frames)?;
+        }
+        for (zone, free) in Zone::ALL.iter().zip(frames.zone_free) {
+            writeln!(f, "  {} zone: {} frames free", zone.name(), free)?;
+        }
+
+        let heap = &self.heap;
+        writeln!(
+            f,
+            "Heap: {} KiB mapped, {} KiB used, {} KiB free, largest free block {} KiB, {} KiB in slabs",
+            heap.size / 1024,
+            heap.used / 1024,
+            heap.free / 1024,
+            heap.largest_free_block / 1024,
+            heap.slab_bytes / 1024
+        )?;
+
+        write!(f, "Page tables: {} frames", self.page_table_frames)
+    }
+}
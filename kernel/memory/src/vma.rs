@@ -0,0 +1,81 @@
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// A virtual memory area: a range of user pages that is backed lazily.
+///
+/// Nothing is mapped when the area is created, the page fault handler maps a
+/// zeroed frame the first time a page is touched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+impl Vma {
+    /// Creates an area covering the pages of `[start, end)`. `flags` are used
+    /// for every page mapped in it, `PRESENT` and `USER_ACCESSIBLE` are added
+    /// automatically.
+    pub fn new(start: VirtAddr, end: VirtAddr, flags: PageTableFlags) -> Self {
+        Self {
+            start: start.align_down(Page::<Size4KiB>::SIZE),
+            end: end.align_up(Page::<Size4KiB>::SIZE),
+            flags: flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+        }
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.end
+    }
+
+    pub fn flags(&self) -> PageTableFlags {
+        self.flags
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    pub fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = Page> {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+}
+
+/// Why a page fault could not be resolved
+#[derive(Debug)]
+pub enum PageFaultError {
+    /// No address space is active
+    NoAddressSpace,
+    /// The address space or the frame allocator is locked, e.g. by the code
+    /// that faulted
+    Busy,
+    /// The address is not covered by any area
+    NoVma,
+    /// The access is not allowed by the area, e.g. a write to a read-only one
+    AccessViolation,
+    FrameAllocationFailed,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for PageFaultError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        PageFaultError::Map(err)
+    }
+}
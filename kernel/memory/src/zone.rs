@@ -0,0 +1,71 @@
+use core::ops::Range;
+
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+/// Ranges of physical memory with different addressing limits.
+///
+/// Devices that can only address part of the physical memory get their
+/// frames from the lower zones, everything else is served from `Normal` first
+/// so the lower zones stay available for them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, reachable by legacy ISA DMA
+    Dma16,
+    /// Below 4 GiB, reachable by 32-bit PCI devices
+    Dma32,
+    /// Everything else
+    Normal,
+}
+
+impl Zone {
+    pub const ALL: [Zone; 3] = [Zone::Dma16, Zone::Dma32, Zone::Normal];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Zone::Dma16 => "DMA16",
+            Zone::Dma32 => "DMA32",
+            Zone::Normal => "NORMAL",
+        }
+    }
+
+    /// Physical addresses covered by the zone
+    pub fn range(self) -> Range<u64> {
+        const DMA16_END: u64 = 16 * 1024 * 1024;
+        const DMA32_END: u64 = 4 * 1024 * 1024 * 1024;
+
+        match self {
+            Zone::Dma16 => 0..DMA16_END,
+            Zone::Dma32 => DMA16_END..DMA32_END,
+            Zone::Normal => DMA32_END..u64::MAX,
+        }
+    }
+
+    /// Frame numbers covered by the zone
+    pub(crate) fn frames(self) -> Range<u64> {
+        let range = self.range();
+
+        range.start / Size4KiB::SIZE..range.end / Size4KiB::SIZE
+    }
+
+    /// The zones an allocation for this zone may be served from, in the order
+    /// they are tried. Lower zones satisfy every limit of the higher ones.
+    pub fn fallbacks(self) -> &'static [Zone] {
+        match self {
+            Zone::Dma16 => &[Zone::Dma16],
+            Zone::Dma32 => &[Zone::Dma32, Zone::Dma16],
+            Zone::Normal => &[Zone::Normal, Zone::Dma32, Zone::Dma16],
+        }
+    }
+
+    /// The zone a frame number belongs to
+    pub(crate) fn of_frame(frame: u64) -> Zone {
+        Zone::ALL
+            .into_iter()
+            .find(|zone| frame < zone.frames().end)
+            .unwrap_or(Zone::Normal)
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
@@ -1,17 +1,10 @@
-use core::{
-    mem,
-    ptr::NonNull,
-    sync::atomic::{AtomicU64, Ordering},
-};
+use core::ptr::NonNull;
 
 use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
 use alloc::sync::Arc;
 use bootloader::BootInfo;
-use kernel_memory::with_mapper_and_allocator;
-use x86_64::{
-    structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
-    PhysAddr, VirtAddr,
-};
+use kernel_memory::{map_mmio, CacheMode, MmioMapping};
+use x86_64::{PhysAddr, VirtAddr};
 
 pub static mut ACPI_TABLES: Option<AcpiTables<Handler>> = None;
 
@@ -30,62 +23,35 @@ impl AcpiHandler for Handler {
         physical_address: usize,
         size: usize,
     ) -> acpi::PhysicalMapping<Self, T> {
-        with_mapper_and_allocator(|mapper, allocator| {
-            let start = PhysAddr::new(physical_address as u64);
-            let start_page = PhysFrame::containing_address(start);
-
-            let phys_frames = {
-                let end = start + mem::size_of::<T>();
-
-                let end_page = PhysFrame::containing_address(end + size);
-
-                PhysFrame::range_inclusive(start_page, end_page + 1)
-            };
-
-            let page_start = what_the_fuck(size as u64 / Size4KiB::SIZE + 1);
-            let pages =
-                { Page::range_inclusive(page_start, page_start + (size as u64 / Size4KiB::SIZE)) };
-
-            for (page, frame) in pages.zip(phys_frames) {
-                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-                unsafe {
-                    mapper
-                        .map_to(page, frame, flags, allocator)
-                        .unwrap()
-                        .flush()
-                }
-            }
-
-            let ret_ptr = page_start.start_address() + (start - start.align_down(Size4KiB::SIZE));
-
-            PhysicalMapping::new(
-                start_page.start_address().as_u64() as usize,
-                NonNull::new(ret_ptr.as_mut_ptr()).unwrap(),
-                size,
-                69,
-                self.clone(),
-            )
-        })
+        // ACPI tables live in RAM, so they are mapped cacheable like the rest
+        // of physical memory
+        let mapping = map_mmio(
+            PhysAddr::new(physical_address as u64),
+            size as u64,
+            CacheMode::WriteBack,
+        )
+        .expect("failed to map ACPI table");
+        let mapped_length = mapping.mapped_len() as usize;
+
+        PhysicalMapping::new(
+            physical_address,
+            NonNull::new(mapping.into_raw().as_mut_ptr()).unwrap(),
+            size,
+            mapped_length,
+            self.clone(),
+        )
     }
 
     fn unmap_physical_region<T>(region: &acpi::PhysicalMapping<Self, T>) {
-        with_mapper_and_allocator(|mapper, _| {
-            let start = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(
-                region.virtual_start().as_ptr(),
-            ));
-
-            let page_range = {
-                let end_page =
-                    Page::containing_address(start.start_address() + region.region_length());
-
-                Page::range_inclusive(start, end_page)
-            };
+        let mapping = unsafe {
+            MmioMapping::from_raw(
+                VirtAddr::from_ptr(region.virtual_start().as_ptr()),
+                PhysAddr::new(region.physical_start() as u64),
+                region.region_length() as u64,
+            )
+        };
 
-            for page in page_range {
-                mapper.unmap(page).expect("failed to unmap page").1.flush();
-            }
-        });
+        drop(mapping);
     }
 }
 
@@ -98,11 +64,3 @@ pub fn init(boot_info: &'static BootInfo) -> AcpiTables<Handler> {
 
     unsafe { AcpiTables::search_for_rsdp_bios(handler).unwrap() }
 }
-
-fn what_the_fuck(size_in_pages: u64) -> Page {
-    static STACK_ALLOC_NEXT: AtomicU64 = AtomicU64::new(0x_6969_5555_0000);
-    let start_addr = VirtAddr::new(
-        STACK_ALLOC_NEXT.fetch_add(size_in_pages * Page::<Size4KiB>::SIZE, Ordering::Relaxed),
-    );
-    Page::from_start_address(start_addr).expect("`STACK_ALLOC_NEXT` not page aligned")
-}
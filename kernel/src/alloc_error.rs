@@ -0,0 +1,32 @@
+use core::alloc::Layout;
+
+use kernel_memory::{allocator::heap_stats, try_with_mapper_and_allocator};
+
+/// Reports what the heap looked like when an allocation failed for good, after
+/// the reclaimers had their turn
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    serial_println!(
+        "OUT OF MEMORY: failed to allocate {} bytes aligned to {}",
+        layout.size(),
+        layout.align()
+    );
+
+    let heap = heap_stats();
+    serial_println!(
+        "  heap: {} bytes mapped, {} used, {} free, largest free block {}, {} in slabs",
+        heap.size,
+        heap.used,
+        heap.free,
+        heap.largest_free_block,
+        heap.slab_bytes
+    );
+
+    // The allocation may have failed while the mapper was held
+    match try_with_mapper_and_allocator(|_, frame_allocator| frame_allocator.free_frames()) {
+        Some(free) => serial_println!("  frames: {} free", free),
+        None => serial_println!("  frames: allocator busy"),
+    }
+
+    panic!("out of memory allocating {:?}", layout);
+}
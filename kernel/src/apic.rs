@@ -0,0 +1,209 @@
+//! Local APIC driver and the switch from the 8259 PICs to the APICs, as
+//! described by the MADT.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use acpi::{AcpiTables, InterruptModel};
+use kernel_memory::{map_mmio, CacheMode};
+use x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr};
+
+use crate::{acpi::Handler, ioapic, irq};
+
+/// Vector the local APIC delivers spurious interrupts on
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const REG_ID: u32 = 0x20;
+const REG_TASK_PRIORITY: u32 = 0x80;
+const REG_EOI: u32 = 0xb0;
+const REG_SPURIOUS: u32 = 0xf0;
+const REG_ERROR_STATUS: u32 = 0x280;
+const REG_ICR_LOW: u32 = 0x300;
+const REG_ICR_HIGH: u32 = 0x310;
+const REG_LVT_TIMER: u32 = 0x320;
+const REG_LVT_ERROR: u32 = 0x370;
+const REG_TIMER_INITIAL_COUNT: u32 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Where the local APIC registers are mapped, zero while the PICs are used.
+/// Every CPU sees its own local APIC at the same address.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// The MADT could not be parsed
+    Acpi(acpi::AcpiError),
+    /// The MADT describes no APICs, the PICs are all there is
+    NoApic,
+}
+
+/// How an interprocessor interrupt is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind {
+    /// Interrupt on `vector`
+    Fixed(u8),
+    Nmi,
+    /// Resets the target into the wait-for-SIPI state
+    Init,
+    /// Starts the target executing at physical page `page` in real mode
+    Startup(u8),
+}
+
+impl IpiKind {
+    fn command(self) -> u32 {
+        match self {
+            IpiKind::Fixed(vector) => vector as u32 | ICR_LEVEL_ASSERT,
+            IpiKind::Nmi => 0b100 << 8 | ICR_LEVEL_ASSERT,
+            IpiKind::Init => 0b101 << 8 | ICR_LEVEL_ASSERT,
+            IpiKind::Startup(page) => page as u32 | 0b110 << 8 | ICR_LEVEL_ASSERT,
+        }
+    }
+}
+
+/// Divisor of the bus clock the local APIC timer counts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerDivide {
+    By1 = 0b1011,
+    By2 = 0b0000,
+    By4 = 0b0001,
+    By8 = 0b0010,
+    By16 = 0b0011,
+    By32 = 0b1000,
+    By64 = 0b1001,
+    By128 = 0b1010,
+}
+
+/// Handle to the local APIC of the current CPU
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        (self.base + register as u64)
+            .as_ptr::<u32>()
+            .read_volatile()
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        (self.base + register as u64)
+            .as_mut_ptr::<u32>()
+            .write_volatile(value)
+    }
+
+    /// Enables the local APIC of the current CPU, with every local interrupt
+    /// masked. Every CPU has to do this before it takes interrupts.
+    pub fn enable(&self) {
+        unsafe {
+            let mut base_msr = Msr::new(IA32_APIC_BASE);
+            base_msr.write(base_msr.read() | APIC_BASE_ENABLE);
+
+            self.write(REG_LVT_TIMER, LVT_MASKED);
+            self.write(REG_LVT_ERROR, LVT_MASKED);
+            self.write(REG_TASK_PRIORITY, 0);
+            self.write(REG_SPURIOUS, SPURIOUS_VECTOR as u32 | SPURIOUS_APIC_ENABLE);
+
+            // Writing the error status register latches and clears errors
+            // from before
+            self.write(REG_ERROR_STATUS, 0);
+            self.write(REG_EOI, 0);
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(REG_ID) >> 24 }
+    }
+
+    /// Signals the end of the interrupt being handled
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(REG_EOI, 0) }
+    }
+
+    /// Sends an interprocessor interrupt to the CPU with the local APIC id
+    /// `target`, waiting until the local APIC has accepted it.
+    pub fn send_ipi(&self, target: u32, kind: IpiKind) {
+        unsafe {
+            self.write(REG_ICR_HIGH, target << 24);
+            self.write(REG_ICR_LOW, kind.command());
+
+            while self.read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Starts the timer, raising `vector` after `initial_count` ticks of the
+    /// divided bus clock, and again every `initial_count` ticks if `periodic`.
+    pub fn start_timer(&self, vector: u8, initial_count: u32, divide: TimerDivide, periodic: bool) {
+        let mode = if periodic { LVT_TIMER_PERIODIC } else { 0 };
+
+        unsafe {
+            self.write(REG_TIMER_DIVIDE, divide as u32);
+            self.write(REG_LVT_TIMER, vector as u32 | mode);
+            self.write(REG_TIMER_INITIAL_COUNT, initial_count);
+        }
+    }
+
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(REG_LVT_TIMER, LVT_MASKED);
+            self.write(REG_TIMER_INITIAL_COUNT, 0);
+        }
+    }
+
+    /// Ticks left until the timer fires
+    pub fn timer_current_count(&self) -> u32 {
+        unsafe { self.read(REG_TIMER_CURRENT_COUNT) }
+    }
+}
+
+/// The local APIC of the current CPU, once `init` switched to the APICs
+pub fn local_apic() -> Option<LocalApic> {
+    match LOCAL_APIC.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(LocalApic {
+            base: VirtAddr::new(base),
+        }),
+    }
+}
+
+/// Maps the local APIC and IOAPICs the MADT describes, enables the local APIC
+/// of the bootstrap processor and moves IRQ delivery from the PICs to the
+/// IOAPICs.
+pub fn init(tables: &AcpiTables<Handler>) -> Result<(), ApicError> {
+    let platform_info = tables.platform_info().map_err(ApicError::Acpi)?;
+
+    let apic = match platform_info.interrupt_model {
+        InterruptModel::Apic(apic) => apic,
+        _ => return Err(ApicError::NoApic),
+    };
+
+    // The local APIC stays mapped for as long as the kernel runs
+    let mapping = map_mmio(
+        PhysAddr::new(apic.local_apic_address),
+        0x1000,
+        CacheMode::Uncached,
+    )
+    .expect("failed to map the local APIC");
+    let local_apic = LocalApic {
+        base: mapping.into_raw(),
+    };
+
+    local_apic.enable();
+    LOCAL_APIC.store(local_apic.base.as_u64(), Ordering::Release);
+
+    ioapic::init(&apic, local_apic.id());
+    irq::switch_to_apic();
+
+    Ok(())
+}
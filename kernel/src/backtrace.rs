@@ -0,0 +1,139 @@
+//! Backtraces found by following the frame pointer chain, which needs the
+//! kernel built with `-C force-frame-pointers=yes`.
+//!
+//! Every frame starts with the saved RBP of its caller followed by the return
+//! address. The walk stays inside the stack it started on if that is a known
+//! one, otherwise it stops at the first frame that is not mapped.
+
+use core::{arch::asm, fmt};
+
+use kernel_memory::{
+    physical_memory_offset,
+    stack::{find_stack, StackInfo},
+    translate_address,
+};
+use x86_64::VirtAddr;
+
+/// Number of return addresses kept
+pub const MAX_DEPTH: usize = 32;
+
+/// On an unknown stack, frames further apart than this end the walk
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+pub struct Backtrace {
+    /// The stack the walk started on, if it is one of `alloc_stack`
+    stack: Option<StackInfo>,
+    addresses: [u64; MAX_DEPTH],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Backtrace of the caller
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+        Self::walk(None, rbp)
+    }
+
+    /// Backtrace of interrupted code, starting at the instruction it was
+    /// interrupted at
+    pub fn from_interrupted(rip: u64, rbp: u64) -> Self {
+        Self::walk(Some(rip), rbp)
+    }
+
+    fn walk(rip: Option<u64>, mut frame: u64) -> Self {
+        let stack = VirtAddr::try_new(frame).ok().and_then(find_stack);
+        let mut backtrace = Self {
+            stack,
+            addresses: [0; MAX_DEPTH],
+            len: 0,
+        };
+
+        if let Some(rip) = rip {
+            backtrace.push(rip);
+        }
+
+        while backtrace.len < MAX_DEPTH && backtrace.is_frame(frame) {
+            // The saved frame pointer, followed by the return address
+            let (next, return_address) = unsafe {
+                let frame = frame as *const u64;
+                (*frame, *frame.add(1))
+            };
+            if return_address == 0 {
+                break;
+            }
+            backtrace.push(return_address);
+
+            // The stack grows down, so callers have their frames above
+            if next <= frame || (stack.is_none() && next - frame > MAX_FRAME_SIZE) {
+                break;
+            }
+            frame = next;
+        }
+
+        backtrace
+    }
+
+    fn push(&mut self, address: u64) {
+        self.addresses[self.len] = address;
+        self.len += 1;
+    }
+
+    /// Whether both words of a frame at `frame` can be read
+    fn is_frame(&self, frame: u64) -> bool {
+        if frame == 0 || frame % 8 != 0 {
+            return false;
+        }
+
+        match self.stack {
+            Some(stack) => frame >= stack.start.as_u64() && frame + 16 <= stack.end.as_u64(),
+            None => is_mapped(frame) && is_mapped(frame + 8),
+        }
+    }
+
+    /// The return addresses, innermost first
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses[..self.len]
+    }
+
+    /// The stack the backtrace was found on, `None` if it is not one of
+    /// `alloc_stack`
+    pub fn stack(&self) -> Option<StackInfo> {
+        self.stack
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stack {
+            Some(stack) => writeln!(
+                f,
+                "backtrace on stack {} ({:?}..{:?}):",
+                stack.name, stack.start, stack.end
+            )?,
+            None => writeln!(f, "backtrace on an unknown stack:")?,
+        }
+
+        for (index, address) in self.addresses().iter().enumerate() {
+            writeln!(f, "{:>3}: {:#018x}", index, address)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn is_mapped(addr: u64) -> bool {
+    // Page tables can only be walked once the physical memory offset is known,
+    // which faults reported by the boot IDT can come before
+    let physical_memory_offset = physical_memory_offset();
+    if physical_memory_offset.is_null() {
+        return false;
+    }
+
+    VirtAddr::try_new(addr)
+        .ok()
+        .and_then(|addr| translate_address(addr, physical_memory_offset))
+        .is_some()
+}
@@ -1,7 +1,7 @@
 use console_vga::VgaConsole;
 use driver_vga::Pixel;
 
-use crate::graphics::graphics_ref;
+use crate::graphics::{get_graphics, graphics_ready, graphics_ref};
 
 pub fn setup_console() -> VgaConsole {
     let vga_graphics = graphics_ref();
@@ -22,3 +22,10 @@ pub fn setup_console() -> VgaConsole {
 
     VgaConsole::new(vga_graphics)
 }
+
+/// A console for reporting fatal errors, once graphics are set up. It starts
+/// at the top of the screen, writing over what is there, and does not take
+/// any locks.
+pub fn fatal_console() -> Option<VgaConsole> {
+    graphics_ready().then(|| VgaConsole::new(get_graphics()))
+}
@@ -0,0 +1,79 @@
+//! A bounded queue for passing input events from interrupt handlers to the
+//! code reading them.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Holds up to `N` events. It never allocates, so interrupt handlers can
+/// push to it; when full, new events are dropped.
+pub struct EventQueue<T: Copy, const N: usize> {
+    inner: Mutex<Ring<T, N>>,
+}
+
+struct Ring<T: Copy, const N: usize> {
+    events: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(Ring {
+                events: [None; N],
+                head: 0,
+                len: 0,
+            }),
+        }
+    }
+
+    /// Adds `event` to the back, returning false if the queue was full
+    pub fn push(&self, event: T) -> bool {
+        without_interrupts(|| {
+            let mut ring = self.inner.lock();
+            if ring.len == N {
+                return false;
+            }
+
+            let tail = (ring.head + ring.len) % N;
+            ring.events[tail] = Some(event);
+            ring.len += 1;
+
+            true
+        })
+    }
+
+    /// Takes the oldest event
+    pub fn pop(&self) -> Option<T> {
+        without_interrupts(|| {
+            let mut ring = self.inner.lock();
+            if ring.len == 0 {
+                return None;
+            }
+
+            let head = ring.head;
+            ring.head = (head + 1) % N;
+            ring.len -= 1;
+
+            ring.events[head].take()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        without_interrupts(|| self.inner.lock().len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        while self.pop().is_some() {}
+    }
+}
+
+impl<T: Copy, const N: usize> Default for EventQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
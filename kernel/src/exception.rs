@@ -0,0 +1,450 @@
+//! Handling of the architectural exceptions (vectors 0-31).
+//!
+//! Every vector enters through a small assembly stub that pushes the general
+//! purpose registers next to the interrupt frame, so a fatal exception can
+//! report the complete register state of the code that faulted.
+
+use core::{
+    arch::global_asm,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        rflags::RFlags,
+    },
+    structures::idt::{PageFaultErrorCode, SelectorErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    backtrace::Backtrace,
+    console::fatal_console,
+    serial::{self, CrLfWriter, SerialWriter},
+};
+
+pub const DIVIDE_ERROR: u8 = 0;
+pub const DEBUG: u8 = 1;
+pub const NON_MASKABLE_INTERRUPT: u8 = 2;
+pub const BREAKPOINT: u8 = 3;
+pub const DOUBLE_FAULT: u8 = 8;
+pub const INVALID_TSS: u8 = 10;
+pub const SEGMENT_NOT_PRESENT: u8 = 11;
+pub const STACK_SEGMENT_FAULT: u8 = 12;
+pub const GENERAL_PROTECTION_FAULT: u8 = 13;
+pub const PAGE_FAULT: u8 = 14;
+
+/// Non-maskable interrupts taken so far
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Distance between the entry stubs of consecutive vectors
+const STUB_SIZE: u64 = 16;
+
+// The stubs push a zero for the vectors without an error code, so the stack
+// layout is the same for all of them, then the vector number and jump to the
+// common part, which saves the registers and calls `exception_handler`.
+// 15 registers, vector, error code and the 5 words of the interrupt frame keep
+// the stack 16 byte aligned for the call.
+global_asm!(
+    r#"
+    .section .text
+    .p2align 4
+    .global exception_stubs
+exception_stubs:
+    .set vector, 0
+    .rept 32
+    .p2align 4
+    .if vector == 8 || (vector >= 10 && vector <= 14) || vector == 17 || vector == 21 || vector == 29 || vector == 30
+    .else
+    pushq $0
+    .endif
+    pushq $vector
+    jmp exception_common
+    .set vector, vector + 1
+    .endr
+
+exception_common:
+    pushq %rax
+    pushq %rbx
+    pushq %rcx
+    pushq %rdx
+    pushq %rsi
+    pushq %rdi
+    pushq %rbp
+    pushq %r8
+    pushq %r9
+    pushq %r10
+    pushq %r11
+    pushq %r12
+    pushq %r13
+    pushq %r14
+    pushq %r15
+    cld
+    movq %rsp, %rdi
+    call exception_handler
+    popq %r15
+    popq %r14
+    popq %r13
+    popq %r12
+    popq %r11
+    popq %r10
+    popq %r9
+    popq %r8
+    popq %rbp
+    popq %rdi
+    popq %rsi
+    popq %rdx
+    popq %rcx
+    popq %rbx
+    popq %rax
+    addq $16, %rsp
+    iretq
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn exception_stubs();
+}
+
+/// Address of the entry stub for `vector`, to put into the IDT
+pub fn stub_address(vector: u8) -> VirtAddr {
+    assert!(vector < 32, "vector {} is not an exception", vector);
+    VirtAddr::new(exception_stubs as *const () as u64) + vector as u64 * STUB_SIZE
+}
+
+/// State of the interrupted code, as laid out on the stack by the entry stubs
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    /// Zero for the exceptions that do not push one
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl ExceptionContext {
+    pub fn vector(&self) -> u8 {
+        self.vector as u8
+    }
+}
+
+/// Control registers at the time of the exception
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        let (frame, flags) = Cr3::read_raw();
+
+        Self {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read_raw(),
+            cr3: frame.start_address().as_u64() | flags as u64,
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+/// Number of non-maskable interrupts taken so far
+pub fn nmi_count() -> u64 {
+    NMI_COUNT.load(Ordering::Relaxed)
+}
+
+/// Mnemonic and name of an exception vector
+pub fn exception_name(vector: u8) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("#DE", "divide error"),
+        1 => ("#DB", "debug"),
+        2 => ("NMI", "non-maskable interrupt"),
+        3 => ("#BP", "breakpoint"),
+        4 => ("#OF", "overflow"),
+        5 => ("#BR", "bound range exceeded"),
+        6 => ("#UD", "invalid opcode"),
+        7 => ("#NM", "device not available"),
+        8 => ("#DF", "double fault"),
+        9 => ("#CSO", "coprocessor segment overrun"),
+        10 => ("#TS", "invalid TSS"),
+        11 => ("#NP", "segment not present"),
+        12 => ("#SS", "stack segment fault"),
+        13 => ("#GP", "general protection fault"),
+        14 => ("#PF", "page fault"),
+        16 => ("#MF", "x87 floating point"),
+        17 => ("#AC", "alignment check"),
+        18 => ("#MC", "machine check"),
+        19 => ("#XM", "SIMD floating point"),
+        20 => ("#VE", "virtualization"),
+        21 => ("#CP", "control protection"),
+        28 => ("#HV", "hypervisor injection"),
+        29 => ("#VC", "VMM communication"),
+        30 => ("#SX", "security"),
+        _ => ("#??", "reserved"),
+    }
+}
+
+/// The error code of the exception, decoded where its format is known
+struct ErrorCode {
+    vector: u8,
+    code: u64,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.code)?;
+
+        match self.vector {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT
+                if self.code == 0 =>
+            {
+                write!(f, " (no selector)")
+            }
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                let selector = SelectorErrorCode::new_truncate(self.code);
+                write!(
+                    f,
+                    " (selector index {} in the {:?}{})",
+                    selector.index(),
+                    selector.descriptor_table(),
+                    if selector.external() {
+                        ", external"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            PAGE_FAULT => {
+                let flags = PageFaultErrorCode::from_bits_truncate(self.code);
+                write!(
+                    f,
+                    " ({}, {}, {} mode{}{})",
+                    if flags.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+                        "protection violation"
+                    } else {
+                        "not present"
+                    },
+                    if flags.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+                        "instruction fetch"
+                    } else if flags.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+                        "write"
+                    } else {
+                        "read"
+                    },
+                    if flags.contains(PageFaultErrorCode::USER_MODE) {
+                        "user"
+                    } else {
+                        "kernel"
+                    },
+                    if flags.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+                        ", reserved bit set"
+                    } else {
+                        ""
+                    },
+                    if flags.contains(PageFaultErrorCode::PROTECTION_KEY) {
+                        ", protection key"
+                    } else {
+                        ""
+                    },
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes the exception, its error code, all registers and a backtrace of the
+/// interrupted code to `out`
+pub fn write_report(
+    out: &mut dyn fmt::Write,
+    context: &ExceptionContext,
+    control: &ControlRegisters,
+) -> fmt::Result {
+    let (mnemonic, name) = exception_name(context.vector());
+
+    writeln!(
+        out,
+        "EXCEPTION: {} {} (vector {})",
+        mnemonic, name, context.vector
+    )?;
+    writeln!(
+        out,
+        "error code: {}",
+        ErrorCode {
+            vector: context.vector(),
+            code: context.error_code,
+        }
+    )?;
+    if context.vector() == PAGE_FAULT {
+        writeln!(out, "accessed address: {:#018x}", control.cr2)?;
+    }
+
+    writeln!(
+        out,
+        "rip: {:#018x} cs: {:#06x} rflags: {:#010x} {:?}",
+        context.rip,
+        context.cs,
+        context.rflags,
+        RFlags::from_bits_truncate(context.rflags)
+    )?;
+    writeln!(out, "rsp: {:#018x} ss: {:#06x}", context.rsp, context.ss)?;
+
+    let registers = [
+        ("rax", context.rax),
+        ("rbx", context.rbx),
+        ("rcx", context.rcx),
+        ("rdx", context.rdx),
+        ("rsi", context.rsi),
+        ("rdi", context.rdi),
+        ("rbp", context.rbp),
+        ("r8", context.r8),
+        ("r9", context.r9),
+        ("r10", context.r10),
+        ("r11", context.r11),
+        ("r12", context.r12),
+        ("r13", context.r13),
+        ("r14", context.r14),
+        ("r15", context.r15),
+    ];
+    for row in registers.chunks(3) {
+        for (name, value) in row {
+            write!(out, "{:>3}: {:#018x} ", name, value)?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(
+        out,
+        "cr0: {:#018x} cr2: {:#018x}\ncr3: {:#018x} cr4: {:#018x}",
+        control.cr0, control.cr2, control.cr3, control.cr4
+    )?;
+
+    write!(
+        out,
+        "{}",
+        Backtrace::from_interrupted(context.rip, context.rbp)
+    )
+}
+
+/// Reports the exception, after `reason` if there is one, to serial and the
+/// framebuffer console, then halts the CPU for good.
+///
+/// The reason is only printed here, after the serial port has been taken
+/// back, as printing earlier could wait for a lock the faulting code holds.
+pub fn fatal(
+    context: &ExceptionContext,
+    control: &ControlRegisters,
+    reason: Option<fmt::Arguments>,
+) -> ! {
+    interrupts::disable();
+
+    // Nothing else runs after this, so take the port even if the exception
+    // interrupted a print
+    unsafe { serial::port().force_polled() };
+    let mut serial = CrLfWriter(&mut SerialWriter);
+    if let Some(reason) = reason {
+        let _ = writeln!(serial, "{}", reason);
+    }
+    let _ = write_report(&mut serial, context, control);
+
+    if let Some(mut console) = fatal_console() {
+        if let Some(reason) = reason {
+            let _ = writeln!(console, "{}", reason);
+        }
+        let _ = write_report(&mut console, context, control);
+    }
+
+    halt()
+}
+
+pub fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        hlt();
+    }
+}
+
+/// Called by the entry stubs with the saved state. Returning resumes the
+/// interrupted code.
+#[no_mangle]
+extern "C" fn exception_handler(context: &mut ExceptionContext) {
+    // Read first, a nested page fault would overwrite CR2
+    let control = ControlRegisters::read();
+
+    match context.vector() {
+        // Can interrupt anything, including a print, so it is only counted
+        NON_MASKABLE_INTERRUPT => {
+            NMI_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+        DEBUG | BREAKPOINT => {
+            let (mnemonic, name) = exception_name(context.vector());
+            serial_println!("EXCEPTION: {} {} at {:#x}", mnemonic, name, context.rip);
+        }
+        PAGE_FAULT => {
+            let address = VirtAddr::new(control.cr2);
+
+            if let Some(stack) = kernel_memory::stack::guard_page_hit(address) {
+                fatal(
+                    context,
+                    &control,
+                    Some(format_args!(
+                        "STACK OVERFLOW in stack {} ({:?}..{:?})",
+                        stack.name, stack.start, stack.end
+                    )),
+                );
+            }
+
+            // Lazily backed and copy-on-write pages of the active address space
+            let error_code = PageFaultErrorCode::from_bits_truncate(context.error_code);
+            if let Err(error) = kernel_memory::address_space::handle_page_fault(address, error_code)
+            {
+                fatal(
+                    context,
+                    &control,
+                    Some(format_args!("Unresolved page fault: {:?}", error)),
+                );
+            }
+        }
+        DOUBLE_FAULT => {
+            // Overflowing a stack faults again while pushing the page fault
+            // frame onto the guard page, which leaves its address in CR2
+            match kernel_memory::stack::guard_page_hit(VirtAddr::new(control.cr2)) {
+                Some(stack) => fatal(
+                    context,
+                    &control,
+                    Some(format_args!(
+                        "STACK OVERFLOW in stack {} ({:?}..{:?})",
+                        stack.name, stack.start, stack.end
+                    )),
+                ),
+                None => fatal(context, &control, None),
+            }
+        }
+        _ => fatal(context, &control, None),
+    }
+}
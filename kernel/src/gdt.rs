@@ -1,51 +1,73 @@
+use alloc::{boxed::Box, format};
+use kernel_memory::{stack::alloc_stack, with_mapper_and_allocator};
 use x86_64::{
-    registers::segmentation::SegmentSelector,
+    instructions::{
+        segmentation::{Segment, CS, DS, SS},
+        tables::load_tss,
+    },
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable},
         tss::TaskStateSegment,
     },
-    VirtAddr,
 };
 
+use crate::percpu::cpu_id;
+
+// Page faults deliberately get no interrupt stack. The CPU loads the IST
+// pointer afresh on every entry, so a page fault raised while the handler
+// resolves another one, e.g. touching a lazily backed page or the heap, would
+// push its frame over the outer handler's. Running on the interrupted stack
+// nests safely, and a fault that overflows it can't push its frame onto the
+// guard page, so it turns into a double fault, whose handler reports the stack
+// through `guard_page_hit`.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
-    data_selector: SegmentSelector,
-}
+/// Size of the interrupt stacks
+const IST_STACK_PAGES: u64 = 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// Loads a GDT and TSS of its own into the current CPU. Every CPU needs a TSS
+/// with its own interrupt stacks, and a GDT to describe it.
+///
+/// The interrupt stacks are allocated here, so the memory subsystem and
+/// `percpu::init` have to have run first.
+pub fn init() {
+    // Named after the CPU, so backtraces and guard page hits tell the CPUs
+    // apart
+    let cpu = cpu_id();
+    let name = |stack| -> &'static str { Box::leak(format!("CPU {} {}", cpu, stack).into()) };
+    let names = [name("double fault"), name("NMI"), name("machine check")];
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+    // Stacks the CPU switches to for exceptions that must work even when the
+    // current stack is broken, like a stack overflow hitting a guard page.
+    // Indexed by the IST index.
+    let ist_stacks = with_mapper_and_allocator(|mapper, frame_allocator| {
+        names.map(|name| {
+            alloc_stack(name, IST_STACK_PAGES, mapper, frame_allocator)
+                .expect("failed to allocate IST stack")
+        })
+    });
 
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
-}
+    let mut tss = TaskStateSegment::new();
+    for (index, stack) in ist_stacks.iter().enumerate() {
+        tss.interrupt_stack_table[index] = stack.end();
+    }
+    // The tables and stacks are in use for as long as the CPU runs
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+    core::mem::forget(ist_stacks);
 
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-                data_selector,
-            },
-        )
-    };
-}
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    gdt.load();
 
-pub fn init() {}
+    unsafe {
+        CS::set_reg(code_selector);
+        DS::set_reg(data_selector);
+        SS::set_reg(data_selector);
+        load_tss(tss_selector);
+    }
+}
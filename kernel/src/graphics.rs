@@ -1,21 +1,26 @@
-use core::mem::MaybeUninit;
+use core::{
+    mem::MaybeUninit,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use alloc::boxed::Box;
 use bootloader::{boot_info::FrameBuffer, BootInfo};
 use driver_vga::VGADriver;
+use kernel_memory::{map_mmio, mmio::set_cache_mode, translate_address, CacheMode};
 use vga_efi::VgaEfi;
+use x86_64::VirtAddr;
 
 pub static mut VGA_BACKEND: MaybeUninit<*const (dyn VGADriver + Send + Sync)> =
     MaybeUninit::uninit();
 
+static GRAPHICS_READY: AtomicBool = AtomicBool::new(false);
+
 pub fn setup_graphics(boot_info: &'static BootInfo) {
     let frame_buffer: &FrameBuffer = boot_info.framebuffer.as_ref().unwrap();
 
     let info = frame_buffer.info();
 
-    let frame_buffer_data = frame_buffer.buffer();
-
-    let frame_buffer_pointer = frame_buffer_data as *const [u8] as *mut [u8];
+    let frame_buffer_pointer = write_combine_frame_buffer(boot_info, frame_buffer);
 
     let renderer = VgaEfi::new(frame_buffer_pointer, info).unwrap();
 
@@ -24,6 +29,37 @@ pub fn setup_graphics(boot_info: &'static BootInfo) {
     let mut swap_src = MaybeUninit::new(renderer);
 
     core::mem::swap(&mut swap_src, unsafe { &mut VGA_BACKEND });
+    GRAPHICS_READY.store(true, Ordering::Release);
+}
+
+/// Whether `setup_graphics` has run, so `VGA_BACKEND` can be used
+pub fn graphics_ready() -> bool {
+    GRAPHICS_READY.load(Ordering::Acquire)
+}
+
+/// Maps the framebuffer write combining with `map_mmio`, which switches the
+/// physical memory mapping of it as well. The bootloader's mapping stays
+/// around, so it is switched too and every mapping agrees on the memory type.
+fn write_combine_frame_buffer(
+    boot_info: &'static BootInfo,
+    frame_buffer: &FrameBuffer,
+) -> *mut [u8] {
+    let physical_offset = VirtAddr::new(*boot_info.physical_memory_offset.as_ref().unwrap());
+    let buffer = frame_buffer.buffer();
+    let start = VirtAddr::from_ptr(buffer.as_ptr());
+    let len = buffer.len() as u64;
+
+    let phys = translate_address(start, physical_offset).expect("framebuffer is not mapped");
+
+    let mapping =
+        map_mmio(phys, len, CacheMode::WriteCombining).expect("failed to map the framebuffer");
+    unsafe {
+        set_cache_mode(start, len, CacheMode::WriteCombining)
+            .expect("failed to make the framebuffer write combining");
+    }
+
+    // The framebuffer is used for as long as the kernel runs
+    core::ptr::slice_from_raw_parts_mut(mapping.into_raw().as_mut_ptr(), buffer.len())
 }
 
 pub fn get_graphics() -> *const (dyn VGADriver + Send + Sync) {
@@ -1,47 +1,77 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{Entry, EntryOptions, InterruptDescriptorTable};
 
-use crate::gdt;
+use crate::{exception::stub_address, gdt, irq, time};
 
 lazy_static! {
-    static ref IDT: InterruptDescriptorTable = {
-        let mut idt = InterruptDescriptorTable::new();
+    /// Loaded before the memory subsystem is up, when there is no TSS with
+    /// interrupt stacks yet
+    static ref BOOT_IDT: InterruptDescriptorTable = exception_table(false);
 
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = exception_table(true);
 
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        irq::set_irq_entries(&mut idt);
+        idt[time::TIMER_VECTOR as usize].set_handler_fn(time::timer_interrupt);
 
         idt
     };
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
+/// An IDT with every architectural exception going through the entry stubs of
+/// `exception`, the reserved vectors stay missing. The exceptions that have to
+/// work on a broken stack only switch to their interrupt stacks if
+/// `interrupt_stacks` is set, as those need the TSS of `gdt::init`.
+fn exception_table(interrupt_stacks: bool) -> InterruptDescriptorTable {
+    let mut idt = InterruptDescriptorTable::new();
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT {}\n{:#?}", error_code, stack_frame);
-}
+    unsafe {
+        set_exception(&mut idt.divide_error, 0);
+        set_exception(&mut idt.debug, 1);
+        set_exception(&mut idt.non_maskable_interrupt, 2);
+        set_exception(&mut idt.breakpoint, 3);
+        set_exception(&mut idt.overflow, 4);
+        set_exception(&mut idt.bound_range_exceeded, 5);
+        set_exception(&mut idt.invalid_opcode, 6);
+        set_exception(&mut idt.device_not_available, 7);
+        set_exception(&mut idt.double_fault, 8);
+        set_exception(&mut idt[9], 9);
+        set_exception(&mut idt.invalid_tss, 10);
+        set_exception(&mut idt.segment_not_present, 11);
+        set_exception(&mut idt.stack_segment_fault, 12);
+        set_exception(&mut idt.general_protection_fault, 13);
+        // Not on an interrupt stack, see the IST indices in `gdt`
+        set_exception(&mut idt.page_fault, 14);
+        set_exception(&mut idt.x87_floating_point, 16);
+        set_exception(&mut idt.alignment_check, 17);
+        set_exception(&mut idt.machine_check, 18);
+        set_exception(&mut idt.simd_floating_point, 19);
+        set_exception(&mut idt.virtualization, 20);
+        set_exception(&mut idt.vmm_communication_exception, 29);
+        set_exception(&mut idt.security_exception, 30);
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
+        if interrupt_stacks {
+            set_exception(&mut idt.non_maskable_interrupt, 2).set_stack_index(gdt::NMI_IST_INDEX);
+            set_exception(&mut idt.double_fault, 8).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            set_exception(&mut idt.machine_check, 18).set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+    }
+
+    idt
+}
 
-    serial_println!("EXCEPTION: PAGE FAULT");
-    serial_println!("Accessed Address: {:?}", Cr2::read());
-    serial_println!("Error Code: {:?}", error_code);
-    serial_println!("{:#?}", stack_frame);
+/// Points `entry` at the entry stub for `vector`.
+///
+/// Safety: `vector` must be the vector of `entry`, the stub pushes a zero error
+/// code for the vectors the CPU pushes none for.
+unsafe fn set_exception<F>(entry: &mut Entry<F>, vector: u8) -> &mut EntryOptions {
+    entry.set_handler_addr(stub_address(vector))
+}
 
-    loop {}
+/// Loads an IDT with just the exceptions, so faults during early boot are
+/// reported instead of resetting the machine. Replaced by `init` once the
+/// memory subsystem and the TSS are set up.
+pub fn init_boot() {
+    BOOT_IDT.load();
 }
 
 pub fn init() {
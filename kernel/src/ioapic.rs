@@ -0,0 +1,180 @@
+//! IOAPIC driver, routing the ISA IRQs to the vectors the PICs used.
+
+use acpi::platform::interrupt::{Apic, Polarity, TriggerMode};
+use alloc::vec::Vec;
+use kernel_memory::{map_mmio, CacheMode};
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, PhysAddr, VirtAddr};
+
+use crate::irq::{irq_vector, IRQ_COUNT};
+
+const REG_SELECT: u64 = 0x00;
+const REG_WINDOW: u64 = 0x10;
+
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u32 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u32 = 1 << 15;
+const ENTRY_MASKED: u32 = 1 << 16;
+
+lazy_static! {
+    static ref IO_APICS: Mutex<Option<IoApics>> = Mutex::new(None);
+}
+
+struct IoApic {
+    base: VirtAddr,
+    /// First global system interrupt this IOAPIC serves
+    gsi_base: u32,
+    /// Number of redirection entries, and so interrupts it serves
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        (self.base + REG_SELECT)
+            .as_mut_ptr::<u32>()
+            .write_volatile(register);
+        (self.base + REG_WINDOW).as_ptr::<u32>().read_volatile()
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        (self.base + REG_SELECT)
+            .as_mut_ptr::<u32>()
+            .write_volatile(register);
+        (self.base + REG_WINDOW)
+            .as_mut_ptr::<u32>()
+            .write_volatile(value);
+    }
+
+    unsafe fn set_entry(&self, gsi: u32, low: u32, destination: u32) {
+        let register = REG_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+
+        // Mask first, so the entry is never live half written
+        self.write(register, ENTRY_MASKED);
+        self.write(register + 1, destination << 24);
+        self.write(register, low);
+    }
+}
+
+/// Where an ISA IRQ comes in, after the MADT's interrupt source overrides
+#[derive(Debug, Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+
+struct IoApics {
+    apics: Vec<IoApic>,
+    isa_routes: [IsaRoute; IRQ_COUNT],
+    /// Local APIC id of the CPU the IRQs are delivered to
+    destination: u32,
+}
+
+impl IoApics {
+    fn serving(&self, gsi: u32) -> Option<&IoApic> {
+        self.apics
+            .iter()
+            .find(|apic| (apic.gsi_base..apic.gsi_base + apic.entries).contains(&gsi))
+    }
+
+    fn set_masked(&self, irq: u8, masked: bool) {
+        let route = self.isa_routes[irq as usize];
+        let apic = match self.serving(route.gsi) {
+            Some(apic) => apic,
+            None => panic!("no IOAPIC serves GSI {} of IRQ {}", route.gsi, irq),
+        };
+
+        let mut low = irq_vector(irq) as u32;
+        if route.active_low {
+            low |= ENTRY_ACTIVE_LOW;
+        }
+        if route.level_triggered {
+            low |= ENTRY_LEVEL_TRIGGERED;
+        }
+        if masked {
+            low |= ENTRY_MASKED;
+        }
+
+        unsafe { apic.set_entry(route.gsi, low, self.destination) };
+    }
+}
+
+/// Maps the IOAPICs of `madt` and masks all of their entries. The ISA IRQs are
+/// delivered to the local APIC `destination` once unmasked.
+pub(crate) fn init(madt: &Apic, destination: u32) {
+    let apics = madt
+        .io_apics
+        .iter()
+        .map(|io_apic| {
+            // The IOAPICs stay mapped for as long as the kernel runs
+            let mapping = map_mmio(
+                PhysAddr::new(io_apic.address as u64),
+                0x20,
+                CacheMode::Uncached,
+            )
+            .expect("failed to map IOAPIC");
+
+            let mut apic = IoApic {
+                base: mapping.into_raw(),
+                gsi_base: io_apic.global_system_interrupt_base,
+                entries: 0,
+            };
+            apic.entries = (unsafe { apic.read(REG_VERSION) } >> 16 & 0xff) + 1;
+
+            for entry in 0..apic.entries {
+                unsafe { apic.set_entry(apic.gsi_base + entry, ENTRY_MASKED, 0) };
+            }
+
+            apic
+        })
+        .collect();
+
+    // ISA interrupts are edge triggered and active high, unless the MADT
+    // says otherwise
+    let mut isa_routes = [IsaRoute {
+        gsi: 0,
+        active_low: false,
+        level_triggered: false,
+    }; IRQ_COUNT];
+    for (irq, route) in isa_routes.iter_mut().enumerate() {
+        route.gsi = irq as u32;
+    }
+
+    for source_override in &madt.interrupt_source_overrides {
+        if let Some(route) = isa_routes.get_mut(source_override.isa_source as usize) {
+            *route = IsaRoute {
+                gsi: source_override.global_system_interrupt,
+                active_low: matches!(source_override.polarity, Polarity::ActiveLow),
+                level_triggered: matches!(source_override.trigger_mode, TriggerMode::Level),
+            };
+        }
+    }
+
+    without_interrupts(|| {
+        *IO_APICS.lock() = Some(IoApics {
+            apics,
+            isa_routes,
+            destination,
+        })
+    });
+}
+
+/// Lets the ISA IRQ `irq` through to the CPU
+pub fn unmask(irq: u8) {
+    without_interrupts(|| {
+        if let Some(io_apics) = IO_APICS.lock().as_ref() {
+            io_apics.set_masked(irq, false);
+        }
+    });
+}
+
+/// Stops the ISA IRQ `irq` from reaching the CPU
+pub fn mask(irq: u8) {
+    without_interrupts(|| {
+        if let Some(io_apics) = IO_APICS.lock().as_ref() {
+            io_apics.set_masked(irq, true);
+        }
+    });
+}
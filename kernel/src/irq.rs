@@ -0,0 +1,187 @@
+//! Dispatch of hardware interrupts to the drivers handling them.
+//!
+//! Drivers register handlers for an IRQ line. A line can be shared, every
+//! handler registered for it runs on each interrupt, so handlers have to check
+//! their device actually raised it.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use crate::{apic, ioapic, pic};
+
+/// Number of IRQ lines
+pub const IRQ_COUNT: usize = 16;
+
+/// Vector the interrupts of `irq` are delivered on
+pub const fn irq_vector(irq: u8) -> u8 {
+    pic::PIC_1_OFFSET + irq
+}
+
+type Handler = Box<dyn Fn(u8) + Send + Sync>;
+
+/// Identifies a registered handler, to unregister it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    irq: u8,
+    id: u64,
+}
+
+lazy_static! {
+    static ref HANDLERS: [Mutex<Vec<(u64, Handler)>>; IRQ_COUNT] = Default::default();
+    static ref INTERRUPT_COUNTS: [AtomicU64; IRQ_COUNT] = Default::default();
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the IOAPICs deliver the IRQs instead of the PICs
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn unmask(irq: u8) {
+    if APIC_ENABLED.load(Ordering::Acquire) {
+        ioapic::unmask(irq);
+    } else {
+        pic::unmask(irq);
+    }
+}
+
+fn mask(irq: u8) {
+    if APIC_ENABLED.load(Ordering::Acquire) {
+        ioapic::mask(irq);
+    } else {
+        pic::mask(irq);
+    }
+}
+
+/// Masks the PICs and unmasks the lines that have handlers on the IOAPICs.
+/// Called by `apic::init` once the IOAPICs are set up.
+pub(crate) fn switch_to_apic() {
+    without_interrupts(|| {
+        pic::disable();
+        APIC_ENABLED.store(true, Ordering::Release);
+
+        for (irq, handlers) in HANDLERS.iter().enumerate() {
+            if !handlers.lock().is_empty() {
+                ioapic::unmask(irq as u8);
+            }
+        }
+    });
+}
+
+/// Registers `handler` for `irq` and unmasks the line.
+///
+/// The handler runs with interrupts disabled and the handlers of the line
+/// locked, so it must not register or unregister handlers itself.
+pub fn register_handler(irq: u8, handler: impl Fn(u8) + Send + Sync + 'static) -> HandlerId {
+    assert!((irq as usize) < IRQ_COUNT, "IRQ {} does not exist", irq);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let handler: Handler = Box::new(handler);
+
+    without_interrupts(|| HANDLERS[irq as usize].lock().push((id, handler)));
+    unmask(irq);
+
+    HandlerId { irq, id }
+}
+
+/// Removes a handler, masking the line if it was the last one
+pub fn unregister_handler(handler: HandlerId) {
+    let removed = without_interrupts(|| {
+        let mut handlers = HANDLERS[handler.irq as usize].lock();
+        if handlers.iter().all(|(id, _)| *id != handler.id) {
+            return None;
+        }
+
+        handlers.retain(|(id, _)| *id != handler.id);
+        Some(handlers.is_empty())
+    });
+
+    if removed == Some(true) {
+        mask(handler.irq);
+    }
+}
+
+/// Number of interrupts received on `irq`, spurious ones not included
+pub fn interrupt_count(irq: u8) -> u64 {
+    INTERRUPT_COUNTS[irq as usize].load(Ordering::Relaxed)
+}
+
+/// Number of spurious interrupts the PICs or local APIC raised
+pub fn spurious_count() -> u64 {
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
+
+fn dispatch(irq: u8) {
+    let apic_enabled = APIC_ENABLED.load(Ordering::Acquire);
+
+    if !apic_enabled && pic::is_spurious(irq) {
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    INTERRUPT_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+
+    for (_, handler) in HANDLERS[irq as usize].lock().iter() {
+        handler(irq);
+    }
+
+    if apic_enabled {
+        if let Some(apic) = apic::local_apic() {
+            apic.end_of_interrupt();
+        }
+    } else {
+        pic::end_of_interrupt(irq);
+    }
+}
+
+/// The local APIC does not expect an EOI for spurious interrupts
+extern "x86-interrupt" fn apic_spurious(_stack_frame: InterruptStackFrame) {
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+macro_rules! irq_entries {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        const IRQ_ENTRIES: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] =
+            [$($name),*];
+    };
+}
+
+irq_entries! {
+    0 => irq_0,
+    1 => irq_1,
+    2 => irq_2,
+    3 => irq_3,
+    4 => irq_4,
+    5 => irq_5,
+    6 => irq_6,
+    7 => irq_7,
+    8 => irq_8,
+    9 => irq_9,
+    10 => irq_10,
+    11 => irq_11,
+    12 => irq_12,
+    13 => irq_13,
+    14 => irq_14,
+    15 => irq_15,
+}
+
+/// Points the IRQ vectors of `idt` at the dispatcher
+pub(crate) fn set_irq_entries(idt: &mut InterruptDescriptorTable) {
+    for (irq, entry) in IRQ_ENTRIES.iter().enumerate() {
+        idt[irq_vector(irq as u8) as usize].set_handler_fn(*entry);
+    }
+
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious);
+}
@@ -0,0 +1,441 @@
+//! Driver for the PS/2 keyboard on the first port of the 8042 controller.
+//! Key events are decoded from scancode set 1 or 2 in the IRQ 1 handler and
+//! queued for `read_event`.
+
+mod layout;
+mod scancode;
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub use self::{
+    layout::{De, Key, KeyboardLayout, Uk, Us},
+    scancode::{Decoder, ScancodeSet},
+};
+use crate::{
+    event_queue::EventQueue,
+    irq,
+    ps2::{self, Ps2Error, Ps2Port, ACK, RESEND},
+};
+
+const IRQ: u8 = 1;
+
+const CMD_SET_LEDS: u8 = 0xed;
+const CMD_SCANCODE_SET: u8 = 0xf0;
+const CMD_ENABLE_SCANNING: u8 = 0xf4;
+const CMD_DISABLE_SCANNING: u8 = 0xf5;
+
+/// Argument of `CMD_SCANCODE_SET` that asks for the current set
+const GET_SCANCODE_SET: u8 = 0x00;
+
+/// Replies for a key detection error or buffer overrun
+const KEY_ERROR: u8 = 0x00;
+const KEY_ERROR_ALTERNATE: u8 = 0xff;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// A physical key, named after its label on a US keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+    Backtick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    /// Above enter on ANSI keyboards, left of it on ISO keyboards
+    Backslash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Quote,
+    Enter,
+    LeftShift,
+    /// Right of left shift, only on ISO keyboards
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftControl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightControl,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    NumLock,
+    NumpadDivide,
+    NumpadMultiply,
+    NumpadSubtract,
+    NumpadAdd,
+    NumpadEnter,
+    NumpadPeriod,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+/// The modifier keys held and the lock keys toggled on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    /// Right alt, which types the third character of a key on layouts that
+    /// have one
+    pub fn alt_gr(&self) -> bool {
+        self.right_alt
+    }
+
+    /// Tracks modifier key presses and releases, returning whether a lock
+    /// key was toggled
+    fn update(&mut self, code: KeyCode, state: KeyState) -> bool {
+        let pressed = state == KeyState::Pressed;
+
+        match code {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftControl => self.left_ctrl = pressed,
+            KeyCode::RightControl => self.right_ctrl = pressed,
+            KeyCode::LeftAlt => self.left_alt = pressed,
+            KeyCode::RightAlt => self.right_alt = pressed,
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NumLock if pressed => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if pressed => self.scroll_lock = !self.scroll_lock,
+            _ => return false,
+        }
+
+        matches!(
+            code,
+            KeyCode::CapsLock | KeyCode::NumLock | KeyCode::ScrollLock
+        )
+    }
+
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+
+        leds
+    }
+}
+
+/// A key press or release
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// The modifiers after this event
+    pub modifiers: Modifiers,
+    /// The character a press types with the current layout
+    pub character: Option<char>,
+}
+
+/// Progress of updating the LEDs from the IRQ handler, which only writes when
+/// the controller can take a byte right away and otherwise tries again on the
+/// next interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedUpdate {
+    Idle,
+    /// `CMD_SET_LEDS` still has to be written
+    Queued,
+    /// Waiting for `CMD_SET_LEDS` to be acknowledged
+    CommandSent,
+    /// The LED state still has to be written
+    CommandAcked,
+    /// Waiting for this LED state to be acknowledged
+    LedsSent(u8),
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+    layout: &'static dyn KeyboardLayout,
+    leds: LedUpdate,
+}
+
+impl Keyboard {
+    /// Moves the LED update on after the keyboard answered `reply`
+    fn led_reply(&mut self, reply: u8) {
+        self.leds = match (self.leds, reply) {
+            (LedUpdate::CommandSent, ACK) => LedUpdate::CommandAcked,
+            (LedUpdate::CommandSent, RESEND) => LedUpdate::Queued,
+            // The lock keys may have changed while the update was underway
+            (LedUpdate::LedsSent(leds), ACK) if leds != self.modifiers.leds() => LedUpdate::Queued,
+            (LedUpdate::LedsSent(_), ACK) => LedUpdate::Idle,
+            (LedUpdate::LedsSent(_), RESEND) => LedUpdate::CommandAcked,
+            (leds, _) => leds,
+        };
+    }
+
+    fn handle_byte(&mut self, byte: u8) {
+        match byte {
+            ACK | RESEND => return self.led_reply(byte),
+            KEY_ERROR | KEY_ERROR_ALTERNATE => return,
+            _ => {}
+        }
+
+        let (code, state) = match self.decoder.decode(byte) {
+            Some(key) => key,
+            None => return,
+        };
+
+        if self.modifiers.update(code, state) && self.leds == LedUpdate::Idle {
+            self.leds = LedUpdate::Queued;
+        }
+
+        let character = match state {
+            KeyState::Pressed => self.layout.map(code, &self.modifiers),
+            KeyState::Released => None,
+        };
+
+        EVENTS.push(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers,
+            character,
+        });
+    }
+
+    /// Writes the next byte of the LED update if the controller is ready
+    fn send_leds(&mut self) {
+        let (byte, next) = match self.leds {
+            LedUpdate::Queued => (CMD_SET_LEDS, LedUpdate::CommandSent),
+            LedUpdate::CommandAcked => {
+                let leds = self.modifiers.leds();
+                (leds, LedUpdate::LedsSent(leds))
+            }
+            _ => return,
+        };
+
+        match ps2::try_write_first(byte) {
+            Ok(true) => self.leds = next,
+            Ok(false) => {}
+            Err(_) => self.leds = LedUpdate::Idle,
+        }
+    }
+}
+
+static KEYBOARD: Mutex<Option<Keyboard>> = Mutex::new(None);
+static EVENTS: EventQueue<KeyEvent, 128> = EventQueue::new();
+
+/// Resets the keyboard, puts it in scancode set 1 or 2 and starts taking key
+/// events on IRQ 1. `ps2::init` has to have found the first port.
+pub fn init() -> Result<ScancodeSet, Ps2Error> {
+    let port = Ps2Port::First;
+
+    ps2::reset_device(port)?;
+    ps2::send(port, CMD_DISABLE_SCANNING)?;
+
+    let set = match current_scancode_set()? {
+        Some(set) => set,
+        None => {
+            ps2::send(port, CMD_SCANCODE_SET)?;
+            ps2::send(port, 2)?;
+            ScancodeSet::Set2
+        }
+    };
+
+    let modifiers = Modifiers {
+        num_lock: true,
+        ..Modifiers::default()
+    };
+    ps2::send(port, CMD_SET_LEDS)?;
+    ps2::send(port, modifiers.leds())?;
+
+    without_interrupts(|| {
+        *KEYBOARD.lock() = Some(Keyboard {
+            decoder: Decoder::new(set),
+            modifiers,
+            layout: &Us,
+            leds: LedUpdate::Idle,
+        })
+    });
+
+    // Registered before the keyboard can interrupt, as the edge of a byte
+    // arriving while the line is still masked would be lost, and with it
+    // every later one, since the controller only interrupts for a new byte
+    let handler = irq::register_handler(IRQ, |_| handle_interrupt());
+    if let Err(err) =
+        ps2::send(port, CMD_ENABLE_SCANNING).and_then(|()| ps2::enable_interrupt(port))
+    {
+        irq::unregister_handler(handler);
+        without_interrupts(|| *KEYBOARD.lock() = None);
+        return Err(err);
+    }
+
+    Ok(set)
+}
+
+/// The scancode set the keyboard uses, `None` for set 3
+fn current_scancode_set() -> Result<Option<ScancodeSet>, Ps2Error> {
+    ps2::send(Ps2Port::First, CMD_SCANCODE_SET)?;
+    ps2::send(Ps2Port::First, GET_SCANCODE_SET)?;
+
+    match ps2::read_data()? {
+        1 => Ok(Some(ScancodeSet::Set1)),
+        2 => Ok(Some(ScancodeSet::Set2)),
+        3 => Ok(None),
+        reply => Err(Ps2Error::UnexpectedReply(reply)),
+    }
+}
+
+fn handle_interrupt() {
+    let byte = match ps2::try_read_data(Ps2Port::First) {
+        Some(byte) => byte,
+        None => return,
+    };
+
+    if let Some(keyboard) = KEYBOARD.lock().as_mut() {
+        keyboard.handle_byte(byte);
+        // Also retries a byte the controller could not take last time
+        keyboard.send_leds();
+    }
+}
+
+/// Switches the layout characters are typed with, US until set
+pub fn set_layout(layout: &'static dyn KeyboardLayout) {
+    without_interrupts(|| {
+        if let Some(keyboard) = KEYBOARD.lock().as_mut() {
+            keyboard.layout = layout;
+        }
+    });
+}
+
+/// Name of the current layout, once initialized
+pub fn layout_name() -> Option<&'static str> {
+    without_interrupts(|| {
+        KEYBOARD
+            .lock()
+            .as_ref()
+            .map(|keyboard| keyboard.layout.name())
+    })
+}
+
+/// The current modifiers, once initialized
+pub fn modifiers() -> Option<Modifiers> {
+    without_interrupts(|| KEYBOARD.lock().as_ref().map(|keyboard| keyboard.modifiers))
+}
+
+/// Takes the oldest key event
+pub fn read_event() -> Option<KeyEvent> {
+    EVENTS.pop()
+}
+
+/// Takes key events until one that types a character
+pub fn read_char() -> Option<char> {
+    while let Some(event) = read_event() {
+        if event.character.is_some() {
+            return event.character;
+        }
+    }
+
+    None
+}
@@ -0,0 +1,358 @@
+//! Keyboard layouts, turning keys into the characters they type.
+
+use super::{KeyCode, Modifiers};
+
+/// The characters a key types without modifiers, with shift and with AltGr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub normal: char,
+    pub shifted: char,
+    pub alt_gr: Option<char>,
+}
+
+impl Key {
+    const fn new(normal: char, shifted: char) -> Self {
+        Self {
+            normal,
+            shifted,
+            alt_gr: None,
+        }
+    }
+
+    const fn with_alt_gr(normal: char, shifted: char, alt_gr: char) -> Self {
+        Self {
+            normal,
+            shifted,
+            alt_gr: Some(alt_gr),
+        }
+    }
+
+    fn letter(letter: char) -> Self {
+        Self::new(letter, letter.to_ascii_uppercase())
+    }
+
+    /// Caps lock only affects keys that type a lower and upper case letter
+    fn is_letter(&self) -> bool {
+        self.normal.is_alphabetic() && self.shifted.is_alphabetic()
+    }
+}
+
+pub trait KeyboardLayout: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The characters of a key in the main block that types text
+    fn key(&self, code: KeyCode) -> Option<Key>;
+
+    /// The character `code` types with `modifiers` held, if any. Control with
+    /// a letter types its control character.
+    fn map(&self, code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        if let Some(character) = common_key(code, modifiers) {
+            return Some(character);
+        }
+
+        let key = self.key(code)?;
+
+        if modifiers.alt_gr() {
+            return key.alt_gr;
+        }
+
+        let shifted = if key.is_letter() {
+            modifiers.shift() != modifiers.caps_lock
+        } else {
+            modifiers.shift()
+        };
+        let character = if shifted { key.shifted } else { key.normal };
+
+        if modifiers.ctrl() && character.is_ascii_alphabetic() {
+            return Some((character.to_ascii_lowercase() as u8 - b'a' + 1) as char);
+        }
+
+        Some(character)
+    }
+}
+
+/// Keys that type the same in every layout
+fn common_key(code: KeyCode, modifiers: &Modifiers) -> Option<char> {
+    use KeyCode::*;
+
+    let character = match code {
+        Enter | NumpadEnter => '\n',
+        Tab => '\t',
+        Backspace => '\x08',
+        Escape => '\x1b',
+        Space => ' ',
+        NumpadDivide => '/',
+        NumpadMultiply => '*',
+        NumpadSubtract => '-',
+        NumpadAdd => '+',
+        _ if !modifiers.num_lock => return None,
+        Numpad0 => '0',
+        Numpad1 => '1',
+        Numpad2 => '2',
+        Numpad3 => '3',
+        Numpad4 => '4',
+        Numpad5 => '5',
+        Numpad6 => '6',
+        Numpad7 => '7',
+        Numpad8 => '8',
+        Numpad9 => '9',
+        NumpadPeriod => '.',
+        _ => return None,
+    };
+
+    Some(character)
+}
+
+/// The letter on a key in the QWERTY arrangement
+fn qwerty_letter(code: KeyCode) -> Option<char> {
+    use KeyCode::*;
+
+    let letter = match code {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    };
+
+    Some(letter)
+}
+
+/// US QWERTY
+#[derive(Debug, Clone, Copy)]
+pub struct Us;
+
+impl KeyboardLayout for Us {
+    fn name(&self) -> &'static str {
+        "us"
+    }
+
+    fn key(&self, code: KeyCode) -> Option<Key> {
+        use KeyCode::*;
+
+        if let Some(letter) = qwerty_letter(code) {
+            return Some(Key::letter(letter));
+        }
+
+        let key = match code {
+            Backtick => Key::new('`', '~'),
+            Key1 => Key::new('1', '!'),
+            Key2 => Key::new('2', '@'),
+            Key3 => Key::new('3', '#'),
+            Key4 => Key::new('4', '$'),
+            Key5 => Key::new('5', '%'),
+            Key6 => Key::new('6', '^'),
+            Key7 => Key::new('7', '&'),
+            Key8 => Key::new('8', '*'),
+            Key9 => Key::new('9', '('),
+            Key0 => Key::new('0', ')'),
+            Minus => Key::new('-', '_'),
+            Equals => Key::new('=', '+'),
+            LeftBracket => Key::new('[', '{'),
+            RightBracket => Key::new(']', '}'),
+            Backslash | NonUsBackslash => Key::new('\\', '|'),
+            Semicolon => Key::new(';', ':'),
+            Quote => Key::new('\'', '"'),
+            Comma => Key::new(',', '<'),
+            Period => Key::new('.', '>'),
+            Slash => Key::new('/', '?'),
+            _ => return None,
+        };
+
+        Some(key)
+    }
+}
+
+/// UK QWERTY
+#[derive(Debug, Clone, Copy)]
+pub struct Uk;
+
+impl KeyboardLayout for Uk {
+    fn name(&self) -> &'static str {
+        "uk"
+    }
+
+    fn key(&self, code: KeyCode) -> Option<Key> {
+        use KeyCode::*;
+
+        let key = match code {
+            Backtick => Key::with_alt_gr('`', '¬', '¦'),
+            Key2 => Key::new('2', '"'),
+            Key3 => Key::new('3', '£'),
+            Key4 => Key::with_alt_gr('4', '$', '€'),
+            Quote => Key::new('\'', '@'),
+            // The key left of enter on ISO keyboards
+            Backslash => Key::new('#', '~'),
+            NonUsBackslash => Key::new('\\', '|'),
+            A => Key::with_alt_gr('a', 'A', 'á'),
+            E => Key::with_alt_gr('e', 'E', 'é'),
+            I => Key::with_alt_gr('i', 'I', 'í'),
+            O => Key::with_alt_gr('o', 'O', 'ó'),
+            U => Key::with_alt_gr('u', 'U', 'ú'),
+            _ => return Us.key(code),
+        };
+
+        Some(key)
+    }
+}
+
+/// German QWERTZ. The dead keys type their accent by themselves.
+#[derive(Debug, Clone, Copy)]
+pub struct De;
+
+impl KeyboardLayout for De {
+    fn name(&self) -> &'static str {
+        "de"
+    }
+
+    fn key(&self, code: KeyCode) -> Option<Key> {
+        use KeyCode::*;
+
+        let key = match code {
+            Y => Key::letter('z'),
+            Z => Key::letter('y'),
+            Q => Key::with_alt_gr('q', 'Q', '@'),
+            E => Key::with_alt_gr('e', 'E', '€'),
+            M => Key::with_alt_gr('m', 'M', 'µ'),
+            code if qwerty_letter(code).is_some() => return Us.key(code),
+            Backtick => Key::new('^', '°'),
+            Key1 => Key::new('1', '!'),
+            Key2 => Key::with_alt_gr('2', '"', '²'),
+            Key3 => Key::with_alt_gr('3', '§', '³'),
+            Key4 => Key::new('4', '$'),
+            Key5 => Key::new('5', '%'),
+            Key6 => Key::new('6', '&'),
+            Key7 => Key::with_alt_gr('7', '/', '{'),
+            Key8 => Key::with_alt_gr('8', '(', '['),
+            Key9 => Key::with_alt_gr('9', ')', ']'),
+            Key0 => Key::with_alt_gr('0', '=', '}'),
+            Minus => Key::with_alt_gr('ß', '?', '\\'),
+            Equals => Key::new('´', '`'),
+            LeftBracket => Key::new('ü', 'Ü'),
+            RightBracket => Key::with_alt_gr('+', '*', '~'),
+            Semicolon => Key::new('ö', 'Ö'),
+            Quote => Key::new('ä', 'Ä'),
+            // The key left of enter on ISO keyboards
+            Backslash => Key::new('#', '\''),
+            NonUsBackslash => Key::with_alt_gr('<', '>', '|'),
+            Comma => Key::new(',', ';'),
+            Period => Key::new('.', ':'),
+            Slash => Key::new('-', '_'),
+            _ => return None,
+        };
+
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::KeyCode::*;
+
+    fn modifiers(shift: bool, caps_lock: bool, alt_gr: bool) -> Modifiers {
+        Modifiers {
+            left_shift: shift,
+            caps_lock,
+            right_alt: alt_gr,
+            ..Modifiers::default()
+        }
+    }
+
+    #[test]
+    fn shift_and_caps_lock() {
+        // (key, shift, caps lock, character)
+        let table = [
+            (A, false, false, 'a'),
+            (A, true, false, 'A'),
+            (A, false, true, 'A'),
+            (A, true, true, 'a'),
+            (Key1, false, false, '1'),
+            (Key1, true, false, '!'),
+            (Key1, false, true, '1'),
+            (Slash, true, true, '?'),
+        ];
+
+        for (code, shift, caps_lock, character) in table {
+            let modifiers = modifiers(shift, caps_lock, false);
+            assert_eq!(Us.map(code, &modifiers), Some(character), "{:?}", code);
+        }
+
+        // Letters outside a-z follow caps lock as well
+        assert_eq!(De.map(Semicolon, &modifiers(false, true, false)), Some('Ö'));
+        assert_eq!(De.map(Minus, &modifiers(false, true, false)), Some('ß'));
+    }
+
+    #[test]
+    fn alt_gr() {
+        // (layout, key, shift, character)
+        let table: [(&dyn KeyboardLayout, _, _, _); 6] = [
+            (&De, Q, false, Some('@')),
+            (&De, Key7, true, Some('{')),
+            (&De, A, false, None),
+            (&Uk, Key4, false, Some('€')),
+            (&Uk, E, false, Some('é')),
+            (&Us, A, false, None),
+        ];
+
+        for (layout, code, shift, character) in table {
+            let modifiers = modifiers(shift, false, true);
+            assert_eq!(layout.map(code, &modifiers), character, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn layouts_differ() {
+        let none = Modifiers::default();
+        let shift = modifiers(true, false, false);
+
+        assert_eq!(De.map(Y, &none), Some('z'));
+        assert_eq!(De.map(Z, &shift), Some('Y'));
+        assert_eq!(Uk.map(Key3, &shift), Some('£'));
+        assert_eq!(Uk.map(Backslash, &none), Some('#'));
+        assert_eq!(Us.map(Backslash, &none), Some('\\'));
+    }
+
+    #[test]
+    fn control_and_common_keys() {
+        let ctrl = Modifiers {
+            left_ctrl: true,
+            ..Modifiers::default()
+        };
+        assert_eq!(Us.map(C, &ctrl), Some('\x03'));
+        assert_eq!(De.map(Y, &ctrl), Some('\x1a'));
+
+        let none = Modifiers::default();
+        let num_lock = Modifiers {
+            num_lock: true,
+            ..Modifiers::default()
+        };
+        assert_eq!(Uk.map(Enter, &none), Some('\n'));
+        assert_eq!(Us.map(NumpadAdd, &none), Some('+'));
+        assert_eq!(Us.map(Numpad7, &none), None);
+        assert_eq!(Us.map(Numpad7, &num_lock), Some('7'));
+        assert_eq!(Us.map(F1, &none), None);
+    }
+}
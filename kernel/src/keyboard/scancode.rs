@@ -0,0 +1,459 @@
+//! Decoding of scancode sets 1 and 2 into key presses and releases.
+
+use super::{KeyCode, KeyState};
+
+/// The scancode set the keyboard sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+const EXTENDED: u8 = 0xe0;
+const PAUSE: u8 = 0xe1;
+const SET_2_RELEASE: u8 = 0xf0;
+const SET_1_RELEASE: u8 = 0x80;
+
+/// Bytes after the first of the pause sequence, which has no release
+const SET_1_PAUSE_LENGTH: u8 = 5;
+const SET_2_PAUSE_LENGTH: u8 = 7;
+
+/// Turns the bytes of a scancode set into key events, one byte at a time
+#[derive(Debug, Clone, Copy)]
+pub struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    /// Bytes of the pause sequence still to skip
+    pause_remaining: u8,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            release: false,
+            pause_remaining: 0,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Feeds the next byte from the keyboard, returning the key event it
+    /// completes
+    pub fn decode(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        if self.pause_remaining > 0 {
+            self.pause_remaining -= 1;
+            return None;
+        }
+
+        match (self.set, byte) {
+            (_, EXTENDED) => {
+                self.extended = true;
+                None
+            }
+            (ScancodeSet::Set1, PAUSE) => {
+                self.pause_remaining = SET_1_PAUSE_LENGTH;
+                Some((KeyCode::Pause, KeyState::Pressed))
+            }
+            (ScancodeSet::Set2, PAUSE) => {
+                self.pause_remaining = SET_2_PAUSE_LENGTH;
+                Some((KeyCode::Pause, KeyState::Pressed))
+            }
+            (ScancodeSet::Set2, SET_2_RELEASE) => {
+                self.release = true;
+                None
+            }
+            (ScancodeSet::Set1, byte) => {
+                let extended = core::mem::take(&mut self.extended);
+                let state = if byte & SET_1_RELEASE != 0 {
+                    KeyState::Released
+                } else {
+                    KeyState::Pressed
+                };
+
+                set_1_key(byte & !SET_1_RELEASE, extended).map(|key| (key, state))
+            }
+            (ScancodeSet::Set2, byte) => {
+                let extended = core::mem::take(&mut self.extended);
+                let state = if core::mem::take(&mut self.release) {
+                    KeyState::Released
+                } else {
+                    KeyState::Pressed
+                };
+
+                set_2_key(byte, extended).map(|key| (key, state))
+            }
+        }
+    }
+}
+
+/// The key of a set 1 make code. The fake shifts around extended keys map to
+/// no key.
+fn set_1_key(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    let key = if extended {
+        match code {
+            0x1c => NumpadEnter,
+            0x1d => RightControl,
+            0x35 => NumpadDivide,
+            0x37 => PrintScreen,
+            0x38 => RightAlt,
+            0x47 => Home,
+            0x48 => ArrowUp,
+            0x49 => PageUp,
+            0x4b => ArrowLeft,
+            0x4d => ArrowRight,
+            0x4f => End,
+            0x50 => ArrowDown,
+            0x51 => PageDown,
+            0x52 => Insert,
+            0x53 => Delete,
+            0x5b => LeftGui,
+            0x5c => RightGui,
+            0x5d => Menu,
+            _ => return None,
+        }
+    } else {
+        match code {
+            0x01 => Escape,
+            0x02 => Key1,
+            0x03 => Key2,
+            0x04 => Key3,
+            0x05 => Key4,
+            0x06 => Key5,
+            0x07 => Key6,
+            0x08 => Key7,
+            0x09 => Key8,
+            0x0a => Key9,
+            0x0b => Key0,
+            0x0c => Minus,
+            0x0d => Equals,
+            0x0e => Backspace,
+            0x0f => Tab,
+            0x10 => Q,
+            0x11 => W,
+            0x12 => E,
+            0x13 => R,
+            0x14 => T,
+            0x15 => Y,
+            0x16 => U,
+            0x17 => I,
+            0x18 => O,
+            0x19 => P,
+            0x1a => LeftBracket,
+            0x1b => RightBracket,
+            0x1c => Enter,
+            0x1d => LeftControl,
+            0x1e => A,
+            0x1f => S,
+            0x20 => D,
+            0x21 => F,
+            0x22 => G,
+            0x23 => H,
+            0x24 => J,
+            0x25 => K,
+            0x26 => L,
+            0x27 => Semicolon,
+            0x28 => Quote,
+            0x29 => Backtick,
+            0x2a => LeftShift,
+            0x2b => Backslash,
+            0x2c => Z,
+            0x2d => X,
+            0x2e => C,
+            0x2f => V,
+            0x30 => B,
+            0x31 => N,
+            0x32 => M,
+            0x33 => Comma,
+            0x34 => Period,
+            0x35 => Slash,
+            0x36 => RightShift,
+            0x37 => NumpadMultiply,
+            0x38 => LeftAlt,
+            0x39 => Space,
+            0x3a => CapsLock,
+            0x3b => F1,
+            0x3c => F2,
+            0x3d => F3,
+            0x3e => F4,
+            0x3f => F5,
+            0x40 => F6,
+            0x41 => F7,
+            0x42 => F8,
+            0x43 => F9,
+            0x44 => F10,
+            0x45 => NumLock,
+            0x46 => ScrollLock,
+            0x47 => Numpad7,
+            0x48 => Numpad8,
+            0x49 => Numpad9,
+            0x4a => NumpadSubtract,
+            0x4b => Numpad4,
+            0x4c => Numpad5,
+            0x4d => Numpad6,
+            0x4e => NumpadAdd,
+            0x4f => Numpad1,
+            0x50 => Numpad2,
+            0x51 => Numpad3,
+            0x52 => Numpad0,
+            0x53 => NumpadPeriod,
+            0x56 => NonUsBackslash,
+            0x57 => F11,
+            0x58 => F12,
+            _ => return None,
+        }
+    };
+
+    Some(key)
+}
+
+/// The key of a set 2 code, without its release prefix. The fake shifts
+/// around extended keys map to no key.
+fn set_2_key(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    let key = if extended {
+        match code {
+            0x11 => RightAlt,
+            0x14 => RightControl,
+            0x1f => LeftGui,
+            0x27 => RightGui,
+            0x2f => Menu,
+            0x4a => NumpadDivide,
+            0x5a => NumpadEnter,
+            0x69 => End,
+            0x6b => ArrowLeft,
+            0x6c => Home,
+            0x70 => Insert,
+            0x71 => Delete,
+            0x72 => ArrowDown,
+            0x74 => ArrowRight,
+            0x75 => ArrowUp,
+            0x7a => PageDown,
+            0x7c => PrintScreen,
+            0x7d => PageUp,
+            _ => return None,
+        }
+    } else {
+        match code {
+            0x01 => F9,
+            0x03 => F5,
+            0x04 => F3,
+            0x05 => F1,
+            0x06 => F2,
+            0x07 => F12,
+            0x09 => F10,
+            0x0a => F8,
+            0x0b => F6,
+            0x0c => F4,
+            0x0d => Tab,
+            0x0e => Backtick,
+            0x11 => LeftAlt,
+            0x12 => LeftShift,
+            0x14 => LeftControl,
+            0x15 => Q,
+            0x16 => Key1,
+            0x1a => Z,
+            0x1b => S,
+            0x1c => A,
+            0x1d => W,
+            0x1e => Key2,
+            0x21 => C,
+            0x22 => X,
+            0x23 => D,
+            0x24 => E,
+            0x25 => Key4,
+            0x26 => Key3,
+            0x29 => Space,
+            0x2a => V,
+            0x2b => F,
+            0x2c => T,
+            0x2d => R,
+            0x2e => Key5,
+            0x31 => N,
+            0x32 => B,
+            0x33 => H,
+            0x34 => G,
+            0x35 => Y,
+            0x36 => Key6,
+            0x3a => M,
+            0x3b => J,
+            0x3c => U,
+            0x3d => Key7,
+            0x3e => Key8,
+            0x41 => Comma,
+            0x42 => K,
+            0x43 => I,
+            0x44 => O,
+            0x45 => Key0,
+            0x46 => Key9,
+            0x49 => Period,
+            0x4a => Slash,
+            0x4b => L,
+            0x4c => Semicolon,
+            0x4d => P,
+            0x4e => Minus,
+            0x52 => Quote,
+            0x54 => LeftBracket,
+            0x55 => Equals,
+            0x58 => CapsLock,
+            0x59 => RightShift,
+            0x5a => Enter,
+            0x5b => RightBracket,
+            0x5d => Backslash,
+            0x61 => NonUsBackslash,
+            0x66 => Backspace,
+            0x69 => Numpad1,
+            0x6b => Numpad4,
+            0x6c => Numpad7,
+            0x70 => Numpad0,
+            0x71 => NumpadPeriod,
+            0x72 => Numpad2,
+            0x73 => Numpad5,
+            0x74 => Numpad6,
+            0x75 => Numpad8,
+            0x76 => Escape,
+            0x77 => NumLock,
+            0x78 => F11,
+            0x79 => NumpadAdd,
+            0x7a => Numpad3,
+            0x7b => NumpadSubtract,
+            0x7c => NumpadMultiply,
+            0x7d => Numpad9,
+            0x7e => ScrollLock,
+            0x83 => F7,
+            _ => return None,
+        }
+    };
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::{KeyCode::*, KeyState::*};
+
+    /// Bytes from the keyboard and the key events they decode to
+    type Case = (&'static [u8], &'static [(KeyCode, KeyState)]);
+
+    fn decode_all(set: ScancodeSet, bytes: &[u8]) -> Vec<(KeyCode, KeyState)> {
+        let mut decoder = Decoder::new(set);
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.decode(byte))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_set_1() {
+        let table: &[Case] = &[
+            (&[0x1e, 0x9e], &[(A, Pressed), (A, Released)]),
+            (&[0x01, 0x81], &[(Escape, Pressed), (Escape, Released)]),
+            (
+                &[0x1d, 0x9d],
+                &[(LeftControl, Pressed), (LeftControl, Released)],
+            ),
+            (
+                &[0xe0, 0x1d, 0xe0, 0x9d],
+                &[(RightControl, Pressed), (RightControl, Released)],
+            ),
+            (
+                &[0xe0, 0x48, 0xe0, 0xc8],
+                &[(ArrowUp, Pressed), (ArrowUp, Released)],
+            ),
+            (&[0x48], &[(Numpad8, Pressed)]),
+            (&[0x56, 0x58], &[(NonUsBackslash, Pressed), (F12, Pressed)]),
+            (&[0x7f, 0xe0, 0x7f], &[]),
+        ];
+
+        for (bytes, events) in table {
+            assert_eq!(
+                decode_all(ScancodeSet::Set1, bytes),
+                *events,
+                "{:x?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_set_2() {
+        let table: &[Case] = &[
+            (&[0x1c, 0xf0, 0x1c], &[(A, Pressed), (A, Released)]),
+            (
+                &[0x76, 0xf0, 0x76],
+                &[(Escape, Pressed), (Escape, Released)],
+            ),
+            (&[0x83], &[(F7, Pressed)]),
+            (
+                &[0xe0, 0x14, 0xe0, 0xf0, 0x14],
+                &[(RightControl, Pressed), (RightControl, Released)],
+            ),
+            (
+                &[0xe0, 0x75, 0xe0, 0xf0, 0x75],
+                &[(ArrowUp, Pressed), (ArrowUp, Released)],
+            ),
+            (&[0x75], &[(Numpad8, Pressed)]),
+            (
+                &[0xe0, 0x11, 0x11],
+                &[(RightAlt, Pressed), (LeftAlt, Pressed)],
+            ),
+            (&[0x00, 0xe0, 0x00], &[]),
+        ];
+
+        for (bytes, events) in table {
+            assert_eq!(
+                decode_all(ScancodeSet::Set2, bytes),
+                *events,
+                "{:x?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_print_screen() {
+        // With the fake shifts around it, which are no key
+        assert_eq!(
+            decode_all(
+                ScancodeSet::Set1,
+                &[0xe0, 0x2a, 0xe0, 0x37, 0xe0, 0xb7, 0xe0, 0xaa]
+            ),
+            [(PrintScreen, Pressed), (PrintScreen, Released)]
+        );
+        assert_eq!(
+            decode_all(
+                ScancodeSet::Set2,
+                &[0xe0, 0x12, 0xe0, 0x7c, 0xe0, 0xf0, 0x7c, 0xe0, 0xf0, 0x12]
+            ),
+            [(PrintScreen, Pressed), (PrintScreen, Released)]
+        );
+    }
+
+    #[test]
+    fn decodes_pause() {
+        // The whole sequence is one press, and the decoder is back in sync
+        // for the next key
+        assert_eq!(
+            decode_all(
+                ScancodeSet::Set1,
+                &[0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0x1e]
+            ),
+            [(Pause, Pressed), (A, Pressed)]
+        );
+        assert_eq!(
+            decode_all(
+                ScancodeSet::Set2,
+                &[0xe1, 0x14, 0x77, 0xe1, 0xf0, 0x14, 0xf0, 0x77, 0x1c]
+            ),
+            [(Pause, Pressed), (A, Pressed)]
+        );
+    }
+}
@@ -1,6 +1,6 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
-#![feature(default_alloc_error_handler)]
+#![feature(alloc_error_handler)]
 
 extern crate alloc;
 
@@ -11,10 +11,28 @@ extern crate lazy_static;
 pub mod serial;
 
 pub mod acpi;
+// Host tests use the handler of the standard library
+#[cfg(not(test))]
+mod alloc_error;
+pub mod apic;
+pub mod backtrace;
 pub mod console;
+pub mod event_queue;
+pub mod exception;
 pub mod gdt;
 pub mod graphics;
 pub mod idt;
+pub mod ioapic;
+pub mod irq;
+pub mod keyboard;
+pub mod mouse;
+pub mod percpu;
+pub mod pic;
+pub mod ps2;
+pub mod rtc;
+pub mod smp;
+pub mod time;
+pub mod uart;
 
 #[no_mangle]
 fn fminf(a: f32, b: f32) -> f32 {
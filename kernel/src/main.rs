@@ -1,6 +1,5 @@
 #![no_std]
 #![no_main]
-#![feature(default_alloc_error_handler)]
 
 extern crate alloc;
 
@@ -13,23 +12,58 @@ use colors::OwoColorize;
 use console_vga::{AnsiConsoleDriver, FormattedChar, RawConsoleDriver};
 use kernel::{
     acpi::{get_acpi_tables, ACPI_TABLES},
+    apic,
+    backtrace::Backtrace,
     console::setup_console,
-    gdt,
+    exception, gdt,
     graphics::setup_graphics,
-    idt, serial_println,
+    idt, keyboard, mouse, percpu, pic, ps2, rtc,
+    serial::{self, CrLfWriter, SerialWriter},
+    serial_println, smp,
+    time::{self, Duration, TickMode},
 };
 use kernel_memory::{allocator::init_heap, init_allocator, with_mapper_and_allocator};
 use palette::Srgb;
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    serial_println!("Panicked at {}", info);
-    loop {}
+    let backtrace = Backtrace::capture();
+
+    // Nothing else runs after a panic, so take the port even if the panic
+    // interrupted a print, like `exception::fatal`
+    x86_64::instructions::interrupts::disable();
+    unsafe { serial::port().force_polled() };
+
+    // Under one lock, so nothing is printed in between
+    let _lock = serial::print_lock();
+    let mut serial = CrLfWriter(&mut SerialWriter);
+    let _ = writeln!(serial, "Panicked at {}", info);
+    let _ = write!(serial, "{}", backtrace);
+
+    exception::halt()
 }
 
 entry_point!(kmain);
 
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
 fn kmain(boot_info: &'static mut BootInfo) -> ! {
+    // Report faults from here on, the full IDT needs the heap
+    idt::init_boot();
+
+    serial_println!("Set up paging");
+    init_allocator(boot_info);
+    serial_println!("[COMPLETE]");
+
+    serial_println!("Setup heap");
+    with_mapper_and_allocator(|mapper, frame_allocator| {
+        init_heap(mapper, frame_allocator).expect("Heap init Failed")
+    });
+    serial_println!("[COMPLETE]");
+
+    // The bootstrap processor is CPU 0
+    percpu::init(0);
+
     serial_println!("Setting up GDT/TSS");
     gdt::init();
     serial_println!("[COMPLETE]");
@@ -38,15 +72,23 @@ fn kmain(boot_info: &'static mut BootInfo) -> ! {
     idt::init();
     serial_println!("[COMPLETE]");
 
-    serial_println!("Set up paging");
-    init_allocator(&boot_info);
+    serial_println!("Setting up PIC");
+    pic::init();
+    x86_64::instructions::interrupts::enable();
     serial_println!("[COMPLETE]");
 
-    serial_println!("Setup heap");
-    with_mapper_and_allocator(|mapper, frame_allocator| {
-        init_heap(mapper, frame_allocator).expect("Heap init Failed")
-    });
-    serial_println!("[COMPLETE]");
+    serial_println!("{}", kernel_memory::stats());
+
+    serial_println!("Check kernel mappings for W^X");
+    let writable_executable = kernel_memory::protection::writable_executable_ranges();
+    for range in &writable_executable {
+        serial_println!(
+            "  writable and executable: {:#x} - {:#x}",
+            range.start.as_u64(),
+            range.start.as_u64() + range.size
+        );
+    }
+    serial_println!("[{} W+X RANGES]", writable_executable.len());
 
     serial_println!("Setup ACPI Tables");
     let tables = kernel::acpi::init(boot_info);
@@ -54,6 +96,54 @@ fn kmain(boot_info: &'static mut BootInfo) -> ! {
     unsafe { ACPI_TABLES = Some(tables) };
     serial_println!("[COMPLETE]");
 
+    serial_println!("Setup APIC");
+    match apic::init(get_acpi_tables()) {
+        Ok(()) => serial_println!("[COMPLETE]"),
+        Err(err) => serial_println!("[USING PIC: {:?}]", err),
+    }
+
+    serial_println!("Setup serial interrupts");
+    match serial::enable_interrupts() {
+        Ok(()) => serial_println!("[COMPLETE]"),
+        Err(err) => serial_println!("[POLLING: {:?}]", err),
+    }
+
+    serial_println!("Setup timekeeping");
+    time::init(get_acpi_tables());
+    if let Err(err) = time::start_tick(TICK_INTERVAL, TickMode::Periodic) {
+        serial_println!("No tick: {:?}", err);
+    }
+    serial_println!("[CLOCK: {}]", time::clock_source_name().unwrap());
+
+    serial_println!("Read RTC");
+    rtc::init(get_acpi_tables());
+    serial_println!("[{} UTC]", rtc::now());
+
+    serial_println!("Start application processors");
+    match smp::init(get_acpi_tables()) {
+        Ok(cpus) => serial_println!("[{} CPUS]", cpus),
+        Err(err) => serial_println!("[SINGLE CPU: {:?}]", err),
+    }
+
+    serial_println!("Setup PS/2 devices");
+    match ps2::init() {
+        Ok(ports) => {
+            if ports.first {
+                match keyboard::init() {
+                    Ok(set) => serial_println!("[KEYBOARD: {:?}]", set),
+                    Err(err) => serial_println!("[NO KEYBOARD: {:?}]", err),
+                }
+            }
+            if ports.second {
+                match mouse::init() {
+                    Ok(kind) => serial_println!("[MOUSE: {:?}]", kind),
+                    Err(err) => serial_println!("[NO MOUSE: {:?}]", err),
+                }
+            }
+        }
+        Err(err) => serial_println!("[NO PS/2 CONTROLLER: {:?}]", err),
+    }
+
     serial_println!("Setup graphics drivers.");
     setup_graphics(boot_info);
     serial_println!("[COMPLETE]");
@@ -63,6 +153,7 @@ fn kmain(boot_info: &'static mut BootInfo) -> ! {
     serial_println!("[COMPLETE]");
 
     writeln!(&mut console, "Graphics Initialized").unwrap();
+    writeln!(&mut console, "{}", kernel_memory::stats()).unwrap();
 
     AnsiConsoleDriver::write_str(
         &mut console,
@@ -75,5 +166,11 @@ fn kmain(boot_info: &'static mut BootInfo) -> ! {
         ),
     );
 
-    loop {}
+    // Echo what is typed into the serial console
+    loop {
+        while let Some(byte) = serial::read_byte() {
+            serial::port().write_byte(byte);
+        }
+        x86_64::instructions::hlt();
+    }
 }
@@ -0,0 +1,349 @@
+//! Driver for the PS/2 mouse on the second port of the 8042 controller.
+//! Movement packets are decoded in the IRQ 12 handler and queued as motion,
+//! button and scroll events for `read_event`.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    event_queue::EventQueue,
+    irq,
+    ps2::{self, Ps2Error, Ps2Port},
+};
+
+const IRQ: u8 = 12;
+
+const CMD_GET_ID: u8 = 0xf2;
+const CMD_SET_SAMPLE_RATE: u8 = 0xf3;
+const CMD_ENABLE_REPORTING: u8 = 0xf4;
+
+/// Samples per second once initialized
+const SAMPLE_RATE: u8 = 100;
+/// Setting these sample rates in a row unlocks the scroll wheel
+const INTELLIMOUSE_SEQUENCE: [u8; 3] = [200, 100, 80];
+
+const ID_INTELLIMOUSE: u8 = 0x03;
+
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+/// Set in the first byte of every packet, used to find the packet boundaries
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    /// Three buttons, three byte packets
+    Standard,
+    /// A scroll wheel as well, four byte packets
+    IntelliMouse,
+}
+
+impl MouseKind {
+    fn packet_size(&self) -> usize {
+        match self {
+            MouseKind::Standard => 3,
+            MouseKind::IntelliMouse => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+impl MouseButton {
+    const ALL: [MouseButton; 3] = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+
+    fn mask(&self) -> u8 {
+        match self {
+            MouseButton::Left => PACKET_LEFT,
+            MouseButton::Right => PACKET_RIGHT,
+            MouseButton::Middle => PACKET_MIDDLE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ButtonState {
+    Pressed,
+    Released,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// Relative movement, with `dy` growing downwards like screen coordinates
+    Motion { dx: i16, dy: i16 },
+    Button {
+        button: MouseButton,
+        state: ButtonState,
+    },
+    /// Scroll wheel movement, positive towards the user
+    Scroll(i8),
+}
+
+/// Collects the bytes of a packet and turns them into events
+struct Mouse {
+    kind: MouseKind,
+    packet: [u8; 4],
+    received: usize,
+    /// The button bits of the last packet
+    buttons: u8,
+}
+
+impl Mouse {
+    fn new(kind: MouseKind) -> Self {
+        Self {
+            kind,
+            packet: [0; 4],
+            received: 0,
+            buttons: 0,
+        }
+    }
+
+    /// Takes the next byte from the mouse, passing the events of a completed
+    /// packet to `emit`
+    fn receive(&mut self, byte: u8, emit: impl FnMut(MouseEvent)) {
+        // A first byte without the always set bit means a byte was lost, so
+        // wait for the start of the next packet
+        if self.received == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+
+        if self.received == self.kind.packet_size() {
+            self.received = 0;
+            self.decode_packet(emit);
+        }
+    }
+
+    fn decode_packet(&mut self, mut emit: impl FnMut(MouseEvent)) {
+        let [flags, x, y, wheel] = self.packet;
+
+        for button in MouseButton::ALL {
+            let pressed = flags & button.mask() != 0;
+            if pressed != (self.buttons & button.mask() != 0) {
+                let state = if pressed {
+                    ButtonState::Pressed
+                } else {
+                    ButtonState::Released
+                };
+                emit(MouseEvent::Button { button, state });
+            }
+        }
+        self.buttons = flags & (PACKET_LEFT | PACKET_RIGHT | PACKET_MIDDLE);
+
+        // The movement of an overflowed packet is meaningless
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) == 0 {
+            let dx = sign_extend(x, flags & PACKET_X_SIGN != 0);
+            let dy = -sign_extend(y, flags & PACKET_Y_SIGN != 0);
+            if dx != 0 || dy != 0 {
+                emit(MouseEvent::Motion { dx, dy });
+            }
+        }
+
+        if self.kind == MouseKind::IntelliMouse && wheel != 0 {
+            emit(MouseEvent::Scroll(wheel as i8));
+        }
+    }
+}
+
+/// Movement is nine bit two's complement, with the sign bit in the flags
+fn sign_extend(value: u8, negative: bool) -> i16 {
+    if negative {
+        value as i16 - 0x100
+    } else {
+        value as i16
+    }
+}
+
+static MOUSE: Mutex<Option<Mouse>> = Mutex::new(None);
+static EVENTS: EventQueue<MouseEvent, 256> = EventQueue::new();
+
+/// Resets the mouse, enables its scroll wheel if it has one and starts
+/// taking packets on IRQ 12. `ps2::init` has to have found the second port.
+pub fn init() -> Result<MouseKind, Ps2Error> {
+    // The replies of the mouse are read from the same buffer as key presses
+    ps2::with_port_disabled(Ps2Port::First, setup)
+}
+
+fn setup() -> Result<MouseKind, Ps2Error> {
+    let port = Ps2Port::Second;
+
+    ps2::reset_device(port)?;
+    // The self test result is followed by the device ID
+    ps2::read_data()?;
+
+    for rate in INTELLIMOUSE_SEQUENCE {
+        set_sample_rate(rate)?;
+    }
+    let kind = match device_id()? {
+        ID_INTELLIMOUSE => MouseKind::IntelliMouse,
+        _ => MouseKind::Standard,
+    };
+    set_sample_rate(SAMPLE_RATE)?;
+
+    without_interrupts(|| *MOUSE.lock() = Some(Mouse::new(kind)));
+
+    // Registered first for the same reason as the keyboard handler, an edge
+    // while the line is masked would stall the mouse for good
+    let handler = irq::register_handler(IRQ, |_| handle_interrupt());
+    if let Err(err) =
+        ps2::send(port, CMD_ENABLE_REPORTING).and_then(|()| ps2::enable_interrupt(port))
+    {
+        irq::unregister_handler(handler);
+        without_interrupts(|| *MOUSE.lock() = None);
+        return Err(err);
+    }
+
+    Ok(kind)
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    ps2::send(Ps2Port::Second, CMD_SET_SAMPLE_RATE)?;
+    ps2::send(Ps2Port::Second, rate)
+}
+
+fn device_id() -> Result<u8, Ps2Error> {
+    ps2::send(Ps2Port::Second, CMD_GET_ID)?;
+    ps2::read_data()
+}
+
+fn handle_interrupt() {
+    let byte = match ps2::try_read_data(Ps2Port::Second) {
+        Some(byte) => byte,
+        None => return,
+    };
+
+    if let Some(mouse) = MOUSE.lock().as_mut() {
+        mouse.receive(byte, |event| {
+            EVENTS.push(event);
+        });
+    }
+}
+
+/// Takes the oldest mouse event
+pub fn read_event() -> Option<MouseEvent> {
+    EVENTS.pop()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{ButtonState::*, MouseButton::*, MouseEvent::*};
+
+    fn receive_all(mouse: &mut Mouse, bytes: &[u8]) -> Vec<MouseEvent> {
+        let mut events = Vec::new();
+        for &byte in bytes {
+            mouse.receive(byte, |event| events.push(event));
+        }
+        events
+    }
+
+    #[test]
+    fn decodes_motion_with_sign_extension() {
+        let mut mouse = Mouse::new(MouseKind::Standard);
+
+        assert_eq!(
+            receive_all(&mut mouse, &[0x08, 0x05, 0x03]),
+            [Motion { dx: 5, dy: -3 }]
+        );
+        assert_eq!(
+            receive_all(&mut mouse, &[0x38, 0xfb, 0xfd]),
+            [Motion { dx: -5, dy: 3 }]
+        );
+        assert_eq!(
+            receive_all(&mut mouse, &[0x18, 0x00, 0xff]),
+            [Motion { dx: -256, dy: -255 }]
+        );
+        assert_eq!(receive_all(&mut mouse, &[0x08, 0x00, 0x00]), []);
+    }
+
+    #[test]
+    fn reports_button_changes() {
+        let mut mouse = Mouse::new(MouseKind::Standard);
+
+        assert_eq!(
+            receive_all(&mut mouse, &[0x09, 0x00, 0x00]),
+            [Button {
+                button: Left,
+                state: Pressed
+            }]
+        );
+        assert_eq!(receive_all(&mut mouse, &[0x09, 0x00, 0x00]), []);
+        assert_eq!(
+            receive_all(&mut mouse, &[0x0e, 0x00, 0x00]),
+            [
+                Button {
+                    button: Left,
+                    state: Released
+                },
+                Button {
+                    button: Right,
+                    state: Pressed
+                },
+                Button {
+                    button: Middle,
+                    state: Pressed
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_the_motion_of_overflowed_packets() {
+        let mut mouse = Mouse::new(MouseKind::Standard);
+
+        assert_eq!(receive_all(&mut mouse, &[0x48, 0x05, 0x05]), []);
+        assert_eq!(receive_all(&mut mouse, &[0x88, 0x05, 0x05]), []);
+        assert_eq!(
+            receive_all(&mut mouse, &[0xc9, 0x05, 0x05]),
+            [Button {
+                button: Left,
+                state: Pressed
+            }]
+        );
+    }
+
+    #[test]
+    fn decodes_the_intellimouse_wheel() {
+        let mut mouse = Mouse::new(MouseKind::IntelliMouse);
+
+        assert_eq!(
+            receive_all(&mut mouse, &[0x08, 0x00, 0x00, 0x01]),
+            [Scroll(1)]
+        );
+        assert_eq!(
+            receive_all(&mut mouse, &[0x08, 0x00, 0x00, 0xff]),
+            [Scroll(-1)]
+        );
+        assert_eq!(receive_all(&mut mouse, &[0x08, 0x01, 0x00]), []);
+        assert_eq!(receive_all(&mut mouse, &[0x00]), [Motion { dx: 1, dy: 0 }]);
+
+        // A standard mouse has no fourth byte
+        let mut mouse = Mouse::new(MouseKind::Standard);
+        assert_eq!(
+            receive_all(&mut mouse, &[0x08, 0x01, 0x00, 0x01]),
+            [Motion { dx: 1, dy: 0 }]
+        );
+    }
+
+    #[test]
+    fn resyncs_after_a_lost_first_byte() {
+        let mut mouse = Mouse::new(MouseKind::Standard);
+
+        // The flags of a packet got lost, so its movement bytes come first
+        assert_eq!(
+            receive_all(&mut mouse, &[0x05, 0x03, 0x08, 0x02, 0x00]),
+            [Motion { dx: 2, dy: 0 }]
+        );
+    }
+}
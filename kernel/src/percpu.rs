@@ -0,0 +1,134 @@
+//! Storage with a separate value for every CPU. GS base points at the data of
+//! the CPU it is running on, which holds the number `cpu_id` returns, and
+//! `per_cpu!` statics keep one value per CPU number.
+
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+/// CPUs the kernel can run on, the rest are left off
+pub const MAX_CPUS: usize = 32;
+
+/// What GS base points at
+#[repr(C)]
+struct CpuLocal {
+    /// Read through GS by `cpu_id`, so it has to stay the first field
+    id: AtomicUsize,
+    apic_id: AtomicU32,
+}
+
+impl CpuLocal {
+    const fn new() -> Self {
+        Self {
+            id: AtomicUsize::new(0),
+            apic_id: AtomicU32::new(0),
+        }
+    }
+}
+
+static CPU_LOCALS: [CpuLocal; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: CpuLocal = CpuLocal::new();
+    [NEW; MAX_CPUS]
+};
+
+/// Points GS base of the current CPU at the data of CPU number `id`. Every
+/// CPU has to do this before using `cpu_id` or `per_cpu!` statics, the
+/// bootstrap processor takes number 0.
+pub fn init(id: usize) {
+    assert!(id < MAX_CPUS, "CPU {} is past MAX_CPUS", id);
+
+    let local = &CPU_LOCALS[id];
+    local.id.store(id, Ordering::Relaxed);
+    local.apic_id.store(initial_apic_id(), Ordering::Relaxed);
+
+    GsBase::write(VirtAddr::from_ptr(local));
+}
+
+/// Local APIC id the CPU came out of reset with (`CPUID.01h:EBX[31:24]`)
+fn initial_apic_id() -> u32 {
+    unsafe { __cpuid(1) }.ebx >> 24
+}
+
+/// Number of the CPU this runs on, below `smp::num_cpus`
+pub fn cpu_id() -> usize {
+    // GS base is still zero before `init`, where this would read address 0
+    debug_assert!(
+        !GsBase::read().is_null(),
+        "percpu::init has not run on this CPU"
+    );
+
+    let id;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) id,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+
+    id
+}
+
+/// Local APIC id of the CPU this runs on
+pub fn apic_id() -> u32 {
+    CPU_LOCALS[cpu_id()].apic_id.load(Ordering::Relaxed)
+}
+
+/// Holds a value for every CPU, declared with `per_cpu!`.
+///
+/// The value of the current CPU is shared with the interrupt handlers running
+/// on it, so it has to cope with being used from them.
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+// Each CPU only reaches its own value, except through `get_for`
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        Self { values }
+    }
+
+    /// The value of the current CPU
+    pub fn get(&self) -> &T {
+        &self.values[cpu_id()]
+    }
+
+    /// The value of CPU `cpu`, which that CPU may be using at the same time
+    pub fn get_for(&self, cpu: usize) -> &T
+    where
+        T: Sync,
+    {
+        &self.values[cpu]
+    }
+}
+
+/// Declares statics with a separate value for every CPU, each starting out as
+/// the initializer, which has to be a constant.
+///
+/// ```ignore
+/// per_cpu! {
+///     static TICKS: AtomicU64 = AtomicU64::new(0);
+/// }
+///
+/// TICKS.get().fetch_add(1, Ordering::Relaxed);
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> = {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: $ty = $init;
+                $crate::percpu::PerCpu::new([INIT; $crate::percpu::MAX_CPUS])
+            };
+        )*
+    };
+}
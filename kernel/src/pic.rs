@@ -0,0 +1,182 @@
+//! Driver for the two cascaded legacy 8259 programmable interrupt controllers.
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// First vector the master PIC delivers on, right after the exceptions
+pub const PIC_1_OFFSET: u8 = 32;
+/// First vector the slave PIC delivers on
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// The slave PIC is cascaded into this line of the master
+const CASCADE_IRQ: u8 = 2;
+
+const CMD_INIT: u8 = 0x11;
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_ISR: u8 = 0x0b;
+const MODE_8086: u8 = 0x01;
+
+static PICS: Mutex<ChainedPics> = Mutex::new(ChainedPics {
+    master: Pic::new(0x20, 0x21),
+    slave: Pic::new(0xa0, 0xa1),
+});
+
+struct Pic {
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Pic {
+    const fn new(command: u16, data: u16) -> Self {
+        Self {
+            command: Port::new(command),
+            data: Port::new(data),
+        }
+    }
+
+    unsafe fn in_service(&mut self) -> u8 {
+        self.command.write(CMD_READ_ISR);
+        self.command.read()
+    }
+
+    unsafe fn end_of_interrupt(&mut self) {
+        self.command.write(CMD_END_OF_INTERRUPT);
+    }
+}
+
+struct ChainedPics {
+    master: Pic,
+    slave: Pic,
+}
+
+impl ChainedPics {
+    /// The PIC serving `irq` and the bit of the line on it
+    fn line(&mut self, irq: u8) -> (&mut Pic, u8) {
+        assert!(irq < 16, "IRQ {} does not exist", irq);
+
+        if irq < 8 {
+            (&mut self.master, 1 << irq)
+        } else {
+            (&mut self.slave, 1 << (irq - 8))
+        }
+    }
+}
+
+/// Writes to an unused port, giving the PICs time to handle a command on
+/// machines where they are slow
+unsafe fn io_wait() {
+    Port::<u8>::new(0x80).write(0);
+}
+
+/// Remaps the PICs to `PIC_1_OFFSET` and `PIC_2_OFFSET`, so IRQs do not come
+/// in on exception vectors, and masks every line except the cascade.
+pub fn init() {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let ChainedPics { master, slave } = &mut *pics;
+
+        unsafe {
+            master.command.write(CMD_INIT);
+            io_wait();
+            slave.command.write(CMD_INIT);
+            io_wait();
+
+            master.data.write(PIC_1_OFFSET);
+            io_wait();
+            slave.data.write(PIC_2_OFFSET);
+            io_wait();
+
+            // Tell the master where the slave is and the slave its identity
+            master.data.write(1 << CASCADE_IRQ);
+            io_wait();
+            slave.data.write(CASCADE_IRQ);
+            io_wait();
+
+            master.data.write(MODE_8086);
+            io_wait();
+            slave.data.write(MODE_8086);
+            io_wait();
+
+            master.data.write(!(1 << CASCADE_IRQ));
+            slave.data.write(0xff);
+        }
+    });
+}
+
+/// Lets `irq` through to the CPU
+pub fn unmask(irq: u8) {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let (pic, bit) = pics.line(irq);
+
+        unsafe {
+            let mask = pic.data.read();
+            pic.data.write(mask & !bit);
+        }
+    });
+}
+
+/// Stops `irq` from reaching the CPU
+pub fn mask(irq: u8) {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let (pic, bit) = pics.line(irq);
+
+        unsafe {
+            let mask = pic.data.read();
+            pic.data.write(mask | bit);
+        }
+    });
+}
+
+/// Masks every line, for when the APICs take over
+pub fn disable() {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+
+        unsafe {
+            pics.master.data.write(0xff);
+            pics.slave.data.write(0xff);
+        }
+    });
+}
+
+/// Whether an interrupt on `irq` is spurious.
+///
+/// The PICs raise IRQ 7 or 15 when a line drops before the CPU acknowledges
+/// it; in that case the line is not marked in service. A spurious IRQ 15 still
+/// needs an EOI on the master, which this sends.
+pub fn is_spurious(irq: u8) -> bool {
+    if irq != 7 && irq != 15 {
+        return false;
+    }
+
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let (pic, bit) = pics.line(irq);
+
+        if unsafe { pic.in_service() } & bit != 0 {
+            return false;
+        }
+
+        if irq == 15 {
+            unsafe { pics.master.end_of_interrupt() };
+        }
+
+        true
+    })
+}
+
+/// Acknowledges `irq`, so the PICs deliver the next interrupt
+pub fn end_of_interrupt(irq: u8) {
+    without_interrupts(|| {
+        let mut pics = PICS.lock();
+
+        unsafe {
+            if irq >= 8 {
+                pics.slave.end_of_interrupt();
+            }
+            pics.master.end_of_interrupt();
+        }
+    });
+}
@@ -0,0 +1,341 @@
+//! Driver for the 8042 PS/2 controller the keyboard and mouse hang off.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::time::{Duration, Instant};
+
+const DATA: u16 = 0x60;
+/// Status register when read, command register when written
+const STATUS_COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the second port
+const STATUS_SECOND_PORT_DATA: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_SECOND: u8 = 0xa7;
+const CMD_ENABLE_SECOND: u8 = 0xa8;
+const CMD_TEST_SECOND: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_FIRST: u8 = 0xab;
+const CMD_DISABLE_FIRST: u8 = 0xad;
+const CMD_ENABLE_FIRST: u8 = 0xae;
+const CMD_WRITE_SECOND: u8 = 0xd4;
+
+const CONFIG_FIRST_INTERRUPT: u8 = 1 << 0;
+const CONFIG_SECOND_INTERRUPT: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// Replies of the devices
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+pub const DEVICE_SELF_TEST_PASSED: u8 = 0xaa;
+
+pub const DEVICE_RESET: u8 = 0xff;
+
+/// How long the controller and devices get to answer
+const TIMEOUT: Duration = Duration::from_millis(50);
+/// Devices take a lot longer to answer a reset
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often a command the device asks to resend is sent
+const RETRIES: usize = 3;
+
+/// The `Ports` `init` found as `CONTROLLER_*` bits, read without a lock so
+/// interrupt handlers can check them while a driver is sending commands
+static CONTROLLER: AtomicU8 = AtomicU8::new(0);
+
+const CONTROLLER_INITIALIZED: u8 = 1 << 0;
+const CONTROLLER_FIRST: u8 = 1 << 1;
+const CONTROLLER_SECOND: u8 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    /// Where the keyboard is connected
+    First,
+    /// Where the mouse is connected
+    Second,
+}
+
+/// The ports that passed their interface test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+    pub first: bool,
+    pub second: bool,
+}
+
+impl Ports {
+    pub fn has(&self, port: Ps2Port) -> bool {
+        match port {
+            Ps2Port::First => self.first,
+            Ps2Port::Second => self.second,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or device did not answer in time
+    Timeout,
+    /// The controller failed its self test with this reply
+    SelfTestFailed(u8),
+    /// No port passed its interface test
+    NoPorts,
+    /// The port is not there or was not initialized
+    NoDevice(Ps2Port),
+    /// The device kept asking for the command again, or answered something
+    /// else than an acknowledgement
+    UnexpectedReply(u8),
+}
+
+unsafe fn status() -> u8 {
+    Port::<u8>::new(STATUS_COMMAND).read()
+}
+
+fn wait_for(timeout: Duration, ready: impl Fn(u8) -> bool) -> Result<(), Ps2Error> {
+    let deadline = Instant::now() + timeout;
+
+    while !ready(unsafe { status() }) {
+        if Instant::now() >= deadline {
+            return Err(Ps2Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+fn write_command(command: u8) -> Result<(), Ps2Error> {
+    wait_for(TIMEOUT, |status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(STATUS_COMMAND).write(command) };
+
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Ps2Error> {
+    wait_for(TIMEOUT, |status| status & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA).write(data) };
+
+    Ok(())
+}
+
+/// Waits for a byte from the controller or a device
+pub fn read_data() -> Result<u8, Ps2Error> {
+    read_data_timeout(TIMEOUT)
+}
+
+fn read_data_timeout(timeout: Duration) -> Result<u8, Ps2Error> {
+    wait_for(timeout, |status| status & STATUS_OUTPUT_FULL != 0)?;
+
+    Ok(unsafe { Port::<u8>::new(DATA).read() })
+}
+
+/// The byte waiting in the output buffer if it came from the device on
+/// `port`, for interrupt handlers
+pub fn try_read_data(port: Ps2Port) -> Option<u8> {
+    unsafe {
+        let status = status();
+        let from_second = status & STATUS_SECOND_PORT_DATA != 0;
+        if status & STATUS_OUTPUT_FULL == 0 || from_second != (port == Ps2Port::Second) {
+            return None;
+        }
+
+        Some(Port::<u8>::new(DATA).read())
+    }
+}
+
+fn flush_output() {
+    unsafe {
+        while status() & STATUS_OUTPUT_FULL != 0 {
+            Port::<u8>::new(DATA).read();
+        }
+    }
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    write_command(CMD_READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Self tests the controller and finds the ports with something connected.
+/// Leaves the ports enabled with their interrupts and scancode translation
+/// off. Needs the clock running for the timeouts.
+pub fn init() -> Result<Ports, Ps2Error> {
+    without_interrupts(|| {
+        write_command(CMD_DISABLE_FIRST)?;
+        write_command(CMD_DISABLE_SECOND)?;
+        flush_output();
+
+        let config = read_config()?
+            & !(CONFIG_FIRST_INTERRUPT | CONFIG_SECOND_INTERRUPT | CONFIG_TRANSLATION);
+        write_config(config)?;
+
+        write_command(CMD_SELF_TEST)?;
+        match read_data()? {
+            SELF_TEST_PASSED => {}
+            reply => return Err(Ps2Error::SelfTestFailed(reply)),
+        }
+        // The self test can reset the controller
+        write_config(config)?;
+
+        // Enabling the second port clears its clock disable bit only on
+        // controllers that have one
+        write_command(CMD_ENABLE_SECOND)?;
+        let dual_channel = read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        write_command(CMD_DISABLE_SECOND)?;
+
+        write_command(CMD_TEST_FIRST)?;
+        let first = read_data()? == PORT_TEST_PASSED;
+
+        let second = dual_channel && {
+            write_command(CMD_TEST_SECOND)?;
+            read_data()? == PORT_TEST_PASSED
+        };
+
+        if !first && !second {
+            return Err(Ps2Error::NoPorts);
+        }
+
+        if first {
+            write_command(CMD_ENABLE_FIRST)?;
+        }
+        if second {
+            write_command(CMD_ENABLE_SECOND)?;
+        }
+
+        let ports = Ports { first, second };
+        let mut bits = CONTROLLER_INITIALIZED;
+        if first {
+            bits |= CONTROLLER_FIRST;
+        }
+        if second {
+            bits |= CONTROLLER_SECOND;
+        }
+        CONTROLLER.store(bits, Ordering::Release);
+
+        Ok(ports)
+    })
+}
+
+/// The ports `init` found
+pub fn ports() -> Option<Ports> {
+    let bits = CONTROLLER.load(Ordering::Acquire);
+    if bits & CONTROLLER_INITIALIZED == 0 {
+        return None;
+    }
+
+    Some(Ports {
+        first: bits & CONTROLLER_FIRST != 0,
+        second: bits & CONTROLLER_SECOND != 0,
+    })
+}
+
+fn check_port(port: Ps2Port) -> Result<(), Ps2Error> {
+    match ports() {
+        Some(ports) if ports.has(port) => Ok(()),
+        _ => Err(Ps2Error::NoDevice(port)),
+    }
+}
+
+/// Sends a byte to the device on `port` without waiting for its reply
+pub fn write(port: Ps2Port, data: u8) -> Result<(), Ps2Error> {
+    check_port(port)?;
+
+    if port == Ps2Port::Second {
+        write_command(CMD_WRITE_SECOND)?;
+    }
+    write_data(data)
+}
+
+/// Sends a byte to the device on the first port if the controller can take it
+/// right away, for interrupt handlers. Returns whether it was sent.
+pub fn try_write_first(data: u8) -> Result<bool, Ps2Error> {
+    check_port(Ps2Port::First)?;
+
+    unsafe {
+        if status() & STATUS_INPUT_FULL != 0 {
+            return Ok(false);
+        }
+        Port::<u8>::new(DATA).write(data);
+    }
+
+    Ok(true)
+}
+
+/// Sends a byte to the device on `port` and waits for it to be acknowledged,
+/// sending it again when the device asks to
+pub fn send(port: Ps2Port, data: u8) -> Result<(), Ps2Error> {
+    for _ in 0..RETRIES {
+        write(port, data)?;
+
+        match read_data()? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            reply => return Err(Ps2Error::UnexpectedReply(reply)),
+        }
+    }
+
+    Err(Ps2Error::UnexpectedReply(RESEND))
+}
+
+/// Resets the device on `port`, waiting for it to pass its self test
+pub fn reset_device(port: Ps2Port) -> Result<(), Ps2Error> {
+    send(port, DEVICE_RESET)?;
+
+    match read_data_timeout(RESET_TIMEOUT)? {
+        DEVICE_SELF_TEST_PASSED => Ok(()),
+        reply => Err(Ps2Error::UnexpectedReply(reply)),
+    }
+}
+
+/// Runs `f` with the device on `port` unable to send anything, so the replies
+/// `f` reads come from the other device. The port is enabled again even if `f`
+/// fails.
+pub fn with_port_disabled<R>(
+    port: Ps2Port,
+    f: impl FnOnce() -> Result<R, Ps2Error>,
+) -> Result<R, Ps2Error> {
+    if check_port(port).is_err() {
+        return f();
+    }
+
+    let (disable, enable) = match port {
+        Ps2Port::First => (CMD_DISABLE_FIRST, CMD_ENABLE_FIRST),
+        Ps2Port::Second => (CMD_DISABLE_SECOND, CMD_ENABLE_SECOND),
+    };
+
+    write_command(disable)?;
+    let result = f();
+    let enabled = write_command(enable);
+
+    let value = result?;
+    enabled.map(|()| value)
+}
+
+/// Lets the device on `port` raise its IRQ, 1 for the first port and 12 for
+/// the second
+pub fn enable_interrupt(port: Ps2Port) -> Result<(), Ps2Error> {
+    check_port(port)?;
+
+    without_interrupts(|| {
+        let bit = match port {
+            Ps2Port::First => CONFIG_FIRST_INTERRUPT,
+            Ps2Port::Second => CONFIG_SECOND_INTERRUPT,
+        };
+
+        let config = read_config()?;
+        write_config(config | bit)
+    })
+}
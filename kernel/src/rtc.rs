@@ -0,0 +1,585 @@
+//! Driver for the CMOS real-time clock, the source of calendar time.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use acpi::{fadt::Fadt, sdt::Signature, AcpiTables};
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{
+    acpi::Handler,
+    irq::{self, HandlerId},
+    time::{Duration, Instant},
+};
+
+/// The IRQ the RTC raises its periodic interrupt on
+pub const RTC_IRQ: u8 = 8;
+
+const INDEX: u16 = 0x70;
+const DATA: u16 = 0x71;
+
+/// Set in the index to keep NMIs disabled while the CMOS is accessed, the index
+/// is written again without it afterwards
+const NMI_DISABLE: u8 = 1 << 7;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE: u8 = 0x0f;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_B_SET: u8 = 1 << 7;
+const STATUS_C_PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+const HOUR_PM: u8 = 1 << 7;
+
+/// The RTC's time base, the periodic interrupt divides it
+const BASE_FREQUENCY: u32 = 32768;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+lazy_static! {
+    static ref RTC: Mutex<Rtc> = Mutex::new(Rtc {
+        century_register: None,
+    });
+}
+
+/// Unix time in nanoseconds at the start of the monotonic clock
+static WALL_CLOCK_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+static PERIODIC_HANDLER: Mutex<Option<HandlerId>> = Mutex::new(None);
+static PERIODIC_RUNNING: AtomicBool = AtomicBool::new(false);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum RtcError {
+    /// Not a valid calendar date, before 1970, after 2069 without a century
+    /// register, or past what the wall clock holds in 2554
+    InvalidDate,
+    /// The periodic interrupt runs at powers of two from 2 to 8192 Hz
+    InvalidFrequency,
+}
+
+/// A calendar date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, zero for earlier dates
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        if days < 0 {
+            return 0;
+        }
+
+        days as u64 * 86400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+        let seconds = timestamp % 86400;
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date `days` after 1970-01-01, as year, month and day
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// The 24 hour clock hour of a 12 hour clock one, which runs from 12 AM to
+/// 11 PM
+fn hour_from_12_hour(hour: u8, pm: bool) -> u8 {
+    match (hour, pm) {
+        (12, false) => 0,
+        (12, true) => 12,
+        (hour, true) => hour + 12,
+        (hour, false) => hour,
+    }
+}
+
+/// The 12 hour clock hour of a 24 hour clock one, and whether it is PM
+fn hour_to_12_hour(hour: u8) -> (u8, bool) {
+    match hour {
+        0 => (12, false),
+        1..=11 => (hour, false),
+        12 => (12, true),
+        hour => (hour - 12, true),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// The clock registers as the RTC stores them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+struct Rtc {
+    /// CMOS register holding the century, from the FADT
+    century_register: Option<u8>,
+}
+
+impl Rtc {
+    unsafe fn read(&mut self, register: u8) -> u8 {
+        Port::<u8>::new(INDEX).write(NMI_DISABLE | register);
+        let value = Port::<u8>::new(DATA).read();
+        Port::<u8>::new(INDEX).write(register);
+
+        value
+    }
+
+    unsafe fn write(&mut self, register: u8, value: u8) {
+        Port::<u8>::new(INDEX).write(NMI_DISABLE | register);
+        Port::<u8>::new(DATA).write(value);
+        Port::<u8>::new(INDEX).write(register);
+    }
+
+    unsafe fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+    }
+
+    unsafe fn read_raw(&mut self) -> RawTime {
+        while self.update_in_progress() {
+            core::hint::spin_loop();
+        }
+
+        RawTime {
+            second: self.read(REG_SECONDS),
+            minute: self.read(REG_MINUTES),
+            hour: self.read(REG_HOURS),
+            day: self.read(REG_DAY),
+            month: self.read(REG_MONTH),
+            year: self.read(REG_YEAR),
+            century: self
+                .century_register
+                .map_or(0, |register| self.read(register)),
+        }
+    }
+
+    fn read_time(&mut self) -> DateTime {
+        unsafe {
+            // An update can start right after the flag was checked, so read
+            // until two reads agree
+            let mut raw = self.read_raw();
+            loop {
+                let again = self.read_raw();
+                if again == raw {
+                    break;
+                }
+                raw = again;
+            }
+
+            let status_b = self.read(REG_STATUS_B);
+            let binary = status_b & STATUS_B_BINARY != 0;
+            let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+            let pm = raw.hour & HOUR_PM != 0;
+            let mut hour = decode(raw.hour & !HOUR_PM);
+            if status_b & STATUS_B_24_HOUR == 0 {
+                hour = hour_from_12_hour(hour, pm);
+            }
+
+            let year = decode(raw.year) as u16;
+            let year = match self.century_register {
+                Some(_) => decode(raw.century) as u16 * 100 + year,
+                None if year < 70 => 2000 + year,
+                None => 1900 + year,
+            };
+
+            DateTime {
+                year,
+                month: decode(raw.month),
+                day: decode(raw.day),
+                hour,
+                minute: decode(raw.minute),
+                second: decode(raw.second),
+            }
+        }
+    }
+
+    fn write_time(&mut self, time: &DateTime) {
+        unsafe {
+            let status_b = self.read(REG_STATUS_B);
+            let binary = status_b & STATUS_B_BINARY != 0;
+            let encode = |value: u8| if binary { value } else { to_bcd(value) };
+
+            let hour = if status_b & STATUS_B_24_HOUR != 0 {
+                encode(time.hour)
+            } else {
+                match hour_to_12_hour(time.hour) {
+                    (hour, true) => encode(hour) | HOUR_PM,
+                    (hour, false) => encode(hour),
+                }
+            };
+
+            // Stop updates while the registers are written
+            self.write(REG_STATUS_B, status_b | STATUS_B_SET);
+
+            self.write(REG_SECONDS, encode(time.second));
+            self.write(REG_MINUTES, encode(time.minute));
+            self.write(REG_HOURS, hour);
+            self.write(REG_DAY, encode(time.day));
+            self.write(REG_MONTH, encode(time.month));
+            self.write(REG_YEAR, encode((time.year % 100) as u8));
+            if let Some(register) = self.century_register {
+                self.write(register, encode((time.year / 100) as u8));
+            }
+
+            self.write(REG_STATUS_B, status_b & !STATUS_B_SET);
+        }
+    }
+}
+
+/// Finds the century register in the FADT and sets the wall clock from the
+/// RTC. Needs the monotonic clock running.
+pub fn init(tables: &AcpiTables<Handler>) {
+    let century_register = unsafe { tables.get_sdt::<Fadt>(Signature::FADT) }
+        .ok()
+        .flatten()
+        .map(|fadt| fadt.century)
+        .filter(|&register| register != 0);
+
+    let time = without_interrupts(|| {
+        let mut rtc = RTC.lock();
+        rtc.century_register = century_register;
+        rtc.read_time()
+    });
+
+    // An RTC set past 2554 leaves the wall clock at 1970
+    if let Some(nanos) = unix_nanos(&time) {
+        set_wall_clock(nanos);
+    }
+}
+
+/// Reads the date and time from the RTC
+pub fn read() -> DateTime {
+    without_interrupts(|| RTC.lock().read_time())
+}
+
+/// Sets the RTC, and the wall clock, to `time`
+pub fn set(time: &DateTime) -> Result<(), RtcError> {
+    let has_century = without_interrupts(|| RTC.lock().century_register.is_some());
+    // The century register holds two BCD digits
+    let last_year = if has_century { 9999 } else { 2069 };
+    if !time.is_valid() || !(1970..=last_year).contains(&time.year) {
+        return Err(RtcError::InvalidDate);
+    }
+    let nanos = unix_nanos(time).ok_or(RtcError::InvalidDate)?;
+
+    without_interrupts(|| RTC.lock().write_time(time));
+    set_wall_clock(nanos);
+
+    Ok(())
+}
+
+/// Nanoseconds since 1970 at `time`, `None` past what a `u64` holds in 2554
+fn unix_nanos(time: &DateTime) -> Option<u64> {
+    time.unix_timestamp().checked_mul(NANOS_PER_SECOND)
+}
+
+/// Makes the wall clock read `unix_nanos` now
+fn set_wall_clock(unix_nanos: u64) {
+    let since_start = Instant::now().since_start().as_nanos() as u64;
+    let offset = unix_nanos.saturating_sub(since_start);
+
+    WALL_CLOCK_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// How far the monotonic clock is behind Unix time
+pub fn wall_clock_offset() -> Duration {
+    Duration::from_nanos(WALL_CLOCK_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Calendar time at `instant`
+pub fn to_date_time(instant: Instant) -> DateTime {
+    DateTime::from_unix_timestamp((wall_clock_offset() + instant.since_start()).as_secs())
+}
+
+/// The current calendar time, from the monotonic clock rather than the RTC
+pub fn now() -> DateTime {
+    to_date_time(Instant::now())
+}
+
+/// Starts the periodic interrupt at `frequency` Hz, a power of two from 2 to
+/// 8192. Each interrupt counts as a tick of the clock.
+pub fn start_periodic_interrupt(frequency: u32) -> Result<(), RtcError> {
+    if !frequency.is_power_of_two() || !(2..=8192).contains(&frequency) {
+        return Err(RtcError::InvalidFrequency);
+    }
+
+    // The frequency is the base frequency shifted right by rate - 1
+    let rate = (BASE_FREQUENCY / frequency).trailing_zeros() as u8 + 1;
+
+    stop_periodic_interrupt();
+    let handler = irq::register_handler(RTC_IRQ, periodic_interrupt);
+
+    without_interrupts(|| {
+        let mut rtc = RTC.lock();
+
+        unsafe {
+            let status_a = rtc.read(REG_STATUS_A);
+            rtc.write(REG_STATUS_A, (status_a & !STATUS_A_RATE) | rate);
+
+            let status_b = rtc.read(REG_STATUS_B);
+            rtc.write(REG_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+
+            // A pending interrupt would hold back the next one
+            rtc.read(REG_STATUS_C);
+        }
+
+        *PERIODIC_HANDLER.lock() = Some(handler);
+        PERIODIC_RUNNING.store(true, Ordering::Release);
+    });
+
+    Ok(())
+}
+
+pub fn stop_periodic_interrupt() {
+    let handler = without_interrupts(|| {
+        let mut rtc = RTC.lock();
+
+        unsafe {
+            let status_b = rtc.read(REG_STATUS_B);
+            rtc.write(REG_STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+        }
+
+        PERIODIC_RUNNING.store(false, Ordering::Release);
+        PERIODIC_HANDLER.lock().take()
+    });
+
+    if let Some(handler) = handler {
+        irq::unregister_handler(handler);
+    }
+}
+
+/// Whether the periodic interrupt is running
+pub fn periodic_interrupt_running() -> bool {
+    PERIODIC_RUNNING.load(Ordering::Acquire)
+}
+
+/// Number of periodic interrupts so far
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+fn periodic_interrupt(_irq: u8) {
+    // The RTC raises no further interrupts until status C is read
+    let flags = unsafe { RTC.lock().read(REG_STATUS_C) };
+
+    if flags & STATUS_C_PERIODIC_INTERRUPT != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+        crate::time::tick();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: u16, month: u8, day: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour: 0,
+            minute: 0,
+            second: 0,
+        }
+    }
+
+    #[test]
+    fn civil_days_round_trip() {
+        // (year, month, day, days since 1970-01-01)
+        let table = [
+            (1970, 1, 1, 0),
+            (1969, 12, 31, -1),
+            (1972, 2, 29, 789),
+            (1972, 3, 1, 790),
+            (2000, 2, 29, 11016),
+            (2000, 3, 1, 11017),
+            (2100, 3, 1, 47541),
+            (2024, 12, 31, 20088),
+        ];
+
+        for (year, month, day, days) in table {
+            assert_eq!(
+                days_from_civil(year, month, day),
+                days,
+                "{}-{}-{}",
+                year,
+                month,
+                day
+            );
+            assert_eq!(civil_from_days(days), (year, month, day));
+        }
+
+        for days in (-1000..100_000).step_by(7) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn leap_years() {
+        assert!(is_leap_year(2000));
+        assert!(is_leap_year(2024));
+        assert!(!is_leap_year(1900));
+        assert!(!is_leap_year(2100));
+        assert!(!is_leap_year(2023));
+
+        assert!(date(2024, 2, 29).is_valid());
+        assert!(!date(2023, 2, 29).is_valid());
+        assert!(!date(2100, 2, 29).is_valid());
+        assert!(!date(2024, 4, 31).is_valid());
+    }
+
+    #[test]
+    fn unix_timestamps() {
+        assert_eq!(date(1970, 1, 1).unix_timestamp(), 0);
+        assert_eq!(DateTime::from_unix_timestamp(0), date(1970, 1, 1));
+        assert_eq!(date(1969, 12, 31).unix_timestamp(), 0);
+
+        let time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 23,
+            minute: 59,
+            second: 59,
+        };
+        assert_eq!(time.unix_timestamp(), 1_709_251_199);
+        assert_eq!(DateTime::from_unix_timestamp(1_709_251_199), time);
+
+        assert!(unix_nanos(&date(2554, 1, 1)).is_some());
+        assert_eq!(unix_nanos(&date(2555, 1, 1)), None);
+    }
+
+    #[test]
+    fn twelve_hour_clock() {
+        // (24 hour clock, 12 hour clock, PM)
+        let table = [
+            (0, 12, false),
+            (1, 1, false),
+            (11, 11, false),
+            (12, 12, true),
+            (13, 1, true),
+            (23, 11, true),
+        ];
+
+        for (hour, hour_12, pm) in table {
+            assert_eq!(hour_to_12_hour(hour), (hour_12, pm));
+            assert_eq!(hour_from_12_hour(hour_12, pm), hour);
+        }
+    }
+
+    #[test]
+    fn bcd() {
+        assert_eq!(from_bcd(0x59), 59);
+        assert_eq!(to_bcd(59), 0x59);
+        assert_eq!(from_bcd(0x00), 0);
+        assert_eq!(to_bcd(99), 0x99);
+
+        for value in 0..100 {
+            assert_eq!(from_bcd(to_bcd(value)), value);
+        }
+    }
+}
@@ -1,23 +1,81 @@
-use spin::{Lazy, Mutex};
-use uart_16550::SerialPort;
+use core::fmt;
 
-pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
-    let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-    serial_port.init();
-    Mutex::new(serial_port)
-});
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::instructions::interrupts;
+
+use crate::uart::{LineConfig, Uart, UartError, COM1};
+
+static COM1_INIT: Once = Once::new();
+
+/// Keeps concurrent prints from interleaving
+static PRINT_LOCK: Mutex<()> = Mutex::new(());
+
+/// COM1, which the kernel prints to, set up on first use
+pub fn port() -> &'static Uart {
+    COM1_INIT.call_once(|| {
+        // Without a UART the output is dropped
+        let _ = COM1.init(LineConfig::default());
+    });
+
+    &COM1
+}
+
+/// Switches COM1 to interrupt driven reads and writes, once the IRQs are set
+/// up
+pub fn enable_interrupts() -> Result<(), UartError> {
+    port().enable_interrupts()
+}
+
+/// The oldest byte received on COM1
+pub fn read_byte() -> Option<u8> {
+    port().read_byte()
+}
+
+/// Writes to COM1 without taking the print lock
+pub struct SerialWriter;
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        port().write(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Serial terminals expect a carriage return after every newline
+pub struct CrLfWriter<'a, W: fmt::Write>(pub &'a mut W);
+
+impl<W: fmt::Write> fmt::Write for CrLfWriter<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (index, line) in s.split('\n').enumerate() {
+            if index > 0 {
+                self.0.write_str("\n\r")?;
+            }
+            self.0.write_str(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An interrupt handler printing while the code it interrupted holds the lock
+/// would deadlock, so with interrupts disabled the lock is only taken if free.
+/// Held around several writes to `SerialWriter` to keep them together.
+pub fn print_lock() -> Option<MutexGuard<'static, ()>> {
+    if interrupts::are_enabled() {
+        Some(PRINT_LOCK.lock())
+    } else {
+        PRINT_LOCK.try_lock()
+    }
+}
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed");
-    });
+    let _lock = print_lock();
+    SerialWriter
+        .write_fmt(args)
+        .expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
@@ -36,3 +94,12 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n\r"), $($arg)*));
 }
+
+/// Dumps every live heap allocation over serial, see
+/// `kernel_memory::debug_alloc`.
+#[cfg(feature = "debug-alloc")]
+pub fn dump_allocations() {
+    let _lock = print_lock();
+    kernel_memory::debug_alloc::dump_live_allocations(&mut SerialWriter)
+        .expect("Printing to serial failed");
+}
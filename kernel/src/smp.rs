@@ -0,0 +1,381 @@
+//! Startup of the application processors the MADT lists.
+//!
+//! Each AP is woken with INIT-SIPI-SIPI into a trampoline below 1 MiB, which
+//! switches from real mode straight to long mode on the page tables of the
+//! bootstrap processor and calls `ap_entry` on a stack of its own.
+//!
+//! There are no TLB shootdowns yet. Once up, an AP only halts and takes
+//! interrupts on its own stacks and tables, so it never uses lower half, heap
+//! growth or device mappings that another CPU could unmap or change under it.
+//! Running more on the APs needs a shootdown IPI first.
+
+use core::{
+    arch::global_asm,
+    ptr::addr_of,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use acpi::{platform::ProcessorState, AcpiError, AcpiTables};
+use kernel_memory::{
+    mmio::init_pat,
+    physical_memory_offset,
+    protection::{enable_nxe, Permissions},
+    stack::alloc_stack,
+    with_mapper_and_allocator,
+};
+use x86_64::{
+    instructions::{hlt, interrupts},
+    registers::control::{Cr0, Cr3, Cr4, Cr4Flags},
+    structures::paging::{Mapper, Page, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+pub use crate::percpu::cpu_id;
+use crate::{
+    acpi::Handler,
+    apic::{local_apic, IpiKind},
+    gdt, idt,
+    percpu::{self, MAX_CPUS},
+    time::{self, Duration, Instant},
+};
+
+/// Size of the kernel stack of an AP
+const AP_STACK_PAGES: u64 = 16;
+
+/// The startup IPI can only point at the first megabyte
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+
+/// Time an AP gets to leave the INIT state
+const INIT_DELAY: Duration = Duration::from_millis(10);
+/// Time an AP gets to reach `ap_entry` after the first startup IPI, before a
+/// second one is sent
+const FIRST_STARTUP_TIMEOUT: Duration = Duration::from_millis(1);
+const STARTUP_TIMEOUT: Duration = Duration::from_millis(100);
+/// Time an AP gets to set itself up once running
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// CPUs running the kernel, the bootstrap processor included
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+/// Set by an AP once it has left the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+// Copied to a page below 1 MiB, where the startup IPI starts the AP in real
+// mode with CS pointing at the page. Going to long mode needs PAE, EFER.LME
+// and paging enabled together with protected mode; the page is identity
+// mapped so execution continues after paging is on. The accessed bits of the
+// GDT entries are set so the CPU never writes to the read only page.
+//
+// The fields after the code are filled in by `Trampoline::prepare`, the ones
+// holding offsets into the trampoline get its physical address added.
+global_asm!(
+    r#"
+    .section .rodata.ap_trampoline, "a"
+    .p2align 4
+    .global ap_trampoline_start
+    .code16
+ap_trampoline_start:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+
+    movl (ap_trampoline_cr4 - ap_trampoline_start), %eax
+    movl %eax, %cr4
+    movl (ap_trampoline_cr3 - ap_trampoline_start), %eax
+    movl %eax, %cr3
+
+    # EFER.LME and EFER.NXE, the page tables use no-execute bits
+    movl $0xc0000080, %ecx
+    rdmsr
+    orl $0x900, %eax
+    wrmsr
+
+    lgdtl (ap_trampoline_gdtr - ap_trampoline_start)
+
+    movl (ap_trampoline_cr0 - ap_trampoline_start), %eax
+    movl %eax, %cr0
+
+    ljmpl *(ap_trampoline_long_jump - ap_trampoline_start)
+
+    .code64
+ap_trampoline_long_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    xorl %eax, %eax
+    movw %ax, %fs
+    movw %ax, %gs
+
+    movq ap_trampoline_stack(%rip), %rsp
+    movq ap_trampoline_argument(%rip), %rdi
+    # Ends the frame pointer chain
+    xorl %ebp, %ebp
+    callq *ap_trampoline_entry(%rip)
+    ud2
+
+    .p2align 3
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9b000000ffff
+    .quad 0x00cf93000000ffff
+ap_trampoline_gdtr:
+    .word ap_trampoline_gdtr - ap_trampoline_gdt - 1
+    .global ap_trampoline_gdt_base
+ap_trampoline_gdt_base:
+    .long ap_trampoline_gdt - ap_trampoline_start
+    .global ap_trampoline_long_jump
+ap_trampoline_long_jump:
+    .long ap_trampoline_long_mode - ap_trampoline_start
+    .word 0x08
+    .global ap_trampoline_cr0
+ap_trampoline_cr0:
+    .long 0
+    .global ap_trampoline_cr3
+ap_trampoline_cr3:
+    .long 0
+    .global ap_trampoline_cr4
+ap_trampoline_cr4:
+    .long 0
+    .p2align 3
+    .global ap_trampoline_stack
+ap_trampoline_stack:
+    .quad 0
+    .global ap_trampoline_entry
+ap_trampoline_entry:
+    .quad 0
+    .global ap_trampoline_argument
+ap_trampoline_argument:
+    .quad 0
+    .global ap_trampoline_end
+ap_trampoline_end:
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_gdt_base: u8;
+    static ap_trampoline_long_jump: u8;
+    static ap_trampoline_cr0: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_cr4: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+    static ap_trampoline_argument: u8;
+    static ap_trampoline_end: u8;
+}
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// The MADT could not be parsed
+    Acpi(AcpiError),
+    /// The MADT lists no processors
+    NoProcessorInfo,
+    /// `apic::init` has not switched to the APICs
+    NoApic,
+    /// No free page below 1 MiB for the trampoline
+    NoTrampolineMemory,
+}
+
+/// The trampoline copied to its page, which stays allocated and identity
+/// mapped for good. An AP that starts late may still run it, and unmapping it
+/// would need a TLB shootdown on the APs that went through it.
+struct Trampoline {
+    frame: PhysFrame,
+}
+
+impl Trampoline {
+    fn new() -> Result<Self, SmpError> {
+        let size = unsafe { addr_of!(ap_trampoline_end) as usize - trampoline_start() as usize };
+        assert!(size <= 0x1000, "AP trampoline does not fit a page");
+
+        let frame = with_mapper_and_allocator(|mapper, frame_allocator| {
+            let frame = frame_allocator
+                .allocate_contiguous_below(PhysAddr::new(TRAMPOLINE_LIMIT), 1, 1)?
+                .start;
+            let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+
+            unsafe {
+                mapper
+                    .map_to(
+                        page,
+                        frame,
+                        Permissions::ReadExecute.flags(),
+                        frame_allocator,
+                    )
+                    .expect("failed to identity map the AP trampoline")
+                    .flush();
+            }
+
+            Some(frame)
+        })
+        .ok_or(SmpError::NoTrampolineMemory)?;
+        let trampoline = Self { frame };
+
+        unsafe { core::ptr::copy_nonoverlapping(trampoline_start(), trampoline.ptr(0), size) };
+        trampoline.relocate(unsafe { addr_of!(ap_trampoline_gdt_base) });
+        trampoline.relocate(unsafe { addr_of!(ap_trampoline_long_jump) });
+
+        // PCIDs only exist in long mode, enabling them before is a fault
+        let cr4 = Cr4::read() - Cr4Flags::PCID;
+        let cr3 = Cr3::read().0.start_address().as_u64();
+        assert!(cr3 < 1 << 32, "kernel page table is above 4 GiB");
+
+        unsafe {
+            trampoline.write(addr_of!(ap_trampoline_cr0), Cr0::read_raw() as u32);
+            trampoline.write(addr_of!(ap_trampoline_cr3), cr3 as u32);
+            trampoline.write(addr_of!(ap_trampoline_cr4), cr4.bits() as u32);
+            trampoline.write(addr_of!(ap_trampoline_entry), ap_entry as *const () as u64);
+        }
+
+        Ok(trampoline)
+    }
+
+    /// Where the startup IPI has to point
+    fn page_number(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Pointer to `offset` in the copy, through the physical memory mapping
+    fn ptr(&self, offset: usize) -> *mut u8 {
+        (physical_memory_offset() + self.frame.start_address().as_u64() + offset as u64)
+            .as_mut_ptr()
+    }
+
+    /// Pointer to the copy of `field`
+    fn field(&self, field: *const u8) -> *mut u8 {
+        self.ptr(field as usize - trampoline_start() as usize)
+    }
+
+    fn write<T>(&self, field: *const u8, value: T) {
+        unsafe { (self.field(field) as *mut T).write_unaligned(value) }
+    }
+
+    /// Turns the offset in `field` into a physical address
+    fn relocate(&self, field: *const u8) {
+        let field = self.field(field) as *mut u32;
+        unsafe {
+            field.write_unaligned(
+                field.read_unaligned() + self.frame.start_address().as_u64() as u32,
+            )
+        };
+    }
+
+    /// Sets the stack and CPU number the next AP starts with
+    fn prepare(&self, stack_end: VirtAddr, cpu: usize) {
+        unsafe {
+            self.write(addr_of!(ap_trampoline_stack), stack_end.as_u64());
+            self.write(addr_of!(ap_trampoline_argument), cpu as u64);
+        }
+    }
+}
+
+fn trampoline_start() -> *const u8 {
+    unsafe { addr_of!(ap_trampoline_start) }
+}
+
+/// Starts every application processor the MADT lists as usable, one at a
+/// time, returning the number of CPUs running. Needs the APICs and the clock
+/// set up.
+pub fn init(tables: &AcpiTables<Handler>) -> Result<usize, SmpError> {
+    let processor_info = tables
+        .platform_info()
+        .map_err(SmpError::Acpi)?
+        .processor_info
+        .ok_or(SmpError::NoProcessorInfo)?;
+    let apic = local_apic().ok_or(SmpError::NoApic)?;
+
+    let trampoline = Trampoline::new()?;
+    let mut next_cpu = 1;
+
+    for processor in &processor_info.application_processors {
+        if !matches!(processor.state, ProcessorState::WaitingForSipi) {
+            continue;
+        }
+        if next_cpu == MAX_CPUS {
+            serial_println!("More than {} CPUs, leaving the rest off", MAX_CPUS);
+            break;
+        }
+
+        let stack = with_mapper_and_allocator(|mapper, frame_allocator| {
+            alloc_stack("AP kernel", AP_STACK_PAGES, mapper, frame_allocator)
+                .expect("failed to allocate AP stack")
+        });
+        trampoline.prepare(stack.end(), next_cpu);
+        AP_STARTED.store(false, Ordering::Release);
+
+        let online = ONLINE_CPUS.load(Ordering::Acquire);
+        let id = processor.local_apic_id;
+
+        apic.send_ipi(id, IpiKind::Init);
+        time::busy_wait(INIT_DELAY);
+        apic.send_ipi(id, IpiKind::Startup(trampoline.page_number()));
+        if !wait_for(FIRST_STARTUP_TIMEOUT, || AP_STARTED.load(Ordering::Acquire)) {
+            apic.send_ipi(id, IpiKind::Startup(trampoline.page_number()));
+        }
+
+        // An AP that turns up late still uses its number and stack, so neither
+        // is given out again
+        next_cpu += 1;
+        core::mem::forget(stack);
+
+        // The trampoline has to keep the parameters of an AP that may still
+        // turn up, so no other AP can be started with it
+        if !wait_for(STARTUP_TIMEOUT, || AP_STARTED.load(Ordering::Acquire)) {
+            serial_println!(
+                "CPU with APIC id {} did not start, leaving the rest off",
+                id
+            );
+            break;
+        }
+
+        if !wait_for(ONLINE_TIMEOUT, || {
+            ONLINE_CPUS.load(Ordering::Acquire) > online
+        }) {
+            serial_println!("CPU with APIC id {} did not come online", id);
+        }
+    }
+
+    Ok(num_cpus())
+}
+
+/// Waits up to `timeout` for `done`, returning whether it happened
+fn wait_for(timeout: Duration, done: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+
+    true
+}
+
+/// Number of CPUs running the kernel
+pub fn num_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::Acquire)
+}
+
+/// Where the trampoline leaves an AP, on its own stack with its CPU number
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    AP_STARTED.store(true, Ordering::Release);
+
+    percpu::init(cpu);
+    enable_nxe();
+    init_pat();
+    gdt::init();
+    idt::init();
+    if let Some(apic) = local_apic() {
+        apic.enable();
+    }
+
+    ONLINE_CPUS.fetch_add(1, Ordering::AcqRel);
+
+    interrupts::enable();
+    loop {
+        hlt();
+    }
+}
@@ -0,0 +1,329 @@
+//! Timekeeping: a monotonic clock read from the best clock source the
+//! machine has, and a tick from the local APIC timer.
+
+mod hpet;
+mod pit;
+mod tsc;
+
+use core::{
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+pub use core::time::Duration;
+
+use acpi::AcpiTables;
+use alloc::boxed::Box;
+use spin::Mutex;
+use x86_64::{
+    instructions::{hlt, interrupts},
+    structures::idt::InterruptStackFrame,
+};
+
+pub use self::{hpet::Hpet, pit::Pit, tsc::Tsc};
+use crate::{
+    acpi::Handler,
+    apic::{local_apic, TimerDivide},
+    irq, rtc,
+};
+
+/// Vector the local APIC timer interrupts on
+pub const TIMER_VECTOR: u8 = 0x30;
+
+/// The IRQ channel 0 of the PIT raises
+const PIT_IRQ: u8 = 0;
+
+/// How long calibrations measure against the reference clock
+const CALIBRATION_WINDOW: Duration = Duration::from_millis(10);
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// A free running counter to keep time with
+pub trait ClockSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The current counter value
+    fn read(&self) -> u64;
+
+    /// Counter increments per second
+    fn frequency(&self) -> u64;
+
+    /// The counter wraps to zero after this value
+    fn mask(&self) -> u64;
+}
+
+/// Extends the counter of a clock source past its wrap around
+struct Clock {
+    source: &'static dyn ClockSource,
+    last: u64,
+    ticks: u64,
+}
+
+impl Clock {
+    fn nanos(&mut self) -> u64 {
+        let now = self.source.read();
+
+        if self.source.mask() == u64::MAX {
+            // A counter that never wraps, like the TSC, can read slightly
+            // behind `last` on another CPU, which must not count as a wrap
+            self.ticks += now.saturating_sub(self.last);
+            self.last = self.last.max(now);
+        } else {
+            self.ticks += now.wrapping_sub(self.last) & self.source.mask();
+            self.last = now;
+        }
+
+        (self.ticks as u128 * NANOS_PER_SECOND / self.source.frequency() as u128) as u64
+    }
+}
+
+lazy_static! {
+    static ref CLOCK: Mutex<Option<Clock>> = Mutex::new(None);
+}
+
+/// Frequency of the local APIC timer with `TIMER_DIVIDE`, zero until calibrated
+static APIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+const TIMER_DIVIDE: TimerDivide = TimerDivide::By16;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_TICK: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the clock sources and picks the best as the clock: the TSC if it
+/// is invariant, otherwise the HPET, otherwise the PIT. The TSC and the local
+/// APIC timer are calibrated against the HPET, or the PIT without one.
+pub fn init(tables: &AcpiTables<Handler>) {
+    let (reference, on_pit) = match Hpet::init(tables) {
+        Some(hpet) => (leak(hpet), false),
+        None => (leak(Pit::init()), true),
+    };
+    let (source, on_pit) = match Tsc::init(reference) {
+        Some(tsc) => (leak(tsc), false),
+        None => (reference, on_pit),
+    };
+
+    interrupts::without_interrupts(|| {
+        *CLOCK.lock() = Some(Clock {
+            source,
+            last: source.read(),
+            ticks: 0,
+        })
+    });
+
+    // The PIT interrupts whenever its counter wraps, reading the clock then
+    // keeps it from missing a wrap, with or without a tick
+    if on_pit {
+        irq::register_handler(PIT_IRQ, |_| {
+            Instant::now();
+        });
+    }
+
+    if let Some(apic) = local_apic() {
+        apic.start_timer(TIMER_VECTOR, u32::MAX, TIMER_DIVIDE, false);
+        let frequency =
+            measure_frequency(reference, || (u32::MAX - apic.timer_current_count()) as u64);
+        apic.stop_timer();
+
+        APIC_TIMER_FREQUENCY.store(frequency, Ordering::Release);
+    }
+}
+
+/// Clock sources live for as long as the kernel runs
+fn leak(source: impl ClockSource + 'static) -> &'static dyn ClockSource {
+    Box::leak(Box::new(source))
+}
+
+/// Name of the clock source the clock runs on, once initialized
+pub fn clock_source_name() -> Option<&'static str> {
+    interrupts::without_interrupts(|| CLOCK.lock().as_ref().map(|clock| clock.source.name()))
+}
+
+/// Measures how fast `counter` counts, using `reference` for the time. The
+/// counter must not wrap during `CALIBRATION_WINDOW`.
+fn measure_frequency(reference: &dyn ClockSource, mut counter: impl FnMut() -> u64) -> u64 {
+    let window = reference.frequency() * CALIBRATION_WINDOW.as_micros() as u64 / 1_000_000;
+
+    interrupts::without_interrupts(|| {
+        let reference_start = reference.read();
+        let start = counter();
+
+        let mut reference_elapsed;
+        loop {
+            reference_elapsed = reference.read().wrapping_sub(reference_start) & reference.mask();
+            if reference_elapsed >= window {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+
+        let elapsed = counter().wrapping_sub(start);
+        (elapsed as u128 * reference.frequency() as u128 / reference_elapsed as u128) as u64
+    })
+}
+
+/// A point in time of the monotonic clock, which starts at `init`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// The current time, always the start of the clock before `init`
+    pub fn now() -> Self {
+        Self(interrupts::without_interrupts(|| {
+            CLOCK.lock().as_mut().map_or(0, Clock::nanos)
+        }))
+    }
+
+    /// Time since `earlier`, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// Time since the start of the clock
+    pub fn since_start(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_sub(nanos))
+            .map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickMode {
+    /// Ticks every interval until stopped
+    Periodic,
+    /// Ticks once after the interval
+    OneShot,
+}
+
+#[derive(Debug)]
+pub enum TickError {
+    /// The APICs are not in use, or `init` has not calibrated the timer
+    NoApicTimer,
+}
+
+/// Starts the local APIC timer of the current CPU ticking after `interval`
+pub fn start_tick(interval: Duration, mode: TickMode) -> Result<(), TickError> {
+    let apic = local_apic().ok_or(TickError::NoApicTimer)?;
+    let frequency = APIC_TIMER_FREQUENCY.load(Ordering::Acquire);
+    if frequency == 0 {
+        return Err(TickError::NoApicTimer);
+    }
+
+    let count = (interval.as_nanos() * frequency as u128 / NANOS_PER_SECOND)
+        .clamp(1, u32::MAX as u128) as u32;
+
+    PERIODIC_TICK.store(mode == TickMode::Periodic, Ordering::Release);
+    apic.start_timer(
+        TIMER_VECTOR,
+        count,
+        TIMER_DIVIDE,
+        mode == TickMode::Periodic,
+    );
+
+    Ok(())
+}
+
+pub fn stop_tick() {
+    PERIODIC_TICK.store(false, Ordering::Release);
+
+    if let Some(apic) = local_apic() {
+        apic.stop_timer();
+    }
+}
+
+/// Number of ticks so far
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Counts a tick, from the local APIC timer or the RTC periodic interrupt
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    // Reading the clock regularly keeps it from missing a wrap of the counter
+    Instant::now();
+}
+
+pub(crate) extern "x86-interrupt" fn timer_interrupt(_stack_frame: InterruptStackFrame) {
+    tick();
+
+    if let Some(apic) = local_apic() {
+        apic.end_of_interrupt();
+    }
+}
+
+/// Spins until `duration` has passed, for short waits on hardware
+pub fn busy_wait(duration: Duration) {
+    assert!(
+        clock_source_name().is_some(),
+        "waiting before the clock is initialized"
+    );
+
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
+    }
+}
+
+/// Waits until `duration` has passed. With a periodic tick, from the local
+/// APIC timer or the RTC, and interrupts enabled the CPU halts between ticks,
+/// otherwise this spins.
+pub fn sleep(duration: Duration) {
+    let periodic_tick = PERIODIC_TICK.load(Ordering::Acquire) || rtc::periodic_interrupt_running();
+
+    if !periodic_tick || !interrupts::are_enabled() {
+        busy_wait(duration);
+        return;
+    }
+
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        hlt();
+    }
+}
@@ -0,0 +1,101 @@
+use acpi::{AcpiTables, HpetInfo};
+use kernel_memory::{map_mmio, CacheMode};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::ClockSource;
+use crate::acpi::Handler;
+
+const REG_CAPABILITIES: u64 = 0x00;
+const REG_CONFIGURATION: u64 = 0x10;
+const REG_MAIN_COUNTER: u64 = 0xf0;
+
+const CAPABILITY_64_BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+const CONFIGURATION_LEGACY_ROUTE: u64 = 1 << 1;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+/// The main counter of the HPET the ACPI HPET table describes
+#[derive(Debug)]
+pub struct Hpet {
+    base: VirtAddr,
+    frequency: u64,
+    is_64_bit: bool,
+}
+
+impl Hpet {
+    /// Maps and starts the HPET, if the firmware describes one
+    pub fn init(tables: &AcpiTables<Handler>) -> Option<Self> {
+        let info = HpetInfo::new(tables).ok()?;
+
+        // The HPET stays mapped for as long as the kernel runs
+        let mapping = map_mmio(
+            PhysAddr::new(info.base_address as u64),
+            0x400,
+            CacheMode::Uncached,
+        )
+        .ok()?;
+        let base = mapping.into_raw();
+
+        unsafe {
+            let capabilities = read(base, REG_CAPABILITIES);
+            let period = capabilities >> 32;
+            if period == 0 {
+                return None;
+            }
+
+            // The counter only runs for us, interrupts keep coming from the
+            // PIT and local APIC timer
+            let configuration = read(base, REG_CONFIGURATION);
+            write(
+                base,
+                REG_CONFIGURATION,
+                (configuration & !CONFIGURATION_LEGACY_ROUTE) | CONFIGURATION_ENABLE,
+            );
+
+            Some(Self {
+                base,
+                frequency: FEMTOSECONDS_PER_SECOND / period,
+                is_64_bit: capabilities & CAPABILITY_64_BIT != 0,
+            })
+        }
+    }
+}
+
+unsafe fn read(base: VirtAddr, register: u64) -> u64 {
+    (base + register).as_ptr::<u64>().read_volatile()
+}
+
+unsafe fn write(base: VirtAddr, register: u64, value: u64) {
+    (base + register).as_mut_ptr::<u64>().write_volatile(value)
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn read(&self) -> u64 {
+        if self.is_64_bit {
+            unsafe { read(self.base, REG_MAIN_COUNTER) }
+        } else {
+            unsafe {
+                (self.base + REG_MAIN_COUNTER)
+                    .as_ptr::<u32>()
+                    .read_volatile() as u64
+            }
+        }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        if self.is_64_bit {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use super::ClockSource;
+
+/// Frequency the PIT counters run at
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+/// Channel 0, low then high byte, rate generator
+const CMD_CHANNEL_0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Latches the count of channel 0, so both bytes come from the same moment
+const CMD_LATCH_CHANNEL_0: u8 = 0b0000_0000;
+
+/// Serializes the two byte accesses to channel 0
+static CHANNEL_0_LOCK: Mutex<()> = Mutex::new(());
+
+/// The PIT's channel 0 counting down through its full 16 bits.
+///
+/// It wraps about every 55 ms, so the clock has to be read at least that
+/// often to stay monotonic. The wrap raises IRQ 0, which `time::init` reads
+/// the clock on when it runs on the PIT.
+#[derive(Debug)]
+pub struct Pit;
+
+impl Pit {
+    /// Starts channel 0 counting from the largest reload value
+    pub fn init() -> Self {
+        without_interrupts(|| {
+            let _lock = CHANNEL_0_LOCK.lock();
+
+            unsafe {
+                Port::<u8>::new(COMMAND).write(CMD_CHANNEL_0_RATE_GENERATOR);
+                // A reload value of 0 counts 65536 ticks
+                let mut data = Port::<u8>::new(CHANNEL_0);
+                data.write(0);
+                data.write(0);
+            }
+        });
+
+        Self
+    }
+
+    fn count(&self) -> u16 {
+        without_interrupts(|| {
+            let _lock = CHANNEL_0_LOCK.lock();
+
+            unsafe {
+                Port::<u8>::new(COMMAND).write(CMD_LATCH_CHANNEL_0);
+                let mut data = Port::<u8>::new(CHANNEL_0);
+                let low = data.read();
+                let high = data.read();
+
+                u16::from_le_bytes([low, high])
+            }
+        })
+    }
+}
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn read(&self) -> u64 {
+        // The counter counts down, turn it into ticks elapsed
+        0u16.wrapping_sub(self.count()) as u64
+    }
+
+    fn frequency(&self) -> u64 {
+        PIT_FREQUENCY
+    }
+
+    fn mask(&self) -> u64 {
+        u16::MAX as u64
+    }
+}
@@ -0,0 +1,49 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use super::{measure_frequency, ClockSource};
+
+/// The time stamp counter, calibrated against another clock source
+#[derive(Debug)]
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl Tsc {
+    /// Calibrates the TSC against `reference`. Only a TSC that keeps a
+    /// constant rate through frequency and power state changes makes a
+    /// clock source, so this returns `None` for older CPUs.
+    pub fn init(reference: &dyn ClockSource) -> Option<Self> {
+        if !is_invariant() {
+            return None;
+        }
+
+        let frequency = measure_frequency(reference, || unsafe { _rdtsc() });
+
+        Some(Self { frequency })
+    }
+}
+
+/// Whether the TSC is invariant (`CPUID.80000007h:EDX.InvariantTSC`)
+fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+
+    max_extended_leaf >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn read(&self) -> u64 {
+        unsafe { _rdtsc() }
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+}
@@ -0,0 +1,469 @@
+//! Driver for the 16550 UARTs behind the COM ports. Ports start out polled;
+//! once their interrupt is enabled, received bytes are buffered by the IRQ
+//! handler and writes go through a transmit buffer the handler drains.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::{
+    interrupts::{self, without_interrupts},
+    port::{Port, PortReadOnly},
+};
+
+use crate::{event_queue::EventQueue, irq};
+
+/// The UART clock divided by 16, the baud rate for a divisor of 1
+const MAX_BAUD: u32 = 115_200;
+
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 1024;
+/// Bytes the transmit FIFO takes once it is empty
+const TX_FIFO_SIZE: usize = 16;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+
+const IIR_NONE_PENDING: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0b1110;
+const IIR_MODEM_STATUS: u8 = 0b0000;
+const IIR_TX_EMPTY: u8 = 0b0010;
+const IIR_RX_AVAILABLE: u8 = 0b0100;
+const IIR_LINE_STATUS: u8 = 0b0110;
+const IIR_RX_TIMEOUT: u8 = 0b1100;
+
+/// Enable and clear the FIFOs, interrupt once 14 bytes were received
+const FCR_ENABLE: u8 = 0xc7;
+
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+/// Connects the interrupt line of the UART to the interrupt controller
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOPBACK: u8 = 1 << 4;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+/// Byte sent to itself in loopback mode to check the UART is there
+const LOOPBACK_TEST: u8 = 0xae;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const fn base(&self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    /// COM1 and COM3 share IRQ 4, COM2 and COM4 share IRQ 3
+    pub const fn irq(&self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    /// The parity bit is always set
+    Mark,
+    /// The parity bit is always clear
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// One and a half with five data bits
+    Two,
+}
+
+/// Baud rate and frame format, 115200 8N1 by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineConfig {
+    fn default() -> Self {
+        Self {
+            baud: MAX_BAUD,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl LineConfig {
+    fn divisor(&self) -> Result<u16, UartError> {
+        if self.baud == 0 || MAX_BAUD % self.baud != 0 {
+            return Err(UartError::UnsupportedBaud(self.baud));
+        }
+
+        u16::try_from(MAX_BAUD / self.baud).map_err(|_| UartError::UnsupportedBaud(self.baud))
+    }
+
+    fn line_control(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000 << 3,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+
+        data_bits | stop_bits | parity
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// The baud rate does not divide 115200
+    UnsupportedBaud(u32),
+    /// Nothing answered the loopback test
+    NotPresent,
+    /// `init` has not set up the port
+    NotInitialized,
+}
+
+/// What the port has been set up for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Mode {
+    Uninitialized,
+    Polled,
+    InterruptDriven,
+}
+
+struct Registers {
+    /// Receive and transmit holding registers, low divisor byte with DLAB
+    data: Port<u8>,
+    /// High divisor byte with DLAB
+    interrupt_enable: Port<u8>,
+    /// Interrupt identification when read, FIFO control when written
+    interrupt_id: Port<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: PortReadOnly<u8>,
+    modem_status: PortReadOnly<u8>,
+}
+
+impl Registers {
+    const fn new(base: u16) -> Self {
+        Self {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            interrupt_id: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: PortReadOnly::new(base + 5),
+            modem_status: PortReadOnly::new(base + 6),
+        }
+    }
+
+    fn set_line(&mut self, divisor: u16, line_control: u8) {
+        unsafe {
+            self.line_control.write(LCR_DLAB);
+            self.data.write(divisor as u8);
+            self.interrupt_enable.write((divisor >> 8) as u8);
+            self.line_control.write(line_control);
+        }
+    }
+
+    fn write_polled(&mut self, byte: u8) {
+        unsafe {
+            while self.line_status.read() & LSR_TX_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+
+    fn try_read(&mut self) -> Option<u8> {
+        unsafe {
+            if self.line_status.read() & LSR_DATA_READY == 0 {
+                return None;
+            }
+
+            Some(self.data.read())
+        }
+    }
+}
+
+pub struct Uart {
+    port: ComPort,
+    registers: Mutex<Registers>,
+    mode: AtomicU8,
+    rx: EventQueue<u8, RX_BUFFER_SIZE>,
+    tx: EventQueue<u8, TX_BUFFER_SIZE>,
+}
+
+pub static COM1: Uart = Uart::new(ComPort::Com1);
+pub static COM2: Uart = Uart::new(ComPort::Com2);
+pub static COM3: Uart = Uart::new(ComPort::Com3);
+pub static COM4: Uart = Uart::new(ComPort::Com4);
+
+impl Uart {
+    const fn new(port: ComPort) -> Self {
+        Self {
+            port,
+            registers: Mutex::new(Registers::new(port.base())),
+            mode: AtomicU8::new(Mode::Uninitialized as u8),
+            rx: EventQueue::new(),
+            tx: EventQueue::new(),
+        }
+    }
+
+    pub fn port(&self) -> ComPort {
+        self.port
+    }
+
+    fn mode(&self) -> Mode {
+        match self.mode.load(Ordering::Acquire) {
+            1 => Mode::Polled,
+            2 => Mode::InterruptDriven,
+            _ => Mode::Uninitialized,
+        }
+    }
+
+    fn set_mode(&self, mode: Mode) {
+        self.mode.store(mode as u8, Ordering::Release);
+    }
+
+    /// Checks the UART is there and sets it up for polling with `config`
+    pub fn init(&self, config: LineConfig) -> Result<(), UartError> {
+        let divisor = config.divisor()?;
+
+        without_interrupts(|| {
+            let mut registers = self.registers.lock();
+
+            unsafe {
+                registers.interrupt_enable.write(0);
+                registers.set_line(divisor, config.line_control());
+                registers.interrupt_id.write(FCR_ENABLE);
+
+                registers
+                    .modem_control
+                    .write(MCR_LOOPBACK | MCR_RTS | MCR_OUT2);
+                registers.data.write(LOOPBACK_TEST);
+                if registers.data.read() != LOOPBACK_TEST {
+                    return Err(UartError::NotPresent);
+                }
+
+                registers.modem_control.write(MCR_DTR | MCR_RTS | MCR_OUT2);
+            }
+
+            self.set_mode(Mode::Polled);
+            Ok(())
+        })
+    }
+
+    /// Changes the baud rate and frame format of an initialized port
+    pub fn configure(&self, config: LineConfig) -> Result<(), UartError> {
+        let divisor = config.divisor()?;
+        if self.mode() == Mode::Uninitialized {
+            return Err(UartError::NotInitialized);
+        }
+
+        self.flush();
+        without_interrupts(|| {
+            self.registers
+                .lock()
+                .set_line(divisor, config.line_control())
+        });
+
+        Ok(())
+    }
+
+    /// Switches the port to buffered, interrupt driven reads and writes
+    pub fn enable_interrupts(&'static self) -> Result<(), UartError> {
+        match self.mode() {
+            Mode::Uninitialized => return Err(UartError::NotInitialized),
+            Mode::InterruptDriven => return Ok(()),
+            Mode::Polled => {}
+        }
+
+        irq::register_handler(self.port.irq(), move |_| self.handle_interrupt());
+
+        without_interrupts(|| {
+            let mut registers = self.registers.lock();
+            // Bytes received while polling are kept for `read_byte`
+            while let Some(byte) = registers.try_read() {
+                self.rx.push(byte);
+            }
+            unsafe {
+                registers
+                    .interrupt_enable
+                    .write(IER_RX_AVAILABLE | IER_LINE_STATUS)
+            };
+
+            self.set_mode(Mode::InterruptDriven);
+        });
+
+        Ok(())
+    }
+
+    fn handle_interrupt(&self) {
+        let mut registers = self.registers.lock();
+
+        loop {
+            let id = unsafe { registers.interrupt_id.read() };
+            if id & IIR_NONE_PENDING != 0 {
+                break;
+            }
+
+            match id & IIR_ID_MASK {
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => {
+                    while let Some(byte) = registers.try_read() {
+                        // Input nobody reads is dropped once the buffer is full
+                        self.rx.push(byte);
+                    }
+                }
+                IIR_TX_EMPTY => self.fill_fifo(&mut registers),
+                IIR_LINE_STATUS => unsafe {
+                    registers.line_status.read();
+                },
+                IIR_MODEM_STATUS => unsafe {
+                    registers.modem_status.read();
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Moves buffered bytes to the empty transmit FIFO, turning the transmit
+    /// interrupt off when there are none left
+    fn fill_fifo(&self, registers: &mut Registers) {
+        for _ in 0..TX_FIFO_SIZE {
+            match self.tx.pop() {
+                Some(byte) => unsafe { registers.data.write(byte) },
+                None => unsafe {
+                    let enabled = registers.interrupt_enable.read();
+                    registers.interrupt_enable.write(enabled & !IER_TX_EMPTY);
+                    return;
+                },
+            }
+        }
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        self.write(&[byte]);
+    }
+
+    /// Writes `bytes`, buffering them once interrupts are enabled. When the
+    /// buffer is full this waits for the interrupt handler to make space, or
+    /// writes the buffer out by polling if interrupts are disabled.
+    pub fn write(&self, bytes: &[u8]) {
+        match self.mode() {
+            Mode::Uninitialized => {}
+            Mode::Polled => without_interrupts(|| {
+                let mut registers = self.registers.lock();
+                for &byte in bytes {
+                    registers.write_polled(byte);
+                }
+            }),
+            Mode::InterruptDriven => {
+                for &byte in bytes {
+                    if self.tx.push(byte) {
+                        continue;
+                    }
+
+                    self.start_transmit();
+                    while !self.tx.push(byte) {
+                        if interrupts::are_enabled() {
+                            core::hint::spin_loop();
+                        } else {
+                            self.flush();
+                        }
+                    }
+                }
+
+                self.start_transmit();
+            }
+        }
+    }
+
+    /// Enabling the transmit interrupt with the FIFO empty raises it right
+    /// away, so the handler starts draining the buffer
+    fn start_transmit(&self) {
+        without_interrupts(|| unsafe {
+            let mut registers = self.registers.lock();
+            let enabled = registers.interrupt_enable.read();
+            registers.interrupt_enable.write(enabled | IER_TX_EMPTY);
+        });
+    }
+
+    /// Writes out the transmit buffer by polling
+    pub fn flush(&self) {
+        without_interrupts(|| {
+            let mut registers = self.registers.lock();
+            while let Some(byte) = self.tx.pop() {
+                registers.write_polled(byte);
+            }
+        });
+    }
+
+    /// The oldest byte received, if any
+    pub fn read_byte(&self) -> Option<u8> {
+        match self.mode() {
+            Mode::Uninitialized => None,
+            Mode::Polled => without_interrupts(|| self.registers.lock().try_read()),
+            Mode::InterruptDriven => self.rx.pop(),
+        }
+    }
+
+    /// Takes the port back to polling for reporting a fatal error, writing
+    /// out what is buffered. Interrupts have to be disabled for good.
+    ///
+    /// # Safety
+    /// The port is taken even if the code that failed was using it.
+    pub unsafe fn force_polled(&self) {
+        if self.registers.is_locked() {
+            self.registers.force_unlock();
+        }
+
+        if self.mode() == Mode::InterruptDriven {
+            self.registers.lock().interrupt_enable.write(0);
+            self.flush();
+            self.set_mode(Mode::Polled);
+        }
+    }
+}